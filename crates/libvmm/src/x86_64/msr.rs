@@ -5,12 +5,28 @@ use x86::msr::{rdmsr, wrmsr};
 #[derive(Debug, Copy, Clone)]
 #[allow(non_camel_case_types)]
 pub enum Msr {
+    IA32_APIC_BASE = 0x1b,
     IA32_FEATURE_CONTROL = 0x3a,
+    IA32_MTRRCAP = 0xfe,
 
     IA32_SYSENTER_CS = 0x174,
     IA32_SYSENTER_ESP = 0x175,
     IA32_SYSENTER_EIP = 0x176,
 
+    IA32_MTRR_PHYSBASE0 = 0x200,
+    IA32_MTRR_PHYSMASK0 = 0x201,
+    IA32_MTRR_FIX64K_00000 = 0x250,
+    IA32_MTRR_FIX16K_80000 = 0x258,
+    IA32_MTRR_FIX16K_A0000 = 0x259,
+    IA32_MTRR_FIX4K_C0000 = 0x268,
+    IA32_MTRR_FIX4K_C8000 = 0x269,
+    IA32_MTRR_FIX4K_D0000 = 0x26a,
+    IA32_MTRR_FIX4K_D8000 = 0x26b,
+    IA32_MTRR_FIX4K_E0000 = 0x26c,
+    IA32_MTRR_FIX4K_E8000 = 0x26d,
+    IA32_MTRR_FIX4K_F0000 = 0x26e,
+    IA32_MTRR_FIX4K_F8000 = 0x26f,
+
     IA32_PAT = 0x277,
     IA32_MTRR_DEF_TYPE = 0x2ff,
     IA32_PERF_GLOBAL_CTRL = 0x38f,
@@ -32,6 +48,9 @@ pub enum Msr {
     IA32_VMX_TRUE_EXIT_CTLS = 0x48f,
     IA32_VMX_TRUE_ENTRY_CTLS = 0x490,
 
+    IA32_X2APIC_APICID = 0x802,
+    IA32_X2APIC_ICR = 0x830,
+
     IA32_EFER = 0xc000_0080,
     IA32_STAR = 0xc000_0081,
     IA32_LSTAR = 0xc000_0082,
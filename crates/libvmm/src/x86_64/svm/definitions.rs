@@ -148,6 +148,15 @@ impl TryFrom<u64> for SvmExitCode {
 #[derive(Debug, Clone, Copy)]
 #[allow(non_camel_case_types)]
 pub enum SvmIntercept {
+    // 0x00 (CR reads and writes)
+    CR0_READ = 0x00,
+    CR3_READ = 0x03,
+    CR4_READ = 0x04,
+    CR8_READ = 0x08,
+    CR0_WRITE = 0x10,
+    CR3_WRITE = 0x13,
+    CR4_WRITE = 0x14,
+    CR8_WRITE = 0x18,
     // 0x0C (vector 3)
     INTR = 0x60,
     NMI = 0x61,
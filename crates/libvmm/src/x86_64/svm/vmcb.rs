@@ -117,6 +117,7 @@ impl Vmcb {
     pub fn set_intercept(&mut self, which: SvmIntercept) {
         let val = which as u8;
         match val {
+            0x00..=0x1F => self.control.intercept_cr |= 1 << val,
             0x60..=0x7F => self.control.intercept_vector3 |= 1 << (val - 0x60),
             0x80..=0x8F => self.control.intercept_vector4 |= 1 << (val - 0x80),
             0xA0..=0xA4 => self.control.intercept_vector5 |= 1 << (val - 0xA0),
@@ -124,6 +125,17 @@ impl Vmcb {
         }
     }
 
+    pub fn clear_intercept(&mut self, which: SvmIntercept) {
+        let val = which as u8;
+        match val {
+            0x00..=0x1F => self.control.intercept_cr &= !(1 << val),
+            0x60..=0x7F => self.control.intercept_vector3 &= !(1 << (val - 0x60)),
+            0x80..=0x8F => self.control.intercept_vector4 &= !(1 << (val - 0x80)),
+            0xA0..=0xA4 => self.control.intercept_vector5 &= !(1 << (val - 0xA0)),
+            _ => {}
+        }
+    }
+
     pub fn inject_event(&mut self, info: VmcbIntInfo, error_code: u32) {
         self.control.event_inj = info.bits();
         self.control.event_inj_err = error_code;
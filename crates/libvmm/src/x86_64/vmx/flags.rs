@@ -198,7 +198,49 @@ impl EPTPointer {
     pub fn from_table_phys(pml4_paddr: usize) -> Self {
         let aligned_addr = pml4_paddr & !0xfff;
         let flags = unsafe { Self::from_bits_unchecked(aligned_addr as u64) };
-        flags | Self::MEMORY_TYPE_WB | Self::WALK_LENGTH_4 | Self::ENABLE_ACCESSED_DIRTY
+        let mut flags = flags | Self::MEMORY_TYPE_WB | Self::WALK_LENGTH_4;
+        if EptVpidCap::read().contains(EptVpidCapFlags::ACCESSED_DIRTY) {
+            flags |= Self::ENABLE_ACCESSED_DIRTY;
+        }
+        flags
+    }
+}
+
+bitflags! {
+    /// IA32_VMX_EPT_VPID_CAP flags, see Intel SDM Volume 3D, Appendix A.10.
+    pub struct EptVpidCapFlags: u64 {
+        /// Support for the INVEPT instruction.
+        const INVEPT = 1 << 20;
+        /// Support for accessed and dirty flags for EPT.
+        const ACCESSED_DIRTY = 1 << 21;
+        /// Support for the single-context INVEPT type.
+        const INVEPT_SINGLE_CONTEXT = 1 << 25;
+        /// Support for the all-context INVEPT type.
+        const INVEPT_ALL_CONTEXT = 1 << 26;
+        /// Support for the INVVPID instruction.
+        const INVVPID = 1 << 32;
+        /// Support for the individual-address INVVPID type.
+        const INVVPID_INDIVIDUAL_ADDRESS = 1 << 40;
+        /// Support for the single-context INVVPID type.
+        const INVVPID_SINGLE_CONTEXT = 1 << 41;
+        /// Support for the all-context INVVPID type.
+        const INVVPID_ALL_CONTEXT = 1 << 42;
+        /// Support for the single-context-retaining-globals INVVPID type.
+        const INVVPID_SINGLE_CONTEXT_RETAINING_GLOBALS = 1 << 43;
+    }
+}
+
+/// VPID and EPT capabilities: IA32_VMX_EPT_VPID_CAP
+pub struct EptVpidCap;
+
+impl MsrReadWrite for EptVpidCap {
+    const MSR: Msr = Msr::IA32_VMX_EPT_VPID_CAP;
+}
+
+impl EptVpidCap {
+    /// Read the current IA32_VMX_EPT_VPID_CAP flags.
+    pub fn read() -> EptVpidCapFlags {
+        EptVpidCapFlags::from_bits_truncate(Self::read_raw())
     }
 }
 
@@ -390,6 +432,44 @@ pub enum InvEptType {
     /// It may invalidate other mappings as well.
     SingleContext = 1,
 
-    /// The logical processor invalidates mappings associated with all EPTPs.
+    /// The logical processor invalidates mappings associated with all EPTPs
+    /// (all-context invalidation).
     Global = 2,
 }
+
+#[repr(C, packed)]
+#[derive(Debug, Clone, Copy)]
+pub struct InvVpidDescriptor {
+    /// VPID (bits 15:0), bits 63:16 are reserved (must be zero)
+    vpid: u64,
+    /// Linear address
+    addr: u64,
+}
+
+impl InvVpidDescriptor {
+    pub fn new(vpid: u16, addr: u64) -> Self {
+        Self {
+            vpid: vpid as u64,
+            addr,
+        }
+    }
+}
+
+#[repr(u64)]
+#[derive(Debug)]
+pub enum InvVpidType {
+    /// The logical processor invalidates mappings for the linear address
+    /// specified in the INVVPID descriptor and tagged with the given VPID.
+    IndividualAddress = 0,
+
+    /// The logical processor invalidates all mappings tagged with the given
+    /// VPID.
+    SingleContext = 1,
+
+    /// The logical processor invalidates all mappings tagged with all VPIDs
+    /// except VPID 0000H.
+    AllContext = 2,
+
+    /// Same as single-context, except that global translations are retained.
+    SingleContextRetainingGlobals = 3,
+}
@@ -3,7 +3,7 @@ use core::arch::asm;
 use x86::bits64::rflags::{self, RFlags};
 use x86::vmx::{Result, VmFail};
 
-use super::flags::{InvEptDescriptor, InvEptType};
+use super::flags::{InvEptDescriptor, InvEptType, InvVpidDescriptor, InvVpidType};
 
 pub use x86::bits64::vmx::{vmxoff, vmxon};
 
@@ -38,3 +38,15 @@ pub unsafe fn invept(invalidation: InvEptType, eptp: u64) -> Result<()> {
     asm!("invept {}, [{}]", in(reg) invalidation as u64, in(reg) &descriptor);
     vmx_capture_status()
 }
+
+/// Invalidate Translations Based on VPID.
+///
+/// # Safety
+///
+/// This function is unsafe because it's possible to violate memory safety
+/// through execution.
+pub unsafe fn invvpid(invalidation: InvVpidType, vpid: u16, addr: u64) -> Result<()> {
+    let descriptor = InvVpidDescriptor::new(vpid, addr);
+    asm!("invvpid {}, [{}]", in(reg) invalidation as u64, in(reg) &descriptor);
+    vmx_capture_status()
+}
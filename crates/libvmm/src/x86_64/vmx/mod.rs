@@ -4,5 +4,5 @@ mod instructions;
 pub mod vmcs;
 
 pub use definitions::{VmxExitReason, VmxInstructionError};
-pub use instructions::{invept, vmxoff, vmxon};
+pub use instructions::{invept, invvpid, vmxoff, vmxon};
 pub use vmcs::Vmcs;
@@ -360,3 +360,28 @@ impl EptViolationInfo {
         })
     }
 }
+
+#[derive(Debug)]
+pub struct CrAccessInfo {
+    pub cr_number: u8,
+    /// 0: MOV to CR, 1: MOV from CR, 2: CLTS, 3: LMSW.
+    pub access_type: u8,
+    /// Whether the operand of LMSW is a memory operand.
+    pub lmsw_memory_operand: bool,
+    /// General-purpose register of MOV CR (0: RAX, 1: RCX, 2: RDX, 3: RBX, 4: RSP, ..., 15: R15).
+    pub gpr: u8,
+    pub lmsw_source_data: u16,
+}
+
+impl CrAccessInfo {
+    pub fn new() -> VmResult<Self> {
+        let qualification = VmcsField64ReadOnly::EXIT_QUALIFICATION.read()?;
+        Ok(Self {
+            cr_number: qualification.get_bits(0..4) as u8,
+            access_type: qualification.get_bits(4..6) as u8,
+            lmsw_memory_operand: qualification.get_bit(6),
+            gpr: qualification.get_bits(8..12) as u8,
+            lmsw_source_data: qualification.get_bits(16..32) as u16,
+        })
+    }
+}
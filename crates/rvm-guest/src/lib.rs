@@ -0,0 +1,250 @@
+//! Guest-side interface of the RVM paravirtual ABI, for kernels running in RVM cells.
+//!
+//! RVM is detected by the signature `RVMRVMRVMRVM` in CPUID leaf 0x4000_0000, and advertises
+//! its paravirtual features in EAX of leaf 0x4000_0001. Hypercalls are issued by VMCALL (Intel)
+//! or VMMCALL (AMD), with the code in EAX and the arguments in RDI, RSI, RDX, R10 and R8. The
+//! result is returned in RAX, values in -4095..-1 are negative errno. Hypercalls returning more
+//! values also write RDI, RSI and RDX in order. Other registers are preserved.
+//!
+//! Hypercalls with bits 30..32 of the code clear (class 0) can only be called at CPL 0, the
+//! others only at CPL > 0. Class 1 has safe hypercalls for user mode applications.
+
+#![cfg_attr(not(test), no_std)]
+
+use core::arch::asm;
+use core::arch::x86_64::{CpuidResult, __cpuid};
+
+/// Keep in sync with `src/hypercall/mod.rs` and `src/arch/x86_64/vmm.rs` of the hypervisor.
+const CPUID_SIGNATURE_LEAF: u32 = 0x4000_0000;
+const CPUID_FEATURES_LEAF: u32 = 0x4000_0001;
+const SIGNATURE: [u8; 12] = *b"RVMRVMRVMRVM";
+
+const ABI_INFO_VERSION: u64 = 0;
+const ABI_INFO_CALL_MASK: u64 = 1;
+
+/// Hypercall codes.
+#[repr(u32)]
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub enum HyperCallCode {
+    HypervisorDisable = 0,
+    HypervisorGetInfo = 5,
+    CpuGetInfo = 7,
+    DebugConsolePutc = 8,
+    HypervisorSetLogLevel = 16,
+    HypervisorDumpStats = 17,
+    HypervisorGetTrace = 18,
+    HypervisorGetAbi = 19,
+    UserGetVersion = 0x4000_0000,
+    UserGetCellId = 0x4000_0001,
+    UserIvshmemDoorbell = 0x4000_0002,
+}
+
+/// Paravirtual features in EAX of CPUID leaf 0x4000_0001.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Features(u32);
+
+impl Features {
+    /// `HypervisorGetAbi` is supported, all other hypercalls can be discovered by it.
+    pub const ABI_INFO: Self = Self(1 << 0);
+    /// The cell is permitted to print by `DebugConsolePutc`.
+    pub const DEBUG_CONSOLE: Self = Self(1 << 1);
+    /// HLT idles in the hypervisor, with lower wake-up latency than spinning in the cell.
+    pub const HLT_IDLE: Self = Self(1 << 2);
+
+    pub const fn bits(self) -> u32 {
+        self.0
+    }
+
+    pub const fn contains(self, other: Self) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+/// Version of the paravirtual ABI. The major version changes on incompatible changes, the minor
+/// version on compatible additions.
+#[derive(Debug, Eq, PartialEq, Ord, PartialOrd, Copy, Clone)]
+pub struct AbiVersion {
+    pub major: u16,
+    pub minor: u16,
+}
+
+impl AbiVersion {
+    /// Decode the major version in bits 16..32 and the minor version in bits 0..16.
+    fn from_bits(bits: u64) -> Self {
+        Self {
+            major: (bits >> 16) as u16,
+            minor: bits as u16,
+        }
+    }
+}
+
+/// POSIX errno returned by a hypercall.
+#[derive(Debug, Eq, PartialEq, Copy, Clone)]
+pub struct Error(pub i32);
+
+impl Error {
+    pub const EPERM: Self = Self(1);
+    pub const EFAULT: Self = Self(14);
+    pub const EINVAL: Self = Self(22);
+    pub const ENOSYS: Self = Self(38);
+}
+
+pub type Result<T = ()> = core::result::Result<T, Error>;
+
+/// Convert the raw value of RAX returned by a hypercall.
+fn check(ret: u64) -> Result<u64> {
+    if (-4095..0).contains(&(ret as i64)) {
+        Err(Error(-(ret as i64) as i32))
+    } else {
+        Ok(ret)
+    }
+}
+
+#[allow(unused_unsafe)] // `__cpuid` is safe in newer toolchains
+fn cpuid(leaf: u32) -> CpuidResult {
+    unsafe { __cpuid(leaf) }
+}
+
+/// The RVM hypervisor running below this guest.
+pub struct Rvm {
+    features: Features,
+    /// Use VMMCALL (AMD) instead of VMCALL (Intel).
+    vmmcall: bool,
+}
+
+impl Rvm {
+    /// Detect RVM by CPUID, returns `None` if not running in an RVM cell.
+    pub fn detect() -> Option<Self> {
+        if cpuid(1).ecx & (1 << 31) == 0 {
+            return None; // no hypervisor
+        }
+        let info = cpuid(CPUID_SIGNATURE_LEAF);
+        let mut signature = [0; 12];
+        signature[0..4].copy_from_slice(&info.ebx.to_le_bytes());
+        signature[4..8].copy_from_slice(&info.ecx.to_le_bytes());
+        signature[8..12].copy_from_slice(&info.edx.to_le_bytes());
+        if signature != SIGNATURE || info.eax < CPUID_FEATURES_LEAF {
+            return None;
+        }
+        let vendor = cpuid(0).ebx.to_le_bytes();
+        Some(Self {
+            features: Features(cpuid(CPUID_FEATURES_LEAF).eax),
+            vmmcall: &vendor == b"Auth" || &vendor == b"Hygo",
+        })
+    }
+
+    pub fn features(&self) -> Features {
+        self.features
+    }
+
+    /// Issue the hypercall `code`, returns the raw values of RAX, RDI, RSI and RDX. Registers
+    /// not written by the hypercall keep the arguments.
+    ///
+    /// # Safety
+    ///
+    /// Some hypercalls disable the hypervisor or write guest memory at addresses in arguments.
+    pub unsafe fn hypercall(&self, code: u32, args: [u64; 5]) -> [u64; 4] {
+        let mut rets = [code as u64, args[0], args[1], args[2]];
+        macro_rules! hypercall {
+            ($instr: literal) => {
+                asm!(
+                    $instr,
+                    inout("rax") rets[0],
+                    inout("rdi") rets[1],
+                    inout("rsi") rets[2],
+                    inout("rdx") rets[3],
+                    in("r10") args[3],
+                    in("r8") args[4],
+                )
+            };
+        }
+        if self.vmmcall {
+            hypercall!("vmmcall");
+        } else {
+            hypercall!("vmcall");
+        }
+        rets
+    }
+
+    /// Issue the hypercall `code` with one argument, returns the value of RAX.
+    fn hypercall1(&self, code: HyperCallCode, arg0: u64) -> Result<u64> {
+        check(unsafe { self.hypercall(code as u32, [arg0, 0, 0, 0, 0]) }[0])
+    }
+
+    fn get_abi(&self, info_type: u64, arg: u64) -> Result<u64> {
+        if !self.features.contains(Features::ABI_INFO) {
+            return Err(Error::ENOSYS);
+        }
+        let code = HyperCallCode::HypervisorGetAbi as u32;
+        check(unsafe { self.hypercall(code, [info_type, arg, 0, 0, 0]) }[0])
+    }
+
+    /// Version of the paravirtual ABI.
+    pub fn abi_version(&self) -> Result<AbiVersion> {
+        self.get_abi(ABI_INFO_VERSION, 0).map(AbiVersion::from_bits)
+    }
+
+    /// Mask of supported hypercalls in `class` (bits 30..32 of codes): bit `n` is set if code
+    /// `class << 30 | n` is supported.
+    pub fn supported_calls(&self, class: u32) -> Result<u64> {
+        self.get_abi(ABI_INFO_CALL_MASK, class as u64)
+    }
+
+    /// Whether the hypercall `code` is supported.
+    pub fn is_supported(&self, code: u32) -> bool {
+        let n = code & ((1 << 30) - 1);
+        n < 64
+            && self
+                .supported_calls(code >> 30)
+                .map_or(false, |mask| mask & (1 << n) != 0)
+    }
+
+    /// Print a character to the hypervisor console.
+    pub fn debug_console_putc(&self, c: u8) -> Result {
+        self.hypercall1(HyperCallCode::DebugConsolePutc, c as u64)
+            .map(|_| ())
+    }
+
+    /// Version of the paravirtual ABI, for user mode applications. Does not work at CPL 0.
+    pub fn user_abi_version(&self) -> Result<AbiVersion> {
+        self.hypercall1(HyperCallCode::UserGetVersion, 0)
+            .map(AbiVersion::from_bits)
+    }
+
+    /// ID of the cell of the caller, for user mode applications. Does not work at CPL 0.
+    pub fn user_cell_id(&self) -> Result<u32> {
+        self.hypercall1(HyperCallCode::UserGetCellId, 0)
+            .map(|id| id as u32)
+    }
+
+    /// Ring the doorbell of the `device`-th ivshmem device of the cell, which sends the
+    /// interrupt programmed in entry `vector` of its MSI-X table. For user mode applications,
+    /// does not work at CPL 0.
+    pub fn user_ivshmem_doorbell(&self, device: u32, vector: u32) -> Result {
+        let code = HyperCallCode::UserIvshmemDoorbell as u32;
+        let args = [device as u64, vector as u64, 0, 0, 0];
+        check(unsafe { self.hypercall(code, args) }[0]).map(|_| ())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check() {
+        assert_eq!(check(0), Ok(0));
+        assert_eq!(check(-38i64 as u64), Err(Error::ENOSYS));
+        assert_eq!(check(-4095i64 as u64), Err(Error(4095)));
+        // Masks with bit 63 set are not errors.
+        assert_eq!(check(1 << 63), Ok(1 << 63));
+    }
+
+    #[test]
+    fn test_features() {
+        let features = Features(Features::ABI_INFO.bits() | Features::HLT_IDLE.bits());
+        assert!(features.contains(Features::ABI_INFO));
+        assert!(features.contains(Features::HLT_IDLE));
+        assert!(!features.contains(Features::DEBUG_CONSOLE));
+    }
+}
@@ -0,0 +1,308 @@
+//! Decoder of the per-CPU VM exit traces returned by the `HypervisorGetTrace` hypercall.
+//!
+//! Usage: `rvm-trace [--chrome] FILE...`, where each file contains one or more trace snapshots
+//! concatenated. Prints the exits as text, or as a Chrome trace JSON (for `chrome://tracing`
+//! or Perfetto) with `--chrome`.
+
+use std::fmt::Write as _;
+use std::io::Write as _;
+use std::{env, fs, io, process};
+
+/// Keep in sync with `src/trace.rs` of the hypervisor.
+const TRACE_MAGIC: u32 = u32::from_le_bytes(*b"RVMT");
+const TRACE_VERSION: u32 = 1;
+const HEADER_SIZE: usize = 40;
+const ENTRY_SIZE: usize = 40;
+
+const TRACE_VENDOR_INTEL: u32 = 1;
+const TRACE_VENDOR_AMD: u32 = 2;
+
+/// Names of `ExitKind` in the hypervisor.
+const EXIT_KINDS: [&str; 15] = [
+    "Other",
+    "Exception",
+    "Nmi",
+    "ExternalInterrupt",
+    "Cpuid",
+    "Hypercall",
+    "MsrRead",
+    "MsrWrite",
+    "NestedPageFault",
+    "Io",
+    "CrAccess",
+    "Hlt",
+    "DirtyLogFull",
+    "Shutdown",
+    "Pause",
+];
+
+#[derive(Debug)]
+struct Header {
+    vendor: u32,
+    cpu_id: u32,
+    tsc_khz: u64,
+    num_entries: u32,
+    total: u64,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+struct Entry {
+    tsc: u64,
+    rip: u64,
+    qualification: u64,
+    cycles: u64,
+    reason: u32,
+    kind: u32,
+}
+
+struct Snapshot {
+    header: Header,
+    entries: Vec<Entry>,
+}
+
+fn u32_at(data: &[u8], off: usize) -> u32 {
+    u32::from_le_bytes(data[off..off + 4].try_into().unwrap())
+}
+
+fn u64_at(data: &[u8], off: usize) -> u64 {
+    u64::from_le_bytes(data[off..off + 8].try_into().unwrap())
+}
+
+fn parse(mut data: &[u8]) -> Result<Vec<Snapshot>, String> {
+    let mut snapshots = Vec::new();
+    while !data.is_empty() {
+        if data.len() < HEADER_SIZE {
+            return Err("truncated header".into());
+        }
+        if u32_at(data, 0) != TRACE_MAGIC {
+            return Err("bad magic".into());
+        }
+        let version = u32_at(data, 4);
+        if version != TRACE_VERSION {
+            return Err(format!("unsupported version {}", version));
+        }
+        let header = Header {
+            vendor: u32_at(data, 8),
+            cpu_id: u32_at(data, 12),
+            tsc_khz: u64_at(data, 16),
+            num_entries: u32_at(data, 28),
+            total: u64_at(data, 32),
+        };
+        let size = HEADER_SIZE + header.num_entries as usize * ENTRY_SIZE;
+        if data.len() < size {
+            return Err("truncated entries".into());
+        }
+        let entries = data[HEADER_SIZE..size]
+            .chunks_exact(ENTRY_SIZE)
+            .map(|e| Entry {
+                tsc: u64_at(e, 0),
+                rip: u64_at(e, 8),
+                qualification: u64_at(e, 16),
+                cycles: u64_at(e, 24),
+                reason: u32_at(e, 32),
+                kind: u32_at(e, 36),
+            })
+            .collect();
+        snapshots.push(Snapshot { header, entries });
+        data = &data[size..];
+    }
+    Ok(snapshots)
+}
+
+fn vmx_reason_name(reason: u32) -> Option<&'static str> {
+    Some(match reason {
+        0 => "EXCEPTION_NMI",
+        1 => "EXTERNAL_INTERRUPT",
+        2 => "TRIPLE_FAULT",
+        3 => "INIT",
+        4 => "SIPI",
+        7 => "PENDING_INTERRUPT",
+        8 => "NMI_WINDOW",
+        10 => "CPUID",
+        12 => "HLT",
+        18 => "VMCALL",
+        28 => "CR_ACCESS",
+        29 => "DR_ACCESS",
+        30 => "IO_INSTRUCTION",
+        31 => "MSR_READ",
+        32 => "MSR_WRITE",
+        40 => "PAUSE_INSTRUCTION",
+        48 => "EPT_VIOLATION",
+        49 => "EPT_MISCONFIG",
+        52 => "PREEMPTION_TIMER",
+        55 => "XSETBV",
+        62 => "PML_FULL",
+        _ => return None,
+    })
+}
+
+fn svm_reason_name(reason: u32) -> Option<&'static str> {
+    Some(match reason {
+        0x00..=0x0f => "CR_READ",
+        0x10..=0x1f => "CR_WRITE",
+        0x40..=0x5f => "EXCP",
+        0x60 => "INTR",
+        0x61 => "NMI",
+        0x63 => "INIT",
+        0x72 => "CPUID",
+        0x77 => "PAUSE",
+        0x78 => "HLT",
+        0x7b => "IOIO",
+        0x7c => "MSR",
+        0x7f => "SHUTDOWN",
+        0x81 => "VMMCALL",
+        0x8d => "XSETBV",
+        0x400 => "NPF",
+        _ => return None,
+    })
+}
+
+fn reason_name(vendor: u32, reason: u32) -> String {
+    let name = match vendor {
+        TRACE_VENDOR_INTEL => vmx_reason_name(reason),
+        TRACE_VENDOR_AMD => svm_reason_name(reason),
+        _ => None,
+    };
+    match name {
+        Some(name) => name.into(),
+        None => format!("{:#x}", reason),
+    }
+}
+
+fn kind_name(kind: u32) -> &'static str {
+    EXIT_KINDS.get(kind as usize).copied().unwrap_or("Unknown")
+}
+
+fn cycles_to_us(cycles: u64, tsc_khz: u64) -> f64 {
+    cycles as f64 * 1000.0 / tsc_khz.max(1) as f64
+}
+
+fn print_text(snapshots: &[Snapshot], out: &mut impl io::Write) -> io::Result<()> {
+    for s in snapshots {
+        let h = &s.header;
+        writeln!(
+            out,
+            "CPU {}: {} of {} exits, TSC {} kHz",
+            h.cpu_id, h.num_entries, h.total, h.tsc_khz
+        )?;
+        for e in &s.entries {
+            writeln!(
+                out,
+                "{:>16.3} us  CPU {:<3} {:<18} {:<20} RIP {:#018x} QUAL {:#018x} {:>8} cycles",
+                cycles_to_us(e.tsc, h.tsc_khz),
+                h.cpu_id,
+                kind_name(e.kind),
+                reason_name(h.vendor, e.reason),
+                e.rip,
+                e.qualification,
+                e.cycles,
+            )?;
+        }
+    }
+    Ok(())
+}
+
+fn print_chrome(snapshots: &[Snapshot], out: &mut impl io::Write) -> io::Result<()> {
+    let mut events = Vec::new();
+    for s in snapshots {
+        let h = &s.header;
+        for e in &s.entries {
+            let mut ev = String::new();
+            write!(
+                ev,
+                "{{\"name\":\"{}\",\"cat\":\"{}\",\"ph\":\"X\",\"ts\":{:.3},\"dur\":{:.3},\
+                \"pid\":0,\"tid\":{},\"args\":{{\"rip\":\"{:#x}\",\"qualification\":\"{:#x}\",\
+                \"cycles\":{}}}}}",
+                reason_name(h.vendor, e.reason),
+                kind_name(e.kind),
+                cycles_to_us(e.tsc, h.tsc_khz),
+                cycles_to_us(e.cycles, h.tsc_khz),
+                h.cpu_id,
+                e.rip,
+                e.qualification,
+                e.cycles,
+            )
+            .unwrap();
+            events.push(ev);
+        }
+    }
+    writeln!(out, "{{\"traceEvents\":[")?;
+    writeln!(out, "{}", events.join(",\n"))?;
+    writeln!(out, "],\"displayTimeUnit\":\"ns\"}}")
+}
+
+fn main() {
+    let mut chrome = false;
+    let mut files = Vec::new();
+    for arg in env::args().skip(1) {
+        match arg.as_str() {
+            "--chrome" => chrome = true,
+            _ => files.push(arg),
+        }
+    }
+    if files.is_empty() {
+        eprintln!("Usage: rvm-trace [--chrome] FILE...");
+        process::exit(1);
+    }
+
+    let mut snapshots = Vec::new();
+    for file in &files {
+        let data = fs::read(file).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        });
+        snapshots.extend(parse(&data).unwrap_or_else(|e| {
+            eprintln!("{}: {}", file, e);
+            process::exit(1);
+        }));
+    }
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let res = if chrome {
+        print_chrome(&snapshots, &mut out)
+    } else {
+        print_text(&snapshots, &mut out)
+    };
+    res.and_then(|_| out.flush()).unwrap();
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse() {
+        let mut data = Vec::new();
+        for v in [TRACE_MAGIC, TRACE_VERSION, TRACE_VENDOR_INTEL, 3] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data.extend_from_slice(&2_000_000u64.to_le_bytes());
+        data.extend_from_slice(&1024u32.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        data.extend_from_slice(&5u64.to_le_bytes());
+        for v in [100u64, 0xffff_8000_0000_1000, 0x10, 200] {
+            data.extend_from_slice(&v.to_le_bytes());
+        }
+        data.extend_from_slice(&10u32.to_le_bytes());
+        data.extend_from_slice(&4u32.to_le_bytes());
+
+        let snapshots = parse(&data).unwrap();
+        assert_eq!(snapshots.len(), 1);
+        assert_eq!(snapshots[0].header.cpu_id, 3);
+        assert_eq!(snapshots[0].header.total, 5);
+        assert_eq!(
+            snapshots[0].entries,
+            [Entry {
+                tsc: 100,
+                rip: 0xffff_8000_0000_1000,
+                qualification: 0x10,
+                cycles: 200,
+                reason: 10,
+                kind: 4,
+            }]
+        );
+        assert_eq!(reason_name(TRACE_VENDOR_INTEL, 10), "CPUID");
+        assert!(parse(&data[..HEADER_SIZE + 1]).is_err());
+    }
+}
@@ -0,0 +1,30 @@
+#!/bin/bash
+# Enable and disable the hypervisor repeatedly without reloading the driver.
+# Usage: ./stress-rvm.sh [ROUNDS]
+
+JH_DIR=~/jailhouse
+JH=$JH_DIR/tools/jailhouse
+ROUNDS=${1:-100}
+
+sudo $JH disable 2>/dev/null
+sudo rmmod jailhouse 2>/dev/null
+sudo insmod $JH_DIR/driver/jailhouse.ko || exit 1
+sudo chown $(whoami) /dev/jailhouse
+
+for i in $(seq 1 $ROUNDS); do
+	echo "Round $i/$ROUNDS"
+	if ! sudo $JH enable $JH_DIR/configs/x86/qemu-ubuntu.cell; then
+		echo "Enable failed in round $i"
+		exit 1
+	fi
+	# Generate some VM exits.
+	for j in $(seq 1 100); do
+		cat /proc/cpuinfo > /dev/null
+	done
+	if ! sudo $JH disable; then
+		echo "Disable failed in round $i"
+		exit 1
+	fi
+done
+
+echo "Passed $ROUNDS rounds."
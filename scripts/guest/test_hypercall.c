@@ -4,6 +4,11 @@
 
 #define HYPERCALL "vmcall"
 
+/* Hypercalls callable from user mode. */
+#define HC_USER_GET_VERSION 0x40000000
+#define HC_USER_GET_CELL_ID 0x40000001
+#define HC_USER_IVSHMEM_DOORBELL 0x40000002
+
 static void in_guest() {
 	printf("Execute VMCALL OK.\n");
 	printf("You are in the Guest mode.\n");
@@ -30,8 +35,10 @@ static inline long hypercall(int num) {
 int main () {
 	signal(SIGSEGV, sig_handler);
 	signal(SIGILL, sig_handler);
-	int ret = hypercall(2333);
-	if (ret == 2333) {
+	long version = hypercall(HC_USER_GET_VERSION);
+	if (version > 0) {
+		printf("RVM paravirt ABI %ld.%ld, cell %ld\n", version >> 16,
+		       version & 0xffff, hypercall(HC_USER_GET_CELL_ID));
 		in_guest();
 	} else {
 		in_host();
@@ -1,10 +1,15 @@
 mod npt;
+mod structs;
 mod vcpu;
 mod vmexit;
 
+use alloc::vec::Vec;
+
 use libvmm::svm::flags::{VmCr, VmCrFlags};
 
+use crate::arch::cpuid::CpuFeatures;
 use crate::error::HvResult;
+use crate::memory::GuestPhysAddr;
 
 pub use npt::NestedPageTable;
 pub use vcpu::Vcpu;
@@ -16,3 +21,19 @@ pub fn check_hypervisor_feature() -> HvResult {
     // TODO: check cpuid
     Ok(())
 }
+
+/// The maximum ASID, 0 is used by the host.
+pub fn max_asid() -> u16 {
+    CpuFeatures::new()
+        .svm_asid_count()
+        .saturating_sub(1)
+        .min(u16::MAX as u32) as u16
+}
+
+/// Dirty pages are logged by write-protection faults, no hardware support is used.
+pub fn set_hw_dirty_log(_enable: bool) {}
+
+/// Always returns `None`, see `set_hw_dirty_log()`.
+pub fn take_hw_dirty_log() -> Option<Vec<GuestPhysAddr>> {
+    None
+}
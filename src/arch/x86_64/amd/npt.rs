@@ -1,6 +1,7 @@
 use crate::arch::page_table::PTEntry;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
 use crate::memory::{GenericPTE, Level4PageTable, MemFlags, PagingInstr};
+use crate::percpu::PerCpu;
 
 #[repr(transparent)]
 #[derive(Clone, Debug)]
@@ -22,6 +23,10 @@ impl GenericPTE for NPTEntry {
     fn is_huge(&self) -> bool {
         self.0.is_huge()
     }
+    fn is_dirty(&self) -> bool {
+        // Dirty pages are logged by write-protection faults, see `MemorySet::handle_dirty_fault()`.
+        self.0.flags().contains(MemFlags::WRITE)
+    }
     fn set_addr(&mut self, paddr: HostPhysAddr) {
         self.0.set_addr(paddr);
     }
@@ -34,6 +39,10 @@ impl GenericPTE for NPTEntry {
     fn set_table(&mut self, paddr: HostPhysAddr) {
         self.0.set_table(paddr)
     }
+    fn clear_dirty(&mut self) {
+        self.0
+            .set_flags(self.0.flags() - MemFlags::WRITE, self.0.is_huge())
+    }
     fn clear(&mut self) {
         self.0.clear()
     }
@@ -42,8 +51,13 @@ impl GenericPTE for NPTEntry {
 pub struct NPTInstr;
 
 impl PagingInstr for NPTInstr {
-    unsafe fn activate(_root_paddr: HostPhysAddr) {}
-    fn flush(_vaddr: Option<usize>) {}
+    unsafe fn activate(root_paddr: HostPhysAddr) {
+        PerCpu::current_mut().vcpu.set_nested_page_table(root_paddr);
+    }
+
+    fn flush(_vaddr: Option<usize>) {
+        crate::arch::vmm::nested_tlb_shootdown();
+    }
 }
 
 pub type NestedPageTable = Level4PageTable<GuestPhysAddr, NPTEntry, NPTInstr>;
@@ -0,0 +1,36 @@
+use crate::memory::{addr::virt_to_phys, AlignedPage, PAGE_SIZE};
+
+/// MSR permission map, accesses to MSRs whose bits are set are intercepted.
+pub(super) struct MsrPermissionMap([AlignedPage; 2]);
+
+impl MsrPermissionMap {
+    fn intercept(&mut self, msr: u32, is_write: bool) {
+        // (AMD APM Volume 2, Section 15.11, MSR Intercepts)
+        // Each MSR is covered by two bits (read and write), in one of the 2-KByte vectors:
+        // 1. MSRs 0x0000_0000..0x0000_1FFF at offset 0x000
+        // 2. MSRs 0xC000_0000..0xC000_1FFF at offset 0x800
+        // 3. MSRs 0xC001_0000..0xC001_1FFF at offset 0x1000
+        let offset = match msr {
+            0..=0x1fff => 0,
+            0xc000_0000..=0xc000_1fff => 0x800,
+            0xc001_0000..=0xc001_1fff => 0x1000,
+            _ => panic!("MSR {:#x} is not covered by the permission map", msr),
+        };
+        let bit = (msr & 0x1fff) as usize * 2 + is_write as usize;
+        let byte = offset + bit / 8;
+        self.0[byte / PAGE_SIZE][byte % PAGE_SIZE] |= 1 << (bit % 8);
+    }
+
+    pub fn paddr(&self) -> usize {
+        virt_to_phys(self.0.as_ptr() as usize)
+    }
+}
+
+impl Default for MsrPermissionMap {
+    fn default() -> Self {
+        // Other MSRs are passed through.
+        let mut map = Self([AlignedPage::new(), AlignedPage::new()]);
+        map.intercept(0x830, true); // IA32_X2APIC_ICR, to deliver INIT and SIPI to parked CPUs
+        map
+    }
+}
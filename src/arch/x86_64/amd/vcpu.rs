@@ -1,5 +1,6 @@
 use core::arch::asm;
 use core::fmt::{Debug, Formatter, Result};
+use core::sync::atomic::{AtomicBool, Ordering};
 
 use libvmm::msr::Msr;
 use libvmm::svm::flags::{InterruptType, VmcbCleanBits, VmcbIntInfo, VmcbTlbControl};
@@ -11,14 +12,26 @@ use x86_64::registers::model_specific::{Efer, EferFlags};
 use x86_64::registers::rflags::RFlags;
 use x86_64::structures::DescriptorTablePointer;
 
-use crate::arch::segmentation::Segment;
+use super::structs::MsrPermissionMap;
+use crate::arch::cpuid::{cpuid, CpuFeatures, CpuIdEax};
+use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::{GeneralRegisters, GuestPageTableImmut, LinuxContext};
+use crate::arch::{GeneralRegisters, GuestPageTable, LinuxContext};
 use crate::cell::Cell;
+use crate::config::CellFlags;
 use crate::error::HvResult;
-use crate::memory::{addr::virt_to_phys, Frame, GenericPageTableImmut};
+use crate::memory::{addr::virt_to_phys, Frame, GenericPageTableImmut, HostPhysAddr};
 use crate::percpu::PerCpu;
 
+/// PAUSEs in a loop before the VM exit, and the max cycles between two PAUSEs in the same loop
+/// if supported, the same as KVM.
+const PAUSE_FILTER_COUNT: u16 = 3000;
+const PAUSE_FILTER_THRESH: u16 = 128;
+
+lazy_static! {
+    static ref MSR_PERMISSION_MAP: MsrPermissionMap = MsrPermissionMap::default();
+}
+
 #[repr(C)]
 pub struct Vcpu {
     /// Save guest general registers when handle VM exits.
@@ -31,6 +44,8 @@ pub struct Vcpu {
     host_save_area: Frame,
     /// Virtual machine control block.
     pub(super) vmcb: Vmcb,
+    /// Flush the guest ASID on the next VMRUN, may be set in the NMI handler.
+    tlb_flush_pending: AtomicBool,
 }
 
 impl Vcpu {
@@ -61,9 +76,13 @@ impl Vcpu {
         info!("successed to turn on SVM.");
 
         // bring CR0 and CR4 into well-defined states.
+        let mut cr4 = super::super::HOST_CR4;
+        if CpuFeatures::new().has_xsave() {
+            cr4 |= Cr4Flags::OSXSAVE;
+        }
         unsafe {
             Cr0::write(super::super::HOST_CR0);
-            Cr4::write(super::super::HOST_CR4);
+            Cr4::write(cr4);
         }
 
         let cpu_data = PerCpu::current();
@@ -73,6 +92,7 @@ impl Vcpu {
             host_stack_top: cpu_data.stack_top() as _,
             host_save_area,
             vmcb: Default::default(),
+            tlb_flush_pending: AtomicBool::new(false),
         };
         ret.vmcb_setup(linux, cell);
 
@@ -89,9 +109,11 @@ impl Vcpu {
         regs.r13 = linux.r13;
         regs.r14 = linux.r14;
         regs.r15 = linux.r15;
+        unsafe { asm!("clgi") };
+        self.apply_nested_tlb_flush();
+        let regs = self.regs();
         unsafe {
             asm!(
-                "clgi",
                 "mov rsp, {0}",
                 restore_regs_from_stack!(),
                 "vmload rax",
@@ -105,6 +127,12 @@ impl Vcpu {
 
     pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
         self.load_vmcb_guest(linux);
+        self.disable()
+    }
+
+    /// Turn off SVM without loading the guest state to Linux. CR0 and CR4 should be restored by
+    /// the caller.
+    pub fn disable(&self) -> HvResult {
         unsafe {
             asm!("stgi");
             Efer::write(Efer::read() - EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE);
@@ -114,6 +142,120 @@ impl Vcpu {
         Ok(())
     }
 
+    /// Reset the guest state as INIT followed by SIPI with `vector` does, the guest starts in
+    /// real mode at `vector << 12`.
+    pub fn reset(&mut self, cell: &Cell, vector: u8) -> HvResult {
+        use SegmentAccessRights as AR;
+        let data_ar = AR::PRESENT | AR::CODE_DATA | AR::WRITABLE | AR::ACCESSED;
+        let code_ar = data_ar | AR::EXECUTABLE;
+        let data_seg = Segment::real_mode(0, data_ar);
+        let real_mode_dtr = DescriptorTablePointer {
+            limit: 0xffff,
+            base: VirtAddr::new(0),
+        };
+
+        self.set_cr(0, super::super::INIT_CR0.bits());
+        self.set_cr(4, 0);
+        self.set_cr(3, 0);
+        let vmcb = &mut self.vmcb.save;
+        Self::set_vmcb_segment(&mut vmcb.es, &data_seg);
+        Self::set_vmcb_segment(
+            &mut vmcb.cs,
+            &Segment::real_mode((vector as u16) << 8, code_ar),
+        );
+        Self::set_vmcb_segment(&mut vmcb.ss, &data_seg);
+        Self::set_vmcb_segment(&mut vmcb.ds, &data_seg);
+        Self::set_vmcb_segment(&mut vmcb.fs, &data_seg);
+        Self::set_vmcb_segment(&mut vmcb.gs, &data_seg);
+        Self::set_vmcb_segment(
+            &mut vmcb.tr,
+            &Segment::real_mode(0, AR::PRESENT | AR::TSS_BUSY),
+        );
+        Self::set_vmcb_segment(&mut vmcb.ldtr, &Segment::invalid());
+        Self::set_vmcb_dtr(&mut vmcb.idtr, &real_mode_dtr);
+        Self::set_vmcb_dtr(&mut vmcb.gdtr, &real_mode_dtr);
+        vmcb.cpl = 0;
+        vmcb.rflags = 0x2;
+        vmcb.rip = 0;
+        vmcb.rsp = 0;
+        vmcb.rax = 0;
+        vmcb.cr2 = 0;
+        vmcb.efer = EferFlags::SECURE_VIRTUAL_MACHINE_ENABLE.bits();
+        vmcb.dr7 = 0x400;
+        vmcb.dr6 = 0xffff_0ff0;
+        self.vmcb.control.event_inj = 0;
+        self.vmcb.control.clean_bits = VmcbCleanBits::empty();
+
+        // FS, GS, TR and LDTR are only loaded by VMLOAD (see `enter()`), which also overwrites
+        // the GS base of the hypervisor.
+        let vmcb_paddr = virt_to_phys(&self.vmcb as *const _ as usize);
+        unsafe {
+            let host_gs_base = Msr::IA32_GS_BASE.read();
+            asm!("vmload rax", in("rax") vmcb_paddr);
+            Msr::IA32_GS_BASE.write(host_gs_base);
+        }
+
+        self.guest_regs = Default::default();
+        self.guest_regs.rdx = cpuid!(CpuIdEax::FeatureInfo as u32).eax as _;
+        self.load_cell(cell);
+        Ok(())
+    }
+
+    /// Emulate a guest write to CR0, EFER.LMA changes as the processor does when paging is
+    /// enabled or disabled with EFER.LME set.
+    pub fn write_guest_cr0(&mut self, val: u64) -> HvResult {
+        self.set_cr(0, val);
+        let efer = &mut self.vmcb.save.efer;
+        let paging = Cr0Flags::from_bits_truncate(val).contains(Cr0Flags::PAGING);
+        if paging && *efer & EferFlags::LONG_MODE_ENABLE.bits() != 0 {
+            *efer |= EferFlags::LONG_MODE_ACTIVE.bits();
+        } else {
+            *efer &= !EferFlags::LONG_MODE_ACTIVE.bits();
+        }
+        Ok(())
+    }
+
+    /// CR4 bits the guest can set. There are no capability MSRs as VMX has, so the bits are
+    /// derived from CPUID, the others are reserved. VMX and SMX are not supported.
+    pub fn supported_cr4_bits() -> u64 {
+        static SUPPORTED_CR4_BITS: spin::Once<u64> = spin::Once::new();
+        *SUPPORTED_CR4_BITS.call_once(|| {
+            let bit = |reg: u32, n: u32, flags: Cr4Flags| {
+                if reg & (1 << n) != 0 {
+                    flags
+                } else {
+                    Cr4Flags::empty()
+                }
+            };
+            let leaf1 = cpuid!(CpuIdEax::FeatureInfo as u32);
+            let mut flags = Cr4Flags::PROTECTED_MODE_VIRTUAL_INTERRUPTS
+                | Cr4Flags::PERFORMANCE_MONITOR_COUNTER
+                | bit(leaf1.edx, 1, Cr4Flags::VIRTUAL_8086_MODE_EXTENSIONS)
+                | bit(leaf1.edx, 2, Cr4Flags::DEBUGGING_EXTENSIONS)
+                | bit(leaf1.edx, 3, Cr4Flags::PAGE_SIZE_EXTENSION)
+                | bit(leaf1.edx, 4, Cr4Flags::TIMESTAMP_DISABLE)
+                | bit(leaf1.edx, 6, Cr4Flags::PHYSICAL_ADDRESS_EXTENSION)
+                | bit(leaf1.edx, 7, Cr4Flags::MACHINE_CHECK_EXCEPTION)
+                | bit(leaf1.edx, 13, Cr4Flags::PAGE_GLOBAL)
+                | bit(leaf1.edx, 24, Cr4Flags::OSFXSR)
+                | bit(leaf1.edx, 25, Cr4Flags::OSXMMEXCPT_ENABLE)
+                | bit(leaf1.ecx, 17, Cr4Flags::PCID)
+                | bit(leaf1.ecx, 26, Cr4Flags::OSXSAVE);
+            if cpuid!(CpuIdEax::VendorInfo as u32).eax >= 7 {
+                let leaf7 = cpuid!(7, 0);
+                flags |= bit(leaf7.ebx, 0, Cr4Flags::FSGSBASE)
+                    | bit(leaf7.ebx, 7, Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION)
+                    | bit(leaf7.ebx, 20, Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+                    | bit(leaf7.ecx, 2, Cr4Flags::USER_MODE_INSTRUCTION_PREVENTION)
+                    | bit(leaf7.ecx, 3, Cr4Flags::PROTECTION_KEY_USER)
+                    | bit(leaf7.ecx, 7, Cr4Flags::CONTROL_FLOW_ENFORCEMENT)
+                    | bit(leaf7.ecx, 16, Cr4Flags::L5_PAGING)
+                    | bit(leaf7.ecx, 31, Cr4Flags::PROTECTION_KEY_SUPERVISOR);
+            }
+            flags.bits()
+        })
+    }
+
     pub fn inject_fault(&mut self) -> HvResult {
         self.vmcb.inject_event(
             VmcbIntInfo::from(
@@ -127,6 +269,8 @@ impl Vcpu {
 
     pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
         self.vmcb.save.rip += instr_len as u64;
+        // The interrupt shadow ends after the instruction, e.g. STI; HLT.
+        self.vmcb.control.int_state &= !1;
         Ok(())
     }
 
@@ -141,13 +285,73 @@ impl Vcpu {
         )
     }
 
-    pub fn guest_page_table(&self) -> GuestPageTableImmut {
-        use crate::memory::addr::align_down;
-        unsafe { GuestPageTableImmut::from_root(align_down(self.vmcb.save.cr3 as _)) }
+    pub fn guest_page_table(&self) -> GuestPageTable {
+        GuestPageTable::new(self)
+    }
+
+    /// Flush all TLB entries of the guest ASID on the next VMRUN.
+    pub fn flush_nested_tlb(&self) -> HvResult {
+        self.tlb_flush_pending.store(true, Ordering::Release);
+        Ok(())
+    }
+
+    /// Request the flush set by `flush_nested_tlb()` in the VMCB, called with GIF clear so that
+    /// no flush from the NMI handler is missed before VMRUN.
+    fn apply_nested_tlb_flush(&mut self) {
+        if self.tlb_flush_pending.swap(false, Ordering::AcqRel) {
+            self.vmcb.control.tlb_control = VmcbTlbControl::FlushAsid as _;
+        }
+    }
+
+    /// Run this vCPU in `cell`: switch to its nested page table and ASID, flush TLB entries
+    /// tagged with the ASID, which may be left by a previous cell owning the same ASID, and
+    /// apply the HLT and PAUSE exiting of the cell.
+    pub fn load_cell(&mut self, cell: &Cell) {
+        self.set_idle_intercepts(cell);
+        self.vmcb.control.guest_asid = cell.asid as _;
+        self.vmcb.control.clean_bits -= VmcbCleanBits::ASID;
+        // Also requests the ASID flush.
+        self.set_nested_page_table(cell.gpm().page_table().root_paddr());
+    }
+
+    /// Dirty pages are logged by write-protection faults, nothing to do.
+    pub fn sync_dirty_log(&mut self) -> HvResult {
+        Ok(())
+    }
+
+    pub fn set_nested_page_table(&mut self, root_paddr: HostPhysAddr) {
+        self.vmcb.control.nest_cr3 = root_paddr as _;
+        self.vmcb.control.clean_bits -= VmcbCleanBits::NP;
+        self.vmcb.control.tlb_control = VmcbTlbControl::FlushAsid as _;
     }
 }
 
 impl Vcpu {
+    fn set_idle_intercepts(&mut self, cell: &Cell) {
+        if super::super::hlt_exiting(cell) {
+            self.vmcb.set_intercept(SvmIntercept::HLT);
+            self.vmcb.set_intercept(SvmIntercept::MWAIT);
+            self.vmcb.set_intercept(SvmIntercept::MWAIT_CONDITIONAL);
+        } else {
+            self.vmcb.clear_intercept(SvmIntercept::HLT);
+            self.vmcb.clear_intercept(SvmIntercept::MWAIT);
+            self.vmcb.clear_intercept(SvmIntercept::MWAIT_CONDITIONAL);
+        }
+        let features = CpuFeatures::new();
+        if cell.config.flags().contains(CellFlags::PAUSE_LOOP_EXITING)
+            && features.has_pause_filter()
+        {
+            self.vmcb.set_intercept(SvmIntercept::PAUSE);
+            self.vmcb.control.pause_filter_count = PAUSE_FILTER_COUNT;
+            if features.has_pause_filter_threshold() {
+                self.vmcb.control.pause_filter_thresh = PAUSE_FILTER_THRESH;
+            }
+        } else {
+            self.vmcb.clear_intercept(SvmIntercept::PAUSE);
+        }
+        self.vmcb.control.clean_bits -= VmcbCleanBits::I;
+    }
+
     fn set_vmcb_dtr(vmcb_seg: &mut VmcbSegment, dtr: &DescriptorTablePointer) {
         vmcb_seg.limit = dtr.limit as u32 & 0xffff;
         vmcb_seg.base = dtr.base.as_u64();
@@ -197,13 +401,20 @@ impl Vcpu {
         let vmcb = &mut self.vmcb.control;
         vmcb.intercept_exceptions = 0;
         vmcb.np_enable = 1;
-        vmcb.guest_asid = 1; // No more than one guest owns the CPU
         vmcb.clean_bits = VmcbCleanBits::empty(); // Explicitly mark all of the state as new
-        vmcb.nest_cr3 = cell.gpm.page_table().root_paddr() as _;
-        vmcb.tlb_control = VmcbTlbControl::FlushAsid as _;
-
+        self.load_cell(cell);
+
+        self.vmcb.control.msrpm_base_pa = MSR_PERMISSION_MAP.paddr() as _;
+        self.vmcb.set_intercept(SvmIntercept::MSR_PROT);
+        // Check CR0 and CR4 writes, which requires the GPR of MOV CR. CR0 writes that only
+        // change TS or MP are not intercepted.
+        if CpuFeatures::new().has_svm_decode_assists() {
+            self.vmcb.set_intercept(SvmIntercept::CR0_SEL_WRITE);
+            self.vmcb.set_intercept(SvmIntercept::CR4_WRITE);
+        }
         self.vmcb.set_intercept(SvmIntercept::NMI);
         self.vmcb.set_intercept(SvmIntercept::CPUID);
+        self.vmcb.set_intercept(SvmIntercept::XSETBV);
         self.vmcb.set_intercept(SvmIntercept::SHUTDOWN);
         self.vmcb.set_intercept(SvmIntercept::VMRUN);
         self.vmcb.set_intercept(SvmIntercept::VMMCALL);
@@ -291,6 +502,11 @@ impl VcpuAccessGuestState for Vcpu {
             4 => self.vmcb.save.cr4 = val,
             _ => unreachable!(),
         }
+        self.vmcb.control.clean_bits -= VmcbCleanBits::CR_X;
+    }
+
+    fn efer(&self) -> u64 {
+        self.vmcb.save.efer
     }
 }
 
@@ -337,6 +553,12 @@ extern "sysv64" fn vmexit_handler_wrapper(cpu_data: &mut PerCpu) {
     let guest_tp = Msr::IA32_GS_BASE.read();
     cpu_data.vcpu.vmcb.save.gs.base = guest_tp;
     unsafe { Msr::IA32_GS_BASE.write(cpu_data as *const _ as u64) };
+    // Take NMIs while handling the VM exit (IF is still clear), so that events sent by other
+    // CPUs are handled even if this CPU spins on a lock held by the sender.
+    unsafe { asm!("stgi") };
     crate::arch::vmm::vmexit_handler();
-    unsafe { Msr::IA32_GS_BASE.write(guest_tp) };
+    unsafe { asm!("clgi") };
+    cpu_data.vcpu.apply_nested_tlb_flush();
+    // May be changed if the vCPU is reset.
+    unsafe { Msr::IA32_GS_BASE.write(cpu_data.vcpu.vmcb.save.gs.base) };
 }
@@ -1,16 +1,19 @@
-use libvmm::svm::flags::VmcbCleanBits;
+use bit_field::BitField;
+use libvmm::svm::flags::{VmcbCleanBits, VmcbTlbControl};
 use libvmm::svm::{SvmExitCode, VmExitInfo};
 
-use crate::arch::vmm::{VcpuAccessGuestState, VmExit};
+use crate::arch::vmm::{ExitKind, VcpuAccessGuestState, VmExit};
 use crate::error::HvResult;
+use crate::percpu::CpuStat;
 
 impl VmExit<'_> {
     fn handle_nmi(&mut self) -> HvResult {
-        unsafe { core::arch::asm!("stgi; clgi") };
+        // Already taken by the host NMI handler, as GIF is set while handling VM exits.
         Ok(())
     }
 
     fn handle_exception(&mut self, vec: u8, exit_info: &VmExitInfo) -> HvResult {
+        self.cpu_data.inc_stat(CpuStat::VmExitsException);
         info!(
             "#VMEXIT(EXCP {}) @ RIP({:#x}): {:#x?}",
             vec, exit_info.guest_rip, exit_info
@@ -21,6 +24,17 @@ impl VmExit<'_> {
 
     fn handle_nested_page_fault(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let guest_paddr = exit_info.exit_info_2;
+        // Write to a present page, may be write-protected for dirty logging.
+        if exit_info.exit_info_1 & 0b11 == 0b11
+            && self
+                .cpu_data
+                .cell()
+                .gpm_mut()
+                .handle_dirty_fault(guest_paddr as usize)?
+        {
+            return Ok(());
+        }
+        self.cpu_data.inc_stat(CpuStat::VmExitsMmio);
         warn!(
             "#VMEXIT(NPF) @ {:#x} RIP({:#x}, {:#x})",
             guest_paddr, exit_info.guest_rip, exit_info.guest_next_rip,
@@ -28,6 +42,30 @@ impl VmExit<'_> {
         hv_result_err!(ENOSYS)
     }
 
+    /// CR0 writes changing bits other than TS and MP, and all CR4 writes are intercepted if
+    /// decode assists are supported, which provide the GPR of MOV CR in EXITINFO1. LMSW changing
+    /// PE or EM is not supported. CR reads are not intercepted, but handled anyway.
+    fn handle_cr_intercept(&mut self, cr: u8, is_write: bool, exit_info: &VmExitInfo) -> HvResult {
+        if !exit_info.exit_info_1.get_bit(63) {
+            return hv_result_err!(ENOSYS, "Only MOV CR is supported");
+        }
+        let gpr = exit_info.exit_info_1.get_bits(0..4) as u8;
+        let instr_len = (exit_info.guest_next_rip - exit_info.guest_rip) as u8;
+        if is_write {
+            let val = self.cpu_data.vcpu.gpr(gpr);
+            self.handle_cr_write(cr, val, instr_len)
+        } else {
+            self.handle_cr_read(cr, gpr, instr_len)
+        }
+    }
+
+    /// Returns the exit code and EXITINFO1.
+    #[cfg(feature = "trace")]
+    pub fn raw_exit_info(&self) -> (u32, u64) {
+        let control = &self.cpu_data.vcpu.vmcb.control;
+        (control.exit_code as u32, control.exit_info_1)
+    }
+
     pub fn handle_exit(&mut self) -> HvResult {
         let vcpu = &mut self.cpu_data.vcpu;
         vcpu.regs_mut().rax = vcpu.vmcb.save.rax;
@@ -35,6 +73,8 @@ impl VmExit<'_> {
         // All guest state is marked unmodified; individual handlers must clear
         // the bits as needed.
         vcpu.vmcb.control.clean_bits = VmcbCleanBits::UNMODIFIED;
+        // No TLB flush unless requested during this VM exit.
+        vcpu.vmcb.control.tlb_control = VmcbTlbControl::DoNotFlush as _;
 
         let exit_info = VmExitInfo::new(&vcpu.vmcb);
         let exit_code = match exit_info.exit_code {
@@ -45,13 +85,39 @@ impl VmExit<'_> {
             }
         };
 
+        self.kind = match exit_code {
+            SvmExitCode::EXCP(_) => ExitKind::Exception,
+            SvmExitCode::NMI => ExitKind::Nmi,
+            SvmExitCode::INTR => ExitKind::ExternalInterrupt,
+            SvmExitCode::CPUID => ExitKind::Cpuid,
+            SvmExitCode::VMMCALL => ExitKind::Hypercall,
+            SvmExitCode::MSR if exit_info.exit_info_1 == 0 => ExitKind::MsrRead,
+            SvmExitCode::MSR => ExitKind::MsrWrite,
+            SvmExitCode::NPF => ExitKind::NestedPageFault,
+            SvmExitCode::IOIO => ExitKind::Io,
+            SvmExitCode::CR_READ(_)
+            | SvmExitCode::CR_WRITE(_)
+            | SvmExitCode::CR0_SEL_WRITE
+            | SvmExitCode::CR_WRITE_TRAP(_) => ExitKind::CrAccess,
+            SvmExitCode::HLT | SvmExitCode::MWAIT | SvmExitCode::MWAIT_CONDITIONAL => ExitKind::Hlt,
+            SvmExitCode::PAUSE => ExitKind::Pause,
+            SvmExitCode::SHUTDOWN => ExitKind::Shutdown,
+            _ => ExitKind::Other,
+        };
         let res = match exit_code {
             SvmExitCode::INVALID => panic!("VM entry failed: {:#x?}\n{:#x?}", exit_info, vcpu.vmcb),
             SvmExitCode::EXCP(vec) => self.handle_exception(vec, &exit_info),
             SvmExitCode::NMI => self.handle_nmi(),
             SvmExitCode::CPUID => self.handle_cpuid(),
+            SvmExitCode::XSETBV => self.handle_xsetbv(),
             SvmExitCode::VMMCALL => self.handle_hypercall(),
             SvmExitCode::NPF => self.handle_nested_page_fault(&exit_info),
+            SvmExitCode::CR_READ(cr) => self.handle_cr_intercept(cr, false, &exit_info),
+            SvmExitCode::CR_WRITE(cr) => self.handle_cr_intercept(cr, true, &exit_info),
+            SvmExitCode::CR0_SEL_WRITE => self.handle_cr_intercept(0, true, &exit_info),
+            SvmExitCode::HLT => self.handle_hlt(),
+            SvmExitCode::MWAIT | SvmExitCode::MWAIT_CONDITIONAL => self.handle_mwait(),
+            SvmExitCode::PAUSE => self.handle_pause(),
             SvmExitCode::MSR => match exit_info.exit_info_1 {
                 0 => self.handle_msr_read(),
                 1 => self.handle_msr_write(),
@@ -0,0 +1,144 @@
+//! Local APIC access for inter-processor interrupts, supports both xAPIC and x2APIC modes.
+
+use bit_field::BitField;
+use libvmm::msr::Msr;
+
+use super::cpuid::{cpuid, CpuIdEax};
+use crate::error::HvResult;
+use crate::memory::{addr::phys_to_virt, MemFlags, MemoryRegion, PAGE_SIZE};
+
+const APIC_BASE_X2APIC_ENABLE: usize = 10;
+
+const XAPIC_ICR_LOW: usize = 0x300;
+const XAPIC_ICR_HIGH: usize = 0x310;
+
+const ICR_DELIVERY_MODE_NMI: u32 = 0b100 << 8;
+const ICR_DELIVERY_MODE_INIT: u64 = 0b101;
+const ICR_DELIVERY_MODE_STARTUP: u64 = 0b110;
+const ICR_DESTINATION_LOGICAL: usize = 11;
+const ICR_DELIVERY_STATUS: usize = 12;
+const ICR_LEVEL_ASSERT: u32 = 1 << 14;
+const ICR_SHORTHAND_NONE: u64 = 0b00;
+const ICR_SHORTHAND_ALL_EXCLUDING_SELF: u64 = 0b11;
+
+/// INIT and startup IPIs decoded from an x2APIC ICR write, other IPIs are not decoded.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum StartupIpi {
+    /// INIT with level assert.
+    Init,
+    /// INIT level de-assert, only meaningful for legacy APICs.
+    InitDeassert,
+    /// Startup IPI with the vector, the target starts in real mode at `vector << 12`.
+    Startup(u8),
+}
+
+/// Destination of an IPI.
+#[derive(Debug, Clone, Copy, Eq, PartialEq)]
+pub enum IpiDestination {
+    /// The CPU with the local APIC ID.
+    Physical(u32),
+    AllExcludingSelf,
+    /// Logical destinations and other shorthands.
+    Unsupported,
+}
+
+impl StartupIpi {
+    /// Decode the value written to the x2APIC ICR, returns `None` if it is not an INIT or
+    /// startup IPI.
+    pub fn from_x2apic_icr(icr: u64) -> Option<(Self, IpiDestination)> {
+        let ipi = match icr.get_bits(8..11) {
+            ICR_DELIVERY_MODE_INIT if icr & ICR_LEVEL_ASSERT as u64 != 0 => Self::Init,
+            ICR_DELIVERY_MODE_INIT => Self::InitDeassert,
+            ICR_DELIVERY_MODE_STARTUP => Self::Startup(icr.get_bits(0..8) as u8),
+            _ => return None,
+        };
+        let dest = match icr.get_bits(18..20) {
+            ICR_SHORTHAND_NONE if !icr.get_bit(ICR_DESTINATION_LOGICAL) => {
+                IpiDestination::Physical(icr.get_bits(32..64) as u32)
+            }
+            ICR_SHORTHAND_ALL_EXCLUDING_SELF => IpiDestination::AllExcludingSelf,
+            _ => IpiDestination::Unsupported,
+        };
+        Some((ipi, dest))
+    }
+}
+
+/// Returns whether the local APIC is in x2APIC mode.
+pub fn is_x2apic() -> bool {
+    Msr::IA32_APIC_BASE.read().get_bit(APIC_BASE_X2APIC_ENABLE)
+}
+
+fn xapic_paddr() -> usize {
+    (Msr::IA32_APIC_BASE.read().get_bits(12..52) << 12) as usize
+}
+
+fn xapic_base() -> usize {
+    phys_to_virt(xapic_paddr())
+}
+
+unsafe fn xapic_read(reg: usize) -> u32 {
+    core::ptr::read_volatile((xapic_base() + reg) as *const u32)
+}
+
+unsafe fn xapic_write(reg: usize, val: u32) {
+    core::ptr::write_volatile((xapic_base() + reg) as *mut u32, val)
+}
+
+/// Map the xAPIC MMIO page into the hypervisor page table, if it's not in x2APIC mode.
+pub fn init() -> HvResult {
+    if is_x2apic() {
+        return Ok(());
+    }
+    let paddr = xapic_paddr();
+    crate::memory::hv_page_table()
+        .write()
+        .insert(MemoryRegion::new_with_offset_mapper(
+            phys_to_virt(paddr),
+            paddr,
+            PAGE_SIZE,
+            MemFlags::READ | MemFlags::WRITE | MemFlags::IO,
+        ))
+}
+
+/// Returns the local APIC ID of the current CPU.
+pub fn apic_id() -> u32 {
+    if is_x2apic() {
+        Msr::IA32_X2APIC_APICID.read() as u32
+    } else {
+        // The xAPIC page may not be mapped yet, use the initial APIC ID instead (Linux never
+        // changes it).
+        cpuid!(CpuIdEax::FeatureInfo as u32).ebx >> 24
+    }
+}
+
+/// Write `icr` to the x2APIC ICR on behalf of the guest.
+pub fn write_x2apic_icr(icr: u64) {
+    unsafe { Msr::IA32_X2APIC_ICR.write(icr) };
+}
+
+/// Send an NMI to the CPU whose local APIC ID is `apic_id`.
+pub fn send_nmi(apic_id: u32) {
+    send_ipi_raw(apic_id, ICR_DELIVERY_MODE_NMI | ICR_LEVEL_ASSERT);
+}
+
+/// Send a fixed interrupt with `vector` to the CPU whose local APIC ID is `apic_id`.
+pub fn send_ipi(apic_id: u32, vector: u8) {
+    send_ipi_raw(apic_id, vector as u32 | ICR_LEVEL_ASSERT);
+}
+
+fn send_ipi_raw(apic_id: u32, icr_low: u32) {
+    if is_x2apic() {
+        unsafe { Msr::IA32_X2APIC_ICR.write(((apic_id as u64) << 32) | icr_low as u64) };
+    } else {
+        unsafe {
+            // The guest may be interrupted between its writes to ICR_HIGH and ICR_LOW.
+            let saved_icr_high = xapic_read(XAPIC_ICR_HIGH);
+            xapic_write(XAPIC_ICR_HIGH, apic_id << 24);
+            xapic_write(XAPIC_ICR_LOW, icr_low);
+            while xapic_read(XAPIC_ICR_LOW).get_bit(ICR_DELIVERY_STATUS) {
+                core::hint::spin_loop();
+            }
+            xapic_write(XAPIC_ICR_HIGH, saved_icr_high);
+        }
+    }
+}
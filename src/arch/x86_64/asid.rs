@@ -0,0 +1,31 @@
+//! Identifiers to tag TLB entries of each cell: VPIDs on Intel, ASIDs on AMD.
+
+use alloc::collections::BTreeMap;
+
+use spin::Mutex;
+
+use super::vmm::max_asid;
+use crate::error::HvResult;
+
+lazy_static! {
+    /// Cell ID -> VPID/ASID.
+    static ref CELL_ASIDS: Mutex<BTreeMap<u32, u16>> = Mutex::new(BTreeMap::new());
+}
+
+/// Allocate a VPID/ASID for the cell `cell_id`. 0 is reserved for the hypervisor.
+pub fn alloc(cell_id: u32) -> HvResult<u16> {
+    let mut asids = CELL_ASIDS.lock();
+    if asids.contains_key(&cell_id) {
+        return hv_result_err!(EEXIST, format!("Cell {} already has an ASID", cell_id));
+    }
+    let asid = (1..=max_asid())
+        .find(|id| !asids.values().any(|used| used == id))
+        .ok_or_else(|| hv_err!(ENOMEM, "No free ASID"))?;
+    asids.insert(cell_id, asid);
+    Ok(asid)
+}
+
+/// Free the VPID/ASID of the cell `cell_id`.
+pub fn free(cell_id: u32) {
+    CELL_ASIDS.lock().remove(&cell_id);
+}
@@ -45,7 +45,7 @@ pub struct LinuxContext {
 }
 
 #[repr(C)]
-#[derive(Debug, Default)]
+#[derive(Debug, Default, Clone)]
 pub struct GeneralRegisters {
     pub rax: u64,
     pub rcx: u64,
@@ -65,6 +65,21 @@ pub struct GeneralRegisters {
     pub r15: u64,
 }
 
+impl GeneralRegisters {
+    /// Returns the register numbered `index` in instruction encodings (0: RAX, ..., 15: R15).
+    /// RSP is not saved here and must be read from the guest state.
+    pub fn get_reg_of_index(&self, index: u8) -> u64 {
+        assert!(index < 16 && index != 4, "Invalid register index {}", index);
+        unsafe { *(self as *const _ as *const u64).add(index as usize) }
+    }
+
+    /// Set the register numbered `index` in instruction encodings, see `get_reg_of_index()`.
+    pub fn set_reg_of_index(&mut self, index: u8, val: u64) {
+        assert!(index < 16 && index != 4, "Invalid register index {}", index);
+        unsafe { *(self as *mut _ as *mut u64).add(index as usize) = val };
+    }
+}
+
 macro_rules! save_regs_to_stack {
     () => {
         "
@@ -151,9 +166,12 @@ impl LinuxContext {
         }
     }
 
-    /// Restore system registers.
+    /// Restore system registers. The GS base is kept for `PerCpu::current()`, and restored by
+    /// `return_to_linux()` (or by the caller of the hypervisor entry).
     pub fn restore(&self) {
         unsafe {
+            let hv_gs_base = Msr::IA32_GS_BASE.read();
+
             Msr::IA32_EFER.write(self.efer);
             Msr::IA32_STAR.write(self.star);
             Msr::IA32_LSTAR.write(self.lstar);
@@ -190,6 +208,7 @@ impl LinuxContext {
             segmentation::load_gs(self.gs.selector);
 
             Msr::IA32_FS_BASE.write(self.fs.base);
+            Msr::IA32_GS_BASE.write(hv_gs_base);
         }
     }
 
@@ -14,6 +14,59 @@ pub fn frequency() -> u16 {
     })
 }
 
+/// Whether MWAIT can be woken up by pending interrupts while they are disabled, which is
+/// required to idle with `wait_for_write(.., true, ..)`.
+pub fn has_mwait_interrupt_break() -> bool {
+    static HAS_MWAIT_INTERRUPT_BREAK: spin::Once<bool> = spin::Once::new();
+    *HAS_MWAIT_INTERRUPT_BREAK.call_once(|| {
+        let cpuid = CpuId::new();
+        has_monitor_mwait()
+            && cpuid.get_monitor_mwait_info().map_or(false, |info| {
+                info.extensions_supported() && info.interrupts_as_break_event()
+            })
+    })
+}
+
+fn has_monitor_mwait() -> bool {
+    static HAS_MONITOR_MWAIT: spin::Once<bool> = spin::Once::new();
+    *HAS_MONITOR_MWAIT.call_once(|| {
+        CpuId::new()
+            .get_feature_info()
+            .map_or(false, |info| info.has_monitor_mwait())
+    })
+}
+
+/// Sleep until `addr` is written by another CPU or an NMI arrives, and also until an external
+/// interrupt is pending if `wake_on_interrupt`. Interrupts stay disabled, so pending ones are
+/// delivered to the guest after the next VM entry.
+///
+/// Returns at once if `pending()` is true after `addr` is monitored, so that no writes are
+/// missed. Only spins once if MONITOR/MWAIT is not supported.
+pub fn wait_for_write<T>(addr: &T, wake_on_interrupt: bool, pending: impl FnOnce() -> bool) {
+    if !has_monitor_mwait() || (wake_on_interrupt && !has_mwait_interrupt_break()) {
+        core::hint::spin_loop();
+        return;
+    }
+    unsafe {
+        core::arch::asm!(
+            "monitor",
+            in("rax") addr as *const T,
+            in("ecx") 0,
+            in("edx") 0,
+            options(nostack)
+        );
+        if !pending() {
+            // Hint 0 for C1, ECX bit 0 to treat masked interrupts as break events.
+            core::arch::asm!(
+                "mwait",
+                in("eax") 0,
+                in("ecx") wake_on_interrupt as u32,
+                options(nostack)
+            );
+        }
+    }
+}
+
 pub fn current_cycle() -> u64 {
     let mut aux = 0;
     unsafe { core::arch::x86_64::__rdtscp(&mut aux) }
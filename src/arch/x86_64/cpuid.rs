@@ -10,9 +10,15 @@ pub use raw_cpuid::{cpuid, CpuId};
 pub(super) enum CpuIdEax {
     VendorInfo = 0x0,
     FeatureInfo = 0x1,
+    CacheParams = 0x4,
+    ExtendedFeatures = 0x7,
+    ExtendedTopology = 0xb,
+    ExtendedState = 0xd,
+    ExtendedTopologyV2 = 0x1f,
     HypervisorInfo = 0x4000_0000,
     HypervisorFeatures = 0x4000_0001,
     AmdFeatureInfo = 0x8000_0001,
+    AmdProcessorCapacity = 0x8000_0008,
 }
 
 bitflags! {
@@ -210,4 +216,49 @@ impl CpuFeatures {
             false
         }
     }
+
+    pub fn phys_addr_bits(&self) -> u8 {
+        if let Some(info) = self.cpuid.get_processor_capacity_feature_info() {
+            info.physical_address_bits()
+        } else {
+            36
+        }
+    }
+
+    #[cfg(feature = "amd")]
+    pub fn svm_asid_count(&self) -> u32 {
+        if let Some(info) = self.cpuid.get_svm_info() {
+            info.supported_asids()
+        } else {
+            0
+        }
+    }
+
+    /// Whether the GPR of intercepted MOV CR and the next RIP are saved on #VMEXIT.
+    #[cfg(feature = "amd")]
+    pub fn has_svm_decode_assists(&self) -> bool {
+        if let Some(info) = self.cpuid.get_svm_info() {
+            info.has_decode_assists() && info.has_nrip()
+        } else {
+            false
+        }
+    }
+
+    #[cfg(feature = "amd")]
+    pub fn has_pause_filter(&self) -> bool {
+        if let Some(info) = self.cpuid.get_svm_info() {
+            info.has_pause_filter()
+        } else {
+            false
+        }
+    }
+
+    #[cfg(feature = "amd")]
+    pub fn has_pause_filter_threshold(&self) -> bool {
+        if let Some(info) = self.cpuid.get_svm_info() {
+            info.has_pause_filter_threshold()
+        } else {
+            false
+        }
+    }
 }
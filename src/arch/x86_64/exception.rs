@@ -1,6 +1,7 @@
 use core::arch::{asm, global_asm};
 
 use super::context::GeneralRegisters;
+use crate::percpu::PerCpu;
 
 global_asm!(include_str!(concat!(env!("OUT_DIR"), "/exception.S")));
 
@@ -56,7 +57,8 @@ pub struct TrapFrame {
 fn exception_handler(frame: &TrapFrame) {
     trace!("Exception or interrupt #{:#x}", frame.num);
     match frame.num as u8 {
-        ExceptionType::NonMaskableInterrupt => handle_nmi(),
+        ExceptionType::NonMaskableInterrupt => handle_nmi(frame),
+        ExceptionType::DeviceNotAvailable => PerCpu::current_mut().fpu.save_guest(),
         ExceptionType::PageFault => handle_page_fault(frame),
         ExceptionType::IrqStart..=ExceptionType::IrqEnd => {
             error!("{:#x?}", frame);
@@ -69,8 +71,15 @@ fn exception_handler(frame: &TrapFrame) {
     }
 }
 
-fn handle_nmi() {
-    warn!("Unhandled exception: NMI");
+fn handle_nmi(frame: &TrapFrame) {
+    // The interrupted code may hold `&mut PerCpu`, don't create another one.
+    let cpu_data = PerCpu::current();
+    if crate::crash::in_progress() {
+        crate::crash::capture_cpu(cpu_data, frame.rip as _, frame.rsp as _);
+    }
+    if !cpu_data.handle_events_in_nmi() {
+        warn!("Unhandled exception: NMI");
+    }
 }
 
 fn handle_page_fault(frame: &TrapFrame) {
@@ -0,0 +1,195 @@
+//! Lazy switching of the FPU and SIMD state between guests and the hypervisor.
+//!
+//! CR0.TS is always set in the hypervisor, so the first FPU or SIMD instruction it executes
+//! causes #NM, which saves the guest state. The guest state is restored before the next VM
+//! entry. The hypervisor is built without SIMD, which is only used in functions with
+//! `#[target_feature]`.
+
+use core::arch::asm;
+use core::ptr;
+
+use x86_64::registers::control::{Cr0, Cr0Flags};
+use x86_64::registers::xcontrol::{XCr0, XCr0Flags};
+
+use super::cpuid::{cpuid, CpuFeatures};
+use crate::error::HvResult;
+use crate::memory::{Frame, PAGE_SIZE};
+
+/// Size of the FXSAVE area, used if XSAVE is not supported.
+const FXSAVE_AREA_SIZE: usize = 512;
+/// Size of the XSAVE header following the FXSAVE area.
+const XSAVE_HEADER_SIZE: usize = 64;
+/// Offsets and initial values of FCW and MXCSR in the FXSAVE area.
+const FCW_OFFSET: usize = 0;
+const FCW_INIT: u16 = 0x37f;
+const MXCSR_OFFSET: usize = 24;
+const MXCSR_INIT: u32 = 0x1f80;
+
+/// XCR0 bits of MPX, AVX-512 and AMX, each group must be enabled together.
+pub const XCR0_MPX: u64 = XCr0Flags::BNDREG.bits() | XCr0Flags::BNDCSR.bits();
+pub const XCR0_AVX512: u64 =
+    XCr0Flags::OPMASK.bits() | XCr0Flags::ZMM_HI256.bits() | XCr0Flags::HI16_ZMM.bits();
+pub const XCR0_AMX: u64 = (1 << 17) | (1 << 18);
+
+/// XCR0 bits supported by the processor, 0 if XSAVE is not supported.
+pub fn supported_xcr0() -> u64 {
+    static SUPPORTED_XCR0: spin::Once<u64> = spin::Once::new();
+    *SUPPORTED_XCR0.call_once(|| {
+        if CpuFeatures::new().has_xsave() {
+            let res = cpuid!(0xd, 0);
+            (res.edx as u64) << 32 | res.eax as u64
+        } else {
+            0
+        }
+    })
+}
+
+/// Returns whether XSETBV can write `val` to XCR0 without #GP, if the states in `allowed` are
+/// supported.
+pub fn is_valid_xcr0(val: u64, allowed: u64) -> bool {
+    let all_or_none = |bits: u64| val & bits == 0 || val & bits == bits;
+    let has = |flags: XCr0Flags| val & flags.bits() != 0;
+    val & !allowed == 0
+        && has(XCr0Flags::X87)
+        && (!has(XCr0Flags::AVX) || has(XCr0Flags::SSE))
+        && (val & XCR0_AVX512 == 0 || has(XCr0Flags::AVX))
+        && all_or_none(XCR0_MPX)
+        && all_or_none(XCR0_AVX512)
+        && all_or_none(XCR0_AMX)
+}
+
+/// XCR0 used by the hypervisor: x87, SSE and AVX if supported.
+fn host_xcr0() -> u64 {
+    supported_xcr0() & (XCr0Flags::X87 | XCr0Flags::SSE | XCr0Flags::AVX).bits()
+}
+
+/// FPU, SSE and XSAVE state of the guest on a CPU, saved while the hypervisor uses them.
+pub struct FpuState {
+    /// XSAVE area (or FXSAVE area) of the guest.
+    area: Frame,
+    /// XCR0 of the guest, loaded to the processor when the guest state is not saved.
+    guest_xcr0: u64,
+    /// Whether the guest state is saved in `area`.
+    saved: bool,
+}
+
+impl FpuState {
+    pub fn new() -> HvResult<Self> {
+        let size = if supported_xcr0() != 0 {
+            // Size of the XSAVE area for all supported states.
+            cpuid!(0xd, 0).ecx as usize
+        } else {
+            FXSAVE_AREA_SIZE
+        };
+        // XRSTOR requires reserved bytes of the XSAVE header to be zero.
+        let mut area = Frame::new_contiguous((size + PAGE_SIZE - 1) / PAGE_SIZE, 0)?;
+        area.zero();
+        Ok(Self {
+            area,
+            guest_xcr0: 0,
+            saved: false,
+        })
+    }
+
+    /// Set XCR0 of the guest to `val`, which has been checked by `is_valid_xcr0()`.
+    pub fn set_guest_xcr0(&mut self, val: u64) {
+        if self.saved {
+            self.guest_xcr0 = val;
+        } else {
+            unsafe { XCr0::write_raw(val) };
+        }
+    }
+
+    /// Called on #NM in the hypervisor: save the guest state, and let the hypervisor use the
+    /// FPU and SIMD registers in their initial state.
+    pub fn save_guest(&mut self) {
+        assert!(!self.saved, "#NM with the guest FPU state saved");
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            asm!("clts");
+            if supported_xcr0() != 0 {
+                self.guest_xcr0 = XCr0::read_raw();
+                asm!("xsave64 [{0}]", in(reg) area, in("eax") -1, in("edx") -1);
+                XCr0::write_raw(host_xcr0());
+            } else {
+                asm!("fxsave64 [{0}]", in(reg) area);
+            }
+            asm!("fninit");
+        }
+        self.saved = true;
+    }
+
+    /// Reset the guest state on INIT: the saved state is dropped, the x87 and SIMD registers
+    /// are initialized and XCR0 is set to 1.
+    pub fn reset_guest(&mut self) {
+        let area = self.area.as_mut_ptr();
+        unsafe {
+            asm!("clts");
+            if supported_xcr0() != 0 {
+                // Components with XSTATE_BV bits clear are initialized by XRSTOR, except MXCSR.
+                ptr::write_bytes(area.add(FXSAVE_AREA_SIZE), 0, XSAVE_HEADER_SIZE);
+                ptr::write_unaligned(area.add(MXCSR_OFFSET) as *mut u32, MXCSR_INIT);
+                // AMX may be armed by Linux in IA32_XFD, restoring it would cause #NM.
+                XCr0::write_raw(supported_xcr0() & !XCR0_AMX);
+                asm!("xrstor64 [{0}]", in(reg) area, in("eax") -1, in("edx") -1);
+                XCr0::write_raw(XCr0Flags::X87.bits());
+            } else {
+                ptr::write_bytes(area, 0, FXSAVE_AREA_SIZE);
+                ptr::write_unaligned(area.add(FCW_OFFSET) as *mut u16, FCW_INIT);
+                ptr::write_unaligned(area.add(MXCSR_OFFSET) as *mut u32, MXCSR_INIT);
+                asm!("fxrstor64 [{0}]", in(reg) area);
+            }
+            Cr0::write(Cr0::read() | Cr0Flags::TASK_SWITCHED);
+        }
+        self.saved = false;
+    }
+
+    /// Restore the guest state if it has been saved, before VM entries or returning to Linux.
+    pub fn restore_guest(&mut self) {
+        if !self.saved {
+            return;
+        }
+        let area = self.area.as_ptr();
+        unsafe {
+            if supported_xcr0() != 0 {
+                XCr0::write_raw(self.guest_xcr0);
+                asm!("xrstor64 [{0}]", in(reg) area, in("eax") -1, in("edx") -1);
+            } else {
+                asm!("fxrstor64 [{0}]", in(reg) area);
+            }
+            Cr0::write(Cr0::read() | Cr0Flags::TASK_SWITCHED);
+        }
+        self.saved = false;
+    }
+
+    /// Check that SIMD in the hypervisor keeps the guest state: XMM0 of the guest is compared
+    /// before and after the hypervisor overwrites it, which saves and restores it on #NM.
+    /// Called with CR0.TS set and the guest state not saved.
+    #[cfg(debug_assertions)]
+    pub fn self_test(&mut self) {
+        let read_guest_xmm0 = || {
+            let mut val = [0u64; 2];
+            unsafe {
+                asm!("clts", "movdqu [{0}], xmm0", in(reg) val.as_mut_ptr());
+                Cr0::write(Cr0::read() | Cr0Flags::TASK_SWITCHED);
+            }
+            val
+        };
+        let before = read_guest_xmm0();
+        unsafe { overwrite_xmm0() };
+        // Set by the #NM handler through `PerCpu::current_mut()`.
+        assert!(
+            unsafe { ptr::read_volatile(&self.saved) },
+            "No #NM on SIMD in the hypervisor"
+        );
+        self.restore_guest();
+        assert_eq!(read_guest_xmm0(), before, "Guest FPU state is not restored");
+    }
+}
+
+/// Use SSE in the hypervisor, as functions with `#[target_feature]` do.
+#[cfg(debug_assertions)]
+#[target_feature(enable = "sse2")]
+unsafe fn overwrite_xmm0() {
+    asm!("pcmpeqd xmm0, xmm0", out("xmm0") _);
+}
@@ -0,0 +1,644 @@
+//! Guest page table walker for all x86 paging modes, see Intel SDM Volume 3A, Chapter 4.
+
+use core::sync::atomic::{AtomicU32, AtomicU64, Ordering};
+
+use bit_field::BitField;
+use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
+use x86_64::structures::idt::PageFaultErrorCode as PFEC;
+use x86_64::structures::paging::page_table::PageTableFlags as PTF;
+
+use super::cpuid::CpuFeatures;
+use super::vmm::VcpuAccessGuestState;
+use crate::error::{HvError, HvResult};
+use crate::memory::gaccess::check_gpaddr;
+use crate::memory::{GuestPhysAddr, GuestVirtAddr, MemFlags, PAGE_SIZE};
+
+const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
+
+/// Paging mode of the guest, determined by CR0.PG, CR4.PAE, CR4.LA57 and EFER.LMA.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PagingMode {
+    /// Paging is disabled (real mode, or protected mode without paging).
+    None,
+    /// 32-bit paging.
+    Legacy,
+    /// PAE paging.
+    Pae,
+    /// 4-level paging.
+    Level4,
+    /// 5-level paging.
+    Level5,
+}
+
+#[derive(Debug)]
+pub enum GuestWalkError {
+    /// The access would cause a #PF with this error code in the guest. It is not injected,
+    /// hypercalls fail with `EFAULT` instead.
+    PageFault(PFEC),
+    /// The guest page table cannot be accessed by the hypervisor.
+    Hv(HvError),
+}
+
+impl From<HvError> for GuestWalkError {
+    fn from(err: HvError) -> Self {
+        Self::Hv(err)
+    }
+}
+
+impl From<GuestWalkError> for HvError {
+    fn from(err: GuestWalkError) -> Self {
+        match err {
+            GuestWalkError::PageFault(code) => {
+                hv_err!(EFAULT, format!("Guest page fault: {:?}", code))
+            }
+            GuestWalkError::Hv(err) => err,
+        }
+    }
+}
+
+pub type GuestWalkResult<T> = Result<T, GuestWalkError>;
+
+/// Reads and updates guest page table entries of `size` (4 or 8) bytes in guest physical memory.
+trait GuestPteAccess {
+    fn read_pte(&self, gpaddr: GuestPhysAddr, size: usize) -> HvResult<u64>;
+
+    /// Atomically sets `flags` in the entry at `gpaddr`.
+    fn set_pte_flags(&self, gpaddr: GuestPhysAddr, size: usize, flags: u64) -> HvResult;
+}
+
+/// Guest page tables in the memory of the current cell.
+struct CellMemory;
+
+impl GuestPteAccess for CellMemory {
+    fn read_pte(&self, gpaddr: GuestPhysAddr, size: usize) -> HvResult<u64> {
+        let hvaddr = check_gpaddr(gpaddr, MemFlags::READ)?;
+        Ok(unsafe {
+            if size == 4 {
+                (*(hvaddr as *const AtomicU32)).load(Ordering::Acquire) as u64
+            } else {
+                (*(hvaddr as *const AtomicU64)).load(Ordering::Acquire)
+            }
+        })
+    }
+
+    fn set_pte_flags(&self, gpaddr: GuestPhysAddr, size: usize, flags: u64) -> HvResult {
+        let hvaddr = check_gpaddr(gpaddr, MemFlags::WRITE)?;
+        unsafe {
+            if size == 4 {
+                (*(hvaddr as *const AtomicU32)).fetch_or(flags as u32, Ordering::AcqRel);
+            } else {
+                (*(hvaddr as *const AtomicU64)).fetch_or(flags, Ordering::AcqRel);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A snapshot of the guest paging state, used to translate guest virtual addresses the way the
+/// guest CPU does.
+#[derive(Debug)]
+pub struct GuestPageTable {
+    mode: PagingMode,
+    root: GuestPhysAddr,
+    cr0: Cr0Flags,
+    cr4: Cr4Flags,
+    efer: EferFlags,
+    rflags: RFlags,
+    phys_addr_bits: usize,
+}
+
+impl GuestPageTable {
+    pub fn new(vcpu: &impl VcpuAccessGuestState) -> Self {
+        let cr0 = Cr0Flags::from_bits_truncate(vcpu.cr(0));
+        let cr4 = Cr4Flags::from_bits_truncate(vcpu.cr(4));
+        let efer = EferFlags::from_bits_truncate(vcpu.efer());
+        let mode = if !cr0.contains(Cr0Flags::PAGING) {
+            PagingMode::None
+        } else if efer.contains(EferFlags::LONG_MODE_ACTIVE) {
+            if cr4.contains(Cr4Flags::L5_PAGING) {
+                PagingMode::Level5
+            } else {
+                PagingMode::Level4
+            }
+        } else if cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION) {
+            PagingMode::Pae
+        } else {
+            PagingMode::Legacy
+        };
+        let cr3 = vcpu.cr(3);
+        let root = match mode {
+            PagingMode::Legacy => cr3 & 0xffff_f000,
+            PagingMode::Pae => cr3 & 0xffff_ffe0,
+            _ => cr3 & PHYS_ADDR_MASK,
+        } as GuestPhysAddr;
+        Self {
+            mode,
+            root,
+            cr0,
+            cr4,
+            efer,
+            rflags: RFlags::from_bits_truncate(vcpu.rflags()),
+            phys_addr_bits: CpuFeatures::new().phys_addr_bits() as usize,
+        }
+    }
+
+    /// Translate `gvaddr` for an access described by `access`, where only `CAUSED_BY_WRITE`,
+    /// `USER_MODE` and `INSTRUCTION_FETCH` are used. Accessed and dirty flags are set as the
+    /// guest CPU would do. Returns the guest physical address and the size of the mapped page.
+    ///
+    /// Protection keys are not checked. PAE PDPTEs are read from memory rather than from the
+    /// registers loaded with CR3.
+    pub fn walk(
+        &self,
+        gvaddr: GuestVirtAddr,
+        access: PFEC,
+    ) -> GuestWalkResult<(GuestPhysAddr, usize)> {
+        self.walk_in(&CellMemory, gvaddr, access)
+    }
+
+    fn walk_in(
+        &self,
+        mem: &impl GuestPteAccess,
+        gvaddr: GuestVirtAddr,
+        access: PFEC,
+    ) -> GuestWalkResult<(GuestPhysAddr, usize)> {
+        let (levels, index_bits, entry_size) = match self.mode {
+            PagingMode::None => return Ok((gvaddr as u32 as GuestPhysAddr, PAGE_SIZE)),
+            PagingMode::Legacy => (2, 10, 4),
+            PagingMode::Pae => (3, 9, 8),
+            PagingMode::Level4 => (4, 9, 8),
+            PagingMode::Level5 => (5, 9, 8),
+        };
+        let vaddr = match self.mode {
+            PagingMode::Legacy | PagingMode::Pae => gvaddr as u32 as usize,
+            _ => {
+                let va_bits = 12 + levels * 9;
+                let sign_ext = (gvaddr as isize) << (64 - va_bits) >> (64 - va_bits);
+                if sign_ext as usize != gvaddr {
+                    return Err(hv_err!(
+                        EFAULT,
+                        format!("Non-canonical guest address {:#x?}", gvaddr)
+                    )
+                    .into());
+                }
+                gvaddr
+            }
+        };
+
+        let nxe = self.efer.contains(EferFlags::NO_EXECUTE_ENABLE);
+        let smep = self
+            .cr4
+            .contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+        let mut error = access & (PFEC::CAUSED_BY_WRITE | PFEC::USER_MODE);
+        if access.contains(PFEC::INSTRUCTION_FETCH) && (nxe || smep) {
+            error |= PFEC::INSTRUCTION_FETCH;
+        }
+
+        let (mut writable, mut user, mut no_exec) = (true, true, false);
+        let mut table = self.root;
+        for level in (0..levels).rev() {
+            let shift = 12 + level * index_bits;
+            let index = (vaddr >> shift) & ((1 << index_bits) - 1);
+            let pte_addr = table + index * entry_size;
+            let pte = mem.read_pte(pte_addr, entry_size)?;
+            let flags = PTF::from_bits_truncate(pte);
+            if !flags.contains(PTF::PRESENT) {
+                return Err(GuestWalkError::PageFault(error));
+            }
+
+            let is_pdpte = self.mode == PagingMode::Pae && level == 2;
+            let is_leaf = level == 0 || (self.huge_page_allowed(level) && pte.get_bit(7));
+            if pte & self.reserved_bits(level, is_leaf) != 0 {
+                return Err(GuestWalkError::PageFault(
+                    error | PFEC::PROTECTION_VIOLATION | PFEC::MALFORMED_TABLE,
+                ));
+            }
+            if !is_pdpte {
+                writable &= flags.contains(PTF::WRITABLE);
+                user &= flags.contains(PTF::USER_ACCESSIBLE);
+                no_exec |= nxe && flags.contains(PTF::NO_EXECUTE);
+            }
+
+            if !is_leaf {
+                // PAE PDPTEs have no accessed flag.
+                if !is_pdpte {
+                    Self::set_pte_flags(mem, pte_addr, entry_size, pte, PTF::ACCESSED)?;
+                }
+                table = (pte & PHYS_ADDR_MASK) as GuestPhysAddr;
+                continue;
+            }
+
+            if !self.is_access_allowed(access, writable, user, no_exec) {
+                return Err(GuestWalkError::PageFault(
+                    error | PFEC::PROTECTION_VIOLATION,
+                ));
+            }
+            let mut new_flags = PTF::ACCESSED;
+            if access.contains(PFEC::CAUSED_BY_WRITE) {
+                new_flags |= PTF::DIRTY;
+            }
+            Self::set_pte_flags(mem, pte_addr, entry_size, pte, new_flags)?;
+
+            let page_size = 1 << shift;
+            let page_paddr = if self.mode == PagingMode::Legacy && level == 1 {
+                // PSE-36: bits 32..40 of the address are in bits 13..21 of the PDE.
+                (pte & 0xffc0_0000) | (pte.get_bits(13..21) << 32)
+            } else {
+                pte & PHYS_ADDR_MASK & !(page_size as u64 - 1)
+            };
+            return Ok((
+                page_paddr as GuestPhysAddr + (vaddr & (page_size - 1)),
+                page_size,
+            ));
+        }
+        unreachable!()
+    }
+
+    fn huge_page_allowed(&self, level: usize) -> bool {
+        match self.mode {
+            PagingMode::Legacy => level == 1 && self.cr4.contains(Cr4Flags::PAGE_SIZE_EXTENSION),
+            PagingMode::Pae => level == 1,
+            _ => level == 1 || level == 2,
+        }
+    }
+
+    /// Returns the reserved bits of an entry at `level` (0 for PTEs), which cause a #PF if set.
+    fn reserved_bits(&self, level: usize, is_leaf: bool) -> u64 {
+        if self.mode == PagingMode::Legacy {
+            return if level == 1 && is_leaf {
+                let bits = self.phys_addr_bits.min(40);
+                (1 << 22) - (1 << (bits - 19))
+            } else {
+                0
+            };
+        }
+        let mut rsvd = PHYS_ADDR_MASK & !((1 << self.phys_addr_bits) - 1);
+        if !self.efer.contains(EferFlags::NO_EXECUTE_ENABLE) {
+            rsvd |= PTF::NO_EXECUTE.bits();
+        }
+        match (self.mode, level) {
+            (PagingMode::Pae, 2) => rsvd |= PTF::NO_EXECUTE.bits() | 0x1e6,
+            (_, 3) | (_, 4) => rsvd |= PTF::HUGE_PAGE.bits(),
+            _ => {}
+        }
+        if level > 0 && is_leaf {
+            // Bit 12 is the PAT bit of huge pages.
+            rsvd |= (1 << (12 + level * 9)) - (1 << 13);
+        }
+        rsvd
+    }
+
+    fn is_access_allowed(&self, access: PFEC, writable: bool, user: bool, no_exec: bool) -> bool {
+        let user_access = access.contains(PFEC::USER_MODE);
+        if access.contains(PFEC::INSTRUCTION_FETCH) {
+            if no_exec || (user_access && !user) {
+                return false;
+            }
+            let smep = self
+                .cr4
+                .contains(Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION);
+            return user_access || !user || !smep;
+        }
+        if user_access {
+            return user && (writable || !access.contains(PFEC::CAUSED_BY_WRITE));
+        }
+        if user
+            && self
+                .cr4
+                .contains(Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION)
+            && !self.rflags.contains(RFlags::ALIGNMENT_CHECK)
+        {
+            return false;
+        }
+        writable
+            || !access.contains(PFEC::CAUSED_BY_WRITE)
+            || !self.cr0.contains(Cr0Flags::WRITE_PROTECT)
+    }
+
+    fn set_pte_flags(
+        mem: &impl GuestPteAccess,
+        pte_addr: GuestPhysAddr,
+        size: usize,
+        pte: u64,
+        flags: PTF,
+    ) -> HvResult {
+        if PTF::from_bits_truncate(pte).contains(flags) {
+            return Ok(());
+        }
+        mem.set_pte_flags(pte_addr, size, flags.bits())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeMap;
+    use alloc::vec::Vec;
+    use core::cell::RefCell;
+
+    use super::*;
+
+    const P: u64 = 1 << 0;
+    const W: u64 = 1 << 1;
+    const U: u64 = 1 << 2;
+    const A: u64 = 1 << 5;
+    const D: u64 = 1 << 6;
+    const PS: u64 = 1 << 7;
+    const NX: u64 = 1 << 63;
+    const PWU: u64 = P | W | U;
+
+    /// Guest memory holding only page table entries, missing entries read as zero.
+    struct MockMemory(RefCell<BTreeMap<GuestPhysAddr, u64>>);
+
+    impl MockMemory {
+        fn new(ptes: &[(GuestPhysAddr, u64)]) -> Self {
+            Self(RefCell::new(ptes.iter().copied().collect()))
+        }
+
+        fn pte(&self, gpaddr: GuestPhysAddr) -> u64 {
+            self.0.borrow().get(&gpaddr).copied().unwrap_or(0)
+        }
+    }
+
+    impl GuestPteAccess for MockMemory {
+        fn read_pte(&self, gpaddr: GuestPhysAddr, size: usize) -> HvResult<u64> {
+            assert_eq!(gpaddr % size, 0);
+            Ok(self.pte(gpaddr))
+        }
+
+        fn set_pte_flags(&self, gpaddr: GuestPhysAddr, _size: usize, flags: u64) -> HvResult {
+            *self.0.borrow_mut().entry(gpaddr).or_insert(0) |= flags;
+            Ok(())
+        }
+    }
+
+    /// The walk result, `Err(None)` for errors other than a guest page fault.
+    type Expect = Result<(GuestPhysAddr, usize), Option<PFEC>>;
+
+    struct Case {
+        name: &'static str,
+        mode: PagingMode,
+        cr0: Cr0Flags,
+        cr4: Cr4Flags,
+        efer: EferFlags,
+        rflags: RFlags,
+        phys_addr_bits: usize,
+        ptes: Vec<(GuestPhysAddr, u64)>,
+        gvaddr: GuestVirtAddr,
+        access: PFEC,
+        expect: Expect,
+    }
+
+    fn case(name: &'static str, mode: PagingMode, ptes: &[(GuestPhysAddr, u64)]) -> Case {
+        Case {
+            name,
+            mode,
+            cr0: Cr0Flags::PAGING,
+            cr4: Cr4Flags::empty(),
+            efer: EferFlags::empty(),
+            rflags: RFlags::empty(),
+            phys_addr_bits: 46,
+            ptes: ptes.to_vec(),
+            gvaddr: 0x40_1234,
+            access: PFEC::empty(),
+            expect: Ok((0x5234, 0x1000)),
+        }
+    }
+
+    /// 4-level page tables mapping 0x40_1234 to 0x5234 with `leaf` as flags of the PTE.
+    fn level4(leaf: u64) -> Vec<(GuestPhysAddr, u64)> {
+        [
+            (0x1000, 0x2000 | PWU),
+            (0x2000, 0x3000 | PWU),
+            (0x3010, 0x4000 | PWU),
+            (0x4008, 0x5000 | leaf),
+        ]
+        .to_vec()
+    }
+
+    fn run(case: &Case) -> (MockMemory, Expect) {
+        let gpt = GuestPageTable {
+            mode: case.mode,
+            root: 0x1000,
+            cr0: case.cr0,
+            cr4: case.cr4,
+            efer: case.efer,
+            rflags: case.rflags,
+            phys_addr_bits: case.phys_addr_bits,
+        };
+        let mem = MockMemory::new(&case.ptes);
+        let res = match gpt.walk_in(&mem, case.gvaddr, case.access) {
+            Ok(res) => Ok(res),
+            Err(GuestWalkError::PageFault(code)) => Err(Some(code)),
+            Err(GuestWalkError::Hv(_)) => Err(None),
+        };
+        (mem, res)
+    }
+
+    #[test]
+    fn test_paging_modes() {
+        use PagingMode::{Legacy, Level4, Level5, Pae};
+        let rsvd = Err(Some(PFEC::PROTECTION_VIOLATION | PFEC::MALFORMED_TABLE));
+        let cases = [
+            Case {
+                gvaddr: 0x1_2345_6789,
+                expect: Ok((0x2345_6789, 0x1000)),
+                ..case("no paging", PagingMode::None, &[])
+            },
+            case(
+                "32-bit 4K",
+                Legacy,
+                &[(0x1004, 0x2000 | PWU), (0x2004, 0x5000 | PWU)],
+            ),
+            Case {
+                cr4: Cr4Flags::PAGE_SIZE_EXTENSION,
+                phys_addr_bits: 36,
+                expect: Ok((0x3_0040_1234, 0x40_0000)),
+                ..case(
+                    "32-bit 4M PSE-36",
+                    Legacy,
+                    &[(0x1004, 0x40_0000 | 3 << 13 | PWU | PS)],
+                )
+            },
+            Case {
+                cr4: Cr4Flags::PAGE_SIZE_EXTENSION,
+                phys_addr_bits: 36,
+                expect: rsvd,
+                ..case(
+                    "32-bit 4M reserved",
+                    Legacy,
+                    &[(0x1004, 0x40_0000 | 1 << 17 | PWU | PS)],
+                )
+            },
+            case(
+                "PAE 4K",
+                Pae,
+                &[
+                    (0x1000, 0x2000 | P),
+                    (0x2010, 0x3000 | PWU),
+                    (0x3008, 0x5000 | PWU),
+                ],
+            ),
+            Case {
+                expect: Ok((0x60_1234, 0x20_0000)),
+                ..case(
+                    "PAE 2M",
+                    Pae,
+                    &[(0x1000, 0x2000 | P), (0x2010, 0x60_0000 | PWU | PS)],
+                )
+            },
+            Case {
+                expect: rsvd,
+                ..case("PAE PDPTE reserved", Pae, &[(0x1000, 0x2000 | P | W)])
+            },
+            case("4-level 4K", Level4, &level4(PWU)),
+            Case {
+                expect: Ok((0x4040_1234, 0x4000_0000)),
+                ..case(
+                    "4-level 1G",
+                    Level4,
+                    &[(0x1000, 0x2000 | PWU), (0x2000, 0x4000_0000 | PWU | PS)],
+                )
+            },
+            Case {
+                expect: rsvd,
+                ..case(
+                    "4-level 1G reserved",
+                    Level4,
+                    &[
+                        (0x1000, 0x2000 | PWU),
+                        (0x2000, 0x4000_0000 | 1 << 13 | PWU | PS),
+                    ],
+                )
+            },
+            Case {
+                expect: rsvd,
+                ..case(
+                    "4-level PML4E with PS",
+                    Level4,
+                    &[(0x1000, 0x2000 | PWU | PS)],
+                )
+            },
+            Case {
+                phys_addr_bits: 39,
+                expect: rsvd,
+                ..case("4-level beyond MAXPHYADDR", Level4, &level4(1 << 40 | PWU))
+            },
+            Case {
+                expect: rsvd,
+                ..case("4-level NX without NXE", Level4, &level4(PWU | NX))
+            },
+            Case {
+                gvaddr: 1 << 47,
+                expect: Err(None),
+                ..case("4-level non-canonical", Level4, &[])
+            },
+            Case {
+                gvaddr: 1 << 47 | 0x40_1234,
+                cr4: Cr4Flags::L5_PAGING,
+                ..case(
+                    "5-level 4K",
+                    Level5,
+                    &[
+                        (0x1000, 0x2000 | PWU),
+                        (0x2800, 0x3000 | PWU),
+                        (0x3000, 0x4000 | PWU),
+                        (0x4010, 0x6000 | PWU),
+                        (0x6008, 0x5000 | PWU),
+                    ],
+                )
+            },
+        ];
+        for case in cases.iter() {
+            assert_eq!(run(case).1, case.expect, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_access_rights() {
+        use PagingMode::Level4;
+        let fault = |code| Err(Some(PFEC::PROTECTION_VIOLATION | code));
+        let write = PFEC::CAUSED_BY_WRITE;
+        let fetch = PFEC::INSTRUCTION_FETCH;
+        let cases = [
+            Case {
+                access: PFEC::USER_MODE,
+                expect: Err(Some(PFEC::USER_MODE)),
+                ..case("not present", Level4, &level4(0))
+            },
+            Case {
+                access: PFEC::USER_MODE,
+                expect: fault(PFEC::USER_MODE),
+                ..case("user reads supervisor page", Level4, &level4(P | W))
+            },
+            Case {
+                access: PFEC::USER_MODE | write,
+                expect: fault(PFEC::USER_MODE | write),
+                ..case("user writes read-only page", Level4, &level4(P | U))
+            },
+            Case {
+                access: write,
+                cr0: Cr0Flags::PAGING | Cr0Flags::WRITE_PROTECT,
+                expect: fault(write),
+                ..case("supervisor write with WP", Level4, &level4(P))
+            },
+            Case {
+                access: write,
+                ..case("supervisor write without WP", Level4, &level4(P))
+            },
+            Case {
+                access: fetch,
+                cr4: Cr4Flags::SUPERVISOR_MODE_EXECUTION_PROTECTION,
+                expect: fault(fetch),
+                ..case("supervisor fetch with SMEP", Level4, &level4(PWU))
+            },
+            Case {
+                access: fetch,
+                ..case("supervisor fetch without SMEP", Level4, &level4(PWU))
+            },
+            Case {
+                cr4: Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+                expect: fault(PFEC::empty()),
+                ..case("supervisor read with SMAP", Level4, &level4(PWU))
+            },
+            Case {
+                cr4: Cr4Flags::SUPERVISOR_MODE_ACCESS_PREVENTION,
+                rflags: RFlags::ALIGNMENT_CHECK,
+                ..case("supervisor read with SMAP and AC", Level4, &level4(PWU))
+            },
+            Case {
+                access: fetch,
+                efer: EferFlags::NO_EXECUTE_ENABLE,
+                expect: fault(fetch),
+                ..case("fetch from NX page", Level4, &level4(PWU | NX))
+            },
+        ];
+        for case in cases.iter() {
+            assert_eq!(run(case).1, case.expect, "{}", case.name);
+        }
+    }
+
+    #[test]
+    fn test_accessed_dirty() {
+        let read = case("read", PagingMode::Level4, &level4(PWU));
+        let (mem, _) = run(&read);
+        for (gpaddr, pte) in level4(PWU) {
+            assert_eq!(mem.pte(gpaddr), pte | A, "{:#x}", gpaddr);
+        }
+
+        let write = Case {
+            access: PFEC::CAUSED_BY_WRITE,
+            ..case("write", PagingMode::Level4, &level4(PWU))
+        };
+        let (mem, _) = run(&write);
+        assert_eq!(mem.pte(0x4008), 0x5000 | PWU | A | D);
+
+        let fault = Case {
+            access: PFEC::USER_MODE,
+            ..case("fault", PagingMode::Level4, &level4(P | W))
+        };
+        let (mem, _) = run(&fault);
+        assert_eq!(mem.pte(0x4008), 0x5000 | P | W);
+    }
+}
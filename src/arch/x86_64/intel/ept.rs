@@ -4,8 +4,9 @@ use bit_field::BitField;
 use bitflags::bitflags;
 use numeric_enum_macro::numeric_enum;
 
+use crate::arch::mtrr;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{GenericPTE, Level4PageTable, MemFlags, PagingInstr};
+use crate::memory::{GenericPTE, Level4PageTable, MemFlags, PageSize, PagingInstr};
 
 bitflags! {
     struct EPTFlags: u64 {
@@ -26,6 +27,10 @@ bitflags! {
         const DIRTY =               1 << 9;
         /// Execute access for user-mode linear addresses.
         const EXECUTE_FOR_USER =    1 << 10;
+        /// Ignored by the processor, records `MemFlags::IO` of the mapping.
+        const SW_IO =               1 << 52;
+        /// Ignored by the processor, records `MemFlags::WRITE_COMBINING` of the mapping.
+        const SW_WRITE_COMBINING =  1 << 53;
     }
 }
 
@@ -41,6 +46,9 @@ numeric_enum! {
     }
 }
 
+/// Physical address bits of entries.
+const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
+
 #[derive(Clone)]
 pub struct EPTEntry(u64);
 
@@ -59,6 +67,12 @@ impl From<MemFlags> for EPTFlags {
         if f.contains(MemFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
+        if f.contains(MemFlags::IO) {
+            ret |= Self::SW_IO;
+        }
+        if f.contains(MemFlags::WRITE_COMBINING) {
+            ret |= Self::SW_WRITE_COMBINING;
+        }
         ret
     }
 }
@@ -75,6 +89,12 @@ impl From<EPTFlags> for MemFlags {
         if f.contains(EPTFlags::EXECUTE) {
             ret |= Self::EXECUTE;
         }
+        if f.contains(EPTFlags::SW_IO) {
+            ret |= Self::IO;
+        }
+        if f.contains(EPTFlags::SW_WRITE_COMBINING) {
+            ret |= Self::WRITE_COMBINING;
+        }
         ret
     }
 }
@@ -83,6 +103,19 @@ impl EPTMemType {
     fn empty() -> Self {
         Self::try_from(0).unwrap()
     }
+
+    /// The memory type for terminal entries. The memory type of RAM follows the host MTRRs, so
+    /// that it's consistent with the host. Huge pages are only used where the MTRR type is the
+    /// same across the page (see `can_map_huge()`), so the first byte decides.
+    fn for_mapping(flags: MemFlags, paddr: HostPhysAddr) -> Self {
+        if flags.contains(MemFlags::IO) {
+            Self::Uncached
+        } else if flags.contains(MemFlags::WRITE_COMBINING) {
+            Self::WriteCombining
+        } else {
+            Self::try_from(mtrr::memory_type(paddr) as u8).unwrap()
+        }
+    }
 }
 
 impl GenericPTE for EPTEntry {
@@ -90,6 +123,7 @@ impl GenericPTE for EPTEntry {
         (self.0.get_bits(12..52) << 12) as usize
     }
     fn flags(&self) -> MemFlags {
+        // The memory type is not reported, it may come from MTRRs instead of the flags.
         self.ept_flags().into()
     }
     fn is_unused(&self) -> bool {
@@ -101,16 +135,24 @@ impl GenericPTE for EPTEntry {
     fn is_huge(&self) -> bool {
         self.ept_flags().contains(EPTFlags::HUGE_PAGE)
     }
+    fn is_dirty(&self) -> bool {
+        self.ept_flags().contains(EPTFlags::DIRTY)
+    }
+    fn can_map_huge(paddr: HostPhysAddr, size: PageSize) -> bool {
+        mtrr::is_uniform(paddr, size as usize)
+    }
 
     fn set_addr(&mut self, paddr: HostPhysAddr) {
         self.0.set_bits(12..52, paddr as u64 >> 12);
     }
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool) {
+        // The memory type depends on the mapped address, `set_addr()` must be called first.
+        let mem_type = EPTMemType::for_mapping(flags, self.addr());
         let mut flags = flags.into();
         if is_huge {
             flags |= EPTFlags::HUGE_PAGE;
         }
-        self.set_flags_and_mem_type(flags, EPTMemType::WriteBack);
+        self.set_flags_and_mem_type(flags, mem_type);
     }
     fn set_table(&mut self, paddr: HostPhysAddr) {
         self.set_addr(paddr);
@@ -119,6 +161,9 @@ impl GenericPTE for EPTEntry {
             EPTMemType::empty(),
         );
     }
+    fn clear_dirty(&mut self) {
+        self.0 &= !EPTFlags::DIRTY.bits();
+    }
     fn clear(&mut self) {
         self.0 = 0
     }
@@ -132,7 +177,9 @@ impl EPTEntry {
         EPTMemType::try_from(self.0.get_bits(3..6) as u8)
     }
     fn set_flags_and_mem_type(&mut self, flags: EPTFlags, mem_type: EPTMemType) {
-        self.0.set_bits(0..12, flags.bits());
+        // Keep the accessed and dirty flags set by the processor.
+        let flags = flags | (self.ept_flags() & (EPTFlags::ACCESSED | EPTFlags::DIRTY));
+        self.0 = (self.0 & PHYS_ADDR_MASK) | flags.bits();
         self.0.set_bits(3..6, mem_type as u64);
     }
 }
@@ -156,7 +203,8 @@ impl PagingInstr for EPTInstr {
     }
 
     fn flush(_vaddr: Option<usize>) {
-        // do nothing
+        // INVEPT cannot invalidate a single guest physical address.
+        crate::arch::vmm::nested_tlb_shootdown();
     }
 }
 
@@ -3,11 +3,18 @@ mod structs;
 mod vcpu;
 mod vmexit;
 
+use alloc::vec::Vec;
+use core::sync::atomic::{AtomicBool, Ordering};
+
+use libvmm::msr::Msr;
+use libvmm::vmx::flags::{EptVpidCap, EptVpidCapFlags, SecondaryVmExecControls};
 use libvmm::vmx::Vmcs;
 use x86::vmx::VmFail;
 
 use crate::arch::cpuid::CpuFeatures;
 use crate::error::{HvError, HvResult};
+use crate::memory::GuestPhysAddr;
+use crate::percpu::{CpuEvents, PerCpu};
 
 pub use ept::ExtendedPageTable as NestedPageTable;
 pub use vcpu::Vcpu;
@@ -32,3 +39,77 @@ pub fn check_hypervisor_feature() -> HvResult {
         hv_result_err!(ENODEV, "VMX feature checks failed!")
     }
 }
+
+/// The maximum VPID.
+pub fn max_asid() -> u16 {
+    u16::MAX
+}
+
+/// Whether page-modification logging can be enabled.
+fn has_pml() -> bool {
+    let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+    allowed1 & SecondaryVmExecControls::PAGE_MOD_LOGGING.bits() != 0
+        && EptVpidCap::read().contains(EptVpidCapFlags::ACCESSED_DIRTY)
+}
+
+static HW_DIRTY_LOG_ENABLED: AtomicBool = AtomicBool::new(false);
+
+fn hw_dirty_log_enabled() -> bool {
+    HW_DIRTY_LOG_ENABLED.load(Ordering::Acquire)
+}
+
+/// Turn PML on all CPUs on to start collecting dirty pages, or off to stop.
+pub fn set_hw_dirty_log(enable: bool) {
+    if !has_pml() {
+        return;
+    }
+    HW_DIRTY_LOG_ENABLED.store(enable, Ordering::Release);
+    sync_hw_dirty_log();
+}
+
+/// Move pages logged by PML to the software logs on all CPUs, and apply `set_hw_dirty_log()`.
+fn sync_hw_dirty_log() {
+    let cpu_data = PerCpu::current_mut();
+    if cpu_data.is_hv_enabled() {
+        if let Err(e) = cpu_data.vcpu.sync_dirty_log() {
+            error!("Failed to sync dirty log: {:?}", e);
+        }
+    }
+    PerCpu::broadcast_events(CpuEvents::SYNC_DIRTY_LOG);
+}
+
+/// Returns guest physical pages logged by PML on all CPUs since the last call, or `None` if PML
+/// is not supported.
+pub fn take_hw_dirty_log() -> Option<Vec<GuestPhysAddr>> {
+    if !has_pml() {
+        return None;
+    }
+    sync_hw_dirty_log();
+
+    let mut pages = Vec::new();
+    for cpu_data in PerCpu::entered() {
+        if cpu_data.is_hv_enabled() {
+            pages.append(&mut cpu_data.vcpu.take_dirty_log());
+        }
+    }
+    pages.sort_unstable();
+    pages.dedup();
+    Some(pages)
+}
+
+/// Whether PAUSE-loop exiting can be enabled.
+fn has_ple() -> bool {
+    let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+    allowed1 & SecondaryVmExecControls::PAUSE_LOOP_EXITING.bits() != 0
+}
+
+/// Whether VPIDs can be enabled and flushed.
+fn has_vpid() -> bool {
+    let allowed1 = (Msr::IA32_VMX_PROCBASED_CTLS2.read() >> 32) as u32;
+    let cap = EptVpidCap::read();
+    allowed1 & SecondaryVmExecControls::VPID.bits() != 0
+        && cap.contains(EptVpidCapFlags::INVVPID)
+        && cap.intersects(
+            EptVpidCapFlags::INVVPID_SINGLE_CONTEXT | EptVpidCapFlags::INVVPID_ALL_CONTEXT,
+        )
+}
@@ -1,28 +1,36 @@
+use alloc::vec::Vec;
 use core::arch::asm;
 use core::fmt::{Debug, Formatter, Result};
+use core::slice;
 
 use libvmm::msr::Msr;
 use libvmm::vmx::{
     self,
+    flags::{EptVpidCap, EptVpidCapFlags, InvEptType, InvVpidType},
     flags::{FeatureControl, FeatureControlFlags, VmxBasic},
+    vmcs::{VmcsField16Control, VmcsField32Control, VmcsField64Control},
     vmcs::{VmcsField16Guest, VmcsField32Guest, VmcsField64Guest},
     vmcs::{VmcsField16Host, VmcsField32Host, VmcsField64Host},
-    vmcs::{VmcsField32Control, VmcsField64Control},
     Vmcs, VmxExitReason,
 };
+use spin::Mutex;
 use x86::segmentation::SegmentSelector;
 use x86_64::addr::VirtAddr;
 use x86_64::registers::control::{Cr0, Cr0Flags, Cr3, Cr4, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
 use x86_64::registers::rflags::RFlags;
 
 use super::structs::{MsrBitmap, VmxRegion};
-use crate::arch::cpuid::CpuFeatures;
+use crate::arch::cpuid::{cpuid, CpuFeatures, CpuIdEax};
 use crate::arch::segmentation::{Segment, SegmentAccessRights};
 use crate::arch::tables::{GdtStruct, IDT};
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::{GeneralRegisters, GuestPageTableImmut, LinuxContext};
+use crate::arch::{GeneralRegisters, GuestPageTable, LinuxContext};
 use crate::cell::Cell;
+use crate::config::CellFlags;
 use crate::error::HvResult;
+use crate::memory::addr::{align_down, GuestPhysAddr};
+use crate::memory::Frame;
 use crate::percpu::PerCpu;
 
 #[repr(C)]
@@ -35,8 +43,20 @@ pub struct Vcpu {
     vmxon_region: VmxRegion,
     /// VMCS of this CPU, required by VMX
     vmcs_region: VmxRegion,
+    /// Page-modification log buffer, if PML is enabled.
+    pml_buffer: Option<Frame>,
+    /// Guest physical pages moved from the PML buffer.
+    pml_log: Mutex<Vec<GuestPhysAddr>>,
 }
 
+/// Number of entries in the PML buffer.
+const PML_ENTRIES: usize = 512;
+
+/// Max TSC cycles between two PAUSEs in the same loop, and the TSC cycles of a loop before the
+/// VM exit, the same as KVM.
+const PLE_GAP: u32 = 128;
+const PLE_WINDOW: u32 = 4096;
+
 lazy_static! {
     static ref MSR_BITMAP: MsrBitmap = MsrBitmap::default();
 }
@@ -70,7 +90,8 @@ impl Vcpu {
             return hv_result_err!(EBUSY, "VMX is already turned on!");
         }
 
-        // Enable VMXON, if required.
+        // Enable VMXON, if required. The lock bit cannot be cleared on failures, but Linux
+        // (e.g. KVM) expects it to be set anyway.
         let ctrl = FeatureControl::read();
         let locked = ctrl.contains(FeatureControlFlags::LOCKED);
         let vmxon_outside = ctrl.contains(FeatureControlFlags::VMXON_ENABLED_OUTSIDE_SMX);
@@ -107,8 +128,13 @@ impl Vcpu {
             host_stack_top: PerCpu::current().stack_top() as _,
             vmxon_region,
             vmcs_region,
+            pml_buffer: None,
+            pml_log: Mutex::new(Vec::new()),
         };
-        ret.vmcs_setup(linux, cell)?;
+        if let Err(e) = ret.vmcs_setup(linux, cell) {
+            ret.disable().ok();
+            return Err(e);
+        }
 
         Ok(ret)
     }
@@ -140,12 +166,72 @@ impl Vcpu {
 
     pub fn exit(&self, linux: &mut LinuxContext) -> HvResult {
         self.load_vmcs_guest(linux)?;
+        self.disable()
+    }
+
+    /// Leave VMX operation without loading the guest state to Linux. CR0 and CR4 should be
+    /// restored by the caller.
+    pub fn disable(&self) -> HvResult {
         Vmcs::clear(self.vmcs_region.paddr())?;
         unsafe { vmx::vmxoff()? };
         info!("successed to turn off VMX.");
         Ok(())
     }
 
+    /// Reset the guest state as INIT followed by SIPI with `vector` does, the guest starts in
+    /// real mode at `vector << 12`.
+    pub fn reset(&mut self, cell: &Cell, vector: u8) -> HvResult {
+        use SegmentAccessRights as AR;
+        let data_ar = AR::PRESENT | AR::CODE_DATA | AR::WRITABLE | AR::ACCESSED;
+        let code_ar = data_ar | AR::EXECUTABLE;
+        set_guest_segment!(Segment::real_mode(0, data_ar), ES);
+        set_guest_segment!(Segment::real_mode((vector as u16) << 8, code_ar), CS);
+        set_guest_segment!(Segment::real_mode(0, data_ar), SS);
+        set_guest_segment!(Segment::real_mode(0, data_ar), DS);
+        set_guest_segment!(Segment::real_mode(0, data_ar), FS);
+        set_guest_segment!(Segment::real_mode(0, data_ar), GS);
+        set_guest_segment!(Segment::real_mode(0, AR::PRESENT | AR::TSS_BUSY), TR);
+        set_guest_segment!(Segment::invalid(), LDTR);
+        VmcsField64Guest::GDTR_BASE.write(0)?;
+        VmcsField32Guest::GDTR_LIMIT.write(0xffff)?;
+        VmcsField64Guest::IDTR_BASE.write(0)?;
+        VmcsField32Guest::IDTR_LIMIT.write(0xffff)?;
+
+        VmcsField64Guest::IA32_EFER.write(0)?;
+        self.set_cr(0, super::super::INIT_CR0.bits());
+        self.set_cr(4, 0);
+        self.set_cr(3, 0);
+        self.set_long_mode_active(false)?;
+
+        VmcsField64Guest::RSP.write(0)?;
+        VmcsField64Guest::RIP.write(0)?;
+        VmcsField64Guest::RFLAGS.write(0x2)?;
+        VmcsField64Guest::DR7.write(0x400)?;
+        VmcsField32Guest::ACTIVITY_STATE.write(0)?;
+        VmcsField32Guest::INTERRUPTIBILITY_INFO.write(0)?;
+        VmcsField64Guest::PENDING_DBG_EXCEPTIONS.write(0)?;
+        VmcsField32Control::VM_ENTRY_INTR_INFO_FIELD.write(0)?;
+
+        self.guest_regs = Default::default();
+        self.guest_regs.rdx = cpuid!(CpuIdEax::FeatureInfo as u32).eax as _;
+        self.load_cell(cell)
+    }
+
+    /// Emulate a guest write to CR0. IA-32e mode is entered or left as the processor does,
+    /// when CR0.PG changes with EFER.LME set.
+    pub fn write_guest_cr0(&mut self, val: u64) -> HvResult {
+        self.set_cr(0, val);
+        let efer = EferFlags::from_bits_truncate(VmcsField64Guest::IA32_EFER.read()?);
+        let paging = Cr0Flags::from_bits_truncate(val).contains(Cr0Flags::PAGING);
+        self.set_long_mode_active(efer.contains(EferFlags::LONG_MODE_ENABLE) && paging)
+    }
+
+    /// CR4 bits the guest can set, VMX and SMX are not exposed.
+    pub fn supported_cr4_bits() -> u64 {
+        Msr::IA32_VMX_CR4_FIXED1.read()
+            & !(Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS | Cr4Flags::SAFER_MODE_EXTENSIONS).bits()
+    }
+
     pub fn inject_fault(&mut self) -> HvResult {
         Vmcs::inject_interrupt(crate::arch::ExceptionType::GeneralProtectionFault, Some(0))?;
         Ok(())
@@ -153,6 +239,11 @@ impl Vcpu {
 
     pub fn advance_rip(&mut self, instr_len: u8) -> HvResult {
         VmcsField64Guest::RIP.write(VmcsField64Guest::RIP.read()? + instr_len as u64)?;
+        // Blocking by STI or MOV SS ends after the instruction, e.g. STI; HLT.
+        let interruptibility = VmcsField32Guest::INTERRUPTIBILITY_INFO.read()?;
+        if interruptibility & 0b11 != 0 {
+            VmcsField32Guest::INTERRUPTIBILITY_INFO.write(interruptibility & !0b11)?;
+        }
         Ok(())
     }
 
@@ -165,13 +256,127 @@ impl Vcpu {
         matches!(Vmcs::exit_reason(), Ok(VmxExitReason::VMCALL))
     }
 
-    pub fn guest_page_table(&self) -> GuestPageTableImmut {
-        use crate::memory::{addr::align_down, GenericPageTableImmut};
-        unsafe { GuestPageTableImmut::from_root(align_down(self.cr(3) as _)) }
+    pub fn guest_page_table(&self) -> GuestPageTable {
+        GuestPageTable::new(self)
+    }
+
+    /// Run this vCPU in `cell`: switch to its EPT and VPID, flush TLB entries tagged with the
+    /// VPID, which may be left by a previous cell owning the same VPID, and apply the HLT and
+    /// PAUSE exiting of the cell.
+    pub fn load_cell(&mut self, cell: &Cell) -> HvResult {
+        unsafe { cell.gpm().activate() }; // Set EPT_POINTER
+        self.set_idle_exiting(cell)?;
+        if super::has_vpid() {
+            VmcsField16Control::VIRTUAL_PROCESSOR_ID.write(cell.asid)?;
+            let invalidation =
+                if EptVpidCap::read().contains(EptVpidCapFlags::INVVPID_SINGLE_CONTEXT) {
+                    InvVpidType::SingleContext
+                } else {
+                    InvVpidType::AllContext
+                };
+            unsafe { vmx::invvpid(invalidation, cell.asid, 0)? };
+        }
+        Ok(())
+    }
+
+    /// Move guest physical pages logged in the PML buffer to the software log, the PML buffer
+    /// is reset. Pages are dropped if dirty logging is not started. PML is turned on or off as
+    /// dirty logging is started or stopped.
+    pub fn sync_dirty_log(&mut self) -> HvResult {
+        let buffer = match &self.pml_buffer {
+            Some(buffer) => buffer,
+            None => return Ok(()),
+        };
+        let enabled = super::hw_dirty_log_enabled();
+        // The index is decremented after each logging, and wraps to 0xffff if the buffer is full.
+        let index = VmcsField16Guest::PML_INDEX.read()?;
+        if enabled {
+            let entries =
+                unsafe { slice::from_raw_parts(buffer.as_ptr() as *const u64, PML_ENTRIES) };
+            self.pml_log.lock().extend(
+                entries[index.wrapping_add(1) as usize..]
+                    .iter()
+                    .map(|&gpaddr| align_down(gpaddr as usize)),
+            );
+        }
+        VmcsField16Guest::PML_INDEX.write(PML_ENTRIES as u16 - 1)?;
+        Self::set_pml(enabled)
+    }
+
+    /// Take the guest physical pages moved from the PML buffer by `sync_dirty_log()`.
+    pub fn take_dirty_log(&self) -> Vec<GuestPhysAddr> {
+        core::mem::take(&mut *self.pml_log.lock())
+    }
+
+    /// Invalidate cached translations derived from the current EPT.
+    pub fn flush_nested_tlb(&self) -> HvResult {
+        let eptp = VmcsField64Control::EPT_POINTER.read()?;
+        let invalidation = if EptVpidCap::read().contains(EptVpidCapFlags::INVEPT_SINGLE_CONTEXT) {
+            InvEptType::SingleContext
+        } else {
+            InvEptType::Global
+        };
+        unsafe { vmx::invept(invalidation, eptp)? };
+        Ok(())
     }
 }
 
 impl Vcpu {
+    fn set_idle_exiting(&mut self, cell: &Cell) -> HvResult {
+        use vmx::flags::PrimaryVmExecControls as CpuCtrl;
+        use vmx::flags::SecondaryVmExecControls as CpuCtrl2;
+        let hlt_bits = (CpuCtrl::HLT_EXITING | CpuCtrl::MWAIT_EXITING).bits();
+        let mut ctrl = VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL.read()?;
+        if super::super::hlt_exiting(cell) {
+            ctrl |= hlt_bits;
+        } else {
+            ctrl &= !hlt_bits;
+        }
+        VmcsField32Control::PROC_BASED_VM_EXEC_CONTROL.write(ctrl)?;
+
+        let ple_bits = CpuCtrl2::PAUSE_LOOP_EXITING.bits();
+        let mut ctrl2 = VmcsField32Control::SECONDARY_VM_EXEC_CONTROL.read()?;
+        if cell.config.flags().contains(CellFlags::PAUSE_LOOP_EXITING) && super::has_ple() {
+            ctrl2 |= ple_bits;
+        } else {
+            ctrl2 &= !ple_bits;
+        }
+        VmcsField32Control::SECONDARY_VM_EXEC_CONTROL.write(ctrl2)?;
+        Ok(())
+    }
+
+    /// Turn page-modification logging on or off, the PML buffer must be set up.
+    fn set_pml(enable: bool) -> HvResult {
+        use vmx::flags::SecondaryVmExecControls as CpuCtrl2;
+        let pml_bits = CpuCtrl2::PAGE_MOD_LOGGING.bits();
+        let mut ctrl2 = VmcsField32Control::SECONDARY_VM_EXEC_CONTROL.read()?;
+        if enable {
+            ctrl2 |= pml_bits;
+        } else {
+            ctrl2 &= !pml_bits;
+        }
+        VmcsField32Control::SECONDARY_VM_EXEC_CONTROL.write(ctrl2)?;
+        Ok(())
+    }
+
+    /// Update EFER.LMA and the "IA-32e mode guest" VM-entry control, which must be consistent.
+    fn set_long_mode_active(&mut self, active: bool) -> HvResult {
+        use vmx::flags::VmEntryControls as EntryCtrl;
+        // Keep reserved bits as they are.
+        let mut efer = VmcsField64Guest::IA32_EFER.read()?;
+        let mut ctrl = VmcsField32Control::VM_ENTRY_CONTROLS.read()?;
+        if active {
+            efer |= EferFlags::LONG_MODE_ACTIVE.bits();
+            ctrl |= EntryCtrl::IA32E_MODE.bits();
+        } else {
+            efer &= !EferFlags::LONG_MODE_ACTIVE.bits();
+            ctrl &= !EntryCtrl::IA32E_MODE.bits();
+        }
+        VmcsField64Guest::IA32_EFER.write(efer)?;
+        VmcsField32Control::VM_ENTRY_CONTROLS.write(ctrl)?;
+        Ok(())
+    }
+
     fn vmcs_setup(&mut self, linux: &LinuxContext, cell: &Cell) -> HvResult {
         let paddr = self.vmcs_region.paddr();
         Vmcs::clear(paddr)?;
@@ -259,10 +464,10 @@ impl Vcpu {
     fn load_vmcs_guest(&self, linux: &mut LinuxContext) -> HvResult {
         linux.rip = VmcsField64Guest::RIP.read()?;
         linux.rsp = VmcsField64Guest::RSP.read()?;
-        linux.cr0 = Cr0Flags::from_bits_truncate(VmcsField64Guest::CR0.read()?);
+        // As the guest has written, including bits owned by the host.
+        linux.cr0 = Cr0Flags::from_bits_truncate(self.cr(0));
         linux.cr3 = VmcsField64Guest::CR3.read()?;
-        linux.cr4 = Cr4Flags::from_bits_truncate(VmcsField64Guest::CR4.read()?)
-            - Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS;
+        linux.cr4 = Cr4Flags::from_bits_truncate(self.cr(4));
 
         linux.es.selector = SegmentSelector::from_raw(VmcsField16Guest::ES_SELECTOR.read()?);
         linux.cs.selector = SegmentSelector::from_raw(VmcsField16Guest::CS_SELECTOR.read()?);
@@ -319,6 +524,19 @@ impl Vcpu {
         if features.has_xsaves_xrstors() {
             val |= CpuCtrl2::XSAVES;
         }
+        if super::has_vpid() {
+            val |= CpuCtrl2::VPID;
+        }
+        if super::has_pml() {
+            let buffer = Frame::new()?;
+            VmcsField64Control::PML_ADDRESS.write(buffer.start_paddr() as _)?;
+            VmcsField16Guest::PML_INDEX.write(PML_ENTRIES as u16 - 1)?;
+            self.pml_buffer = Some(buffer);
+            // Turned on by `sync_dirty_log()` when dirty logging starts.
+            if super::hw_dirty_log_enabled() {
+                val |= CpuCtrl2::PAGE_MOD_LOGGING;
+            }
+        }
         Vmcs::set_control(
             VmcsField32Control::SECONDARY_VM_EXEC_CONTROL,
             Msr::IA32_VMX_PROCBASED_CTLS2.read(),
@@ -351,10 +569,11 @@ impl Vcpu {
         VmcsField32Control::VM_EXIT_MSR_LOAD_COUNT.write(0)?;
         VmcsField32Control::VM_ENTRY_MSR_LOAD_COUNT.write(0)?;
 
-        VmcsField64Control::CR4_GUEST_HOST_MASK.write(0)?;
         VmcsField32Control::CR3_TARGET_COUNT.write(0)?;
+        VmcsField32Control::PLE_GAP.write(PLE_GAP)?;
+        VmcsField32Control::PLE_WINDOW.write(PLE_WINDOW)?;
 
-        unsafe { cell.gpm.activate() }; // Set EPT_POINTER
+        self.load_cell(cell)?;
 
         VmcsField64Control::MSR_BITMAP.write(MSR_BITMAP.paddr() as _)?;
         VmcsField32Control::EXCEPTION_BITMAP.write(0)?;
@@ -399,7 +618,11 @@ impl VcpuAccessGuestState for Vcpu {
     fn cr(&self, cr_idx: usize) -> u64 {
         (|| -> HvResult<u64> {
             Ok(match cr_idx {
-                0 => VmcsField64Guest::CR0.read()?,
+                0 => {
+                    let host_mask = VmcsField64Control::CR0_GUEST_HOST_MASK.read()?;
+                    (VmcsField64Control::CR0_READ_SHADOW.read()? & host_mask)
+                        | (VmcsField64Guest::CR0.read()? & !host_mask)
+                }
                 3 => VmcsField64Guest::CR3.read()?,
                 4 => {
                     let host_mask = VmcsField64Control::CR4_GUEST_HOST_MASK.read()?;
@@ -435,9 +658,11 @@ impl VcpuAccessGuestState for Vcpu {
                 3 => VmcsField64Guest::CR3.write(val)?,
                 4 => {
                     // Retrieve/validate restrictions on CR4
+                    //
+                    // VMXE is required by VMX operation, the guest reads it from the shadow as
+                    // it has written.
                     let must0 = Msr::IA32_VMX_CR4_FIXED1.read();
                     let must1 = Msr::IA32_VMX_CR4_FIXED0.read();
-                    let val = val | Cr4Flags::VIRTUAL_MACHINE_EXTENSIONS.bits();
                     VmcsField64Guest::CR4.write((val & must0) | must1)?;
                     VmcsField64Control::CR4_READ_SHADOW.write(val)?;
                     VmcsField64Control::CR4_GUEST_HOST_MASK.write(must1 | !must0)?;
@@ -448,6 +673,10 @@ impl VcpuAccessGuestState for Vcpu {
         })()
         .expect("Failed to write guest control register")
     }
+
+    fn efer(&self) -> u64 {
+        VmcsField64Guest::IA32_EFER.read().unwrap()
+    }
 }
 
 impl Debug for Vcpu {
@@ -1,13 +1,20 @@
-use libvmm::vmx::vmcs::{EptViolationInfo, ExitInterruptInfo, VmExitInfo};
+use libvmm::vmx::vmcs::{CrAccessInfo, EptViolationInfo, ExitInterruptInfo, VmExitInfo};
 use libvmm::vmx::VmxExitReason;
+use x86_64::registers::control::Cr0Flags;
 
-use crate::arch::vmm::VmExit;
+use crate::arch::vmm::{ExitKind, VcpuAccessGuestState, VmExit};
 use crate::arch::ExceptionType;
 use crate::error::HvResult;
+use crate::percpu::CpuStat;
 
 impl VmExit<'_> {
     fn handle_exception_nmi(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let intr_info = ExitInterruptInfo::new()?;
+        if intr_info.vector == ExceptionType::NonMaskableInterrupt {
+            self.kind = ExitKind::Nmi;
+        } else {
+            self.cpu_data.inc_stat(CpuStat::VmExitsException);
+        }
         info!(
             "VM exit: Exception or NMI @ RIP({:#x}, {}): {:#x?}",
             exit_info.guest_rip, exit_info.exit_instruction_length, intr_info
@@ -23,6 +30,7 @@ impl VmExit<'_> {
 
     fn handle_ept_violation(&mut self, exit_info: &VmExitInfo) -> HvResult {
         let ept_vio_info = EptViolationInfo::new()?;
+        self.cpu_data.inc_stat(CpuStat::VmExitsMmio);
         warn!(
             "VM exit: EPT violation @ {:#x} RIP({:#x}, {}): {:#x?}",
             ept_vio_info.guest_paddr,
@@ -33,6 +41,47 @@ impl VmExit<'_> {
         hv_result_err!(ENOSYS)
     }
 
+    /// CR0 and CR4 writes are intercepted when the guest changes bits owned by the host, see
+    /// `set_cr()`. MOV from CR3 is not intercepted, but handled anyway.
+    fn handle_cr_access(&mut self, exit_info: &VmExitInfo) -> HvResult {
+        let cr_info = CrAccessInfo::new()?;
+        trace!(
+            "VM exit: CR access @ RIP({:#x}): {:#x?}",
+            exit_info.guest_rip,
+            cr_info
+        );
+        let instr_len = exit_info.exit_instruction_length as u8;
+        let cr0 = self.cpu_data.vcpu.cr(0);
+        match cr_info.access_type {
+            0 => {
+                let val = self.cpu_data.vcpu.gpr(cr_info.gpr);
+                self.handle_cr_write(cr_info.cr_number, val, instr_len)
+            }
+            1 => self.handle_cr_read(cr_info.cr_number, cr_info.gpr, instr_len),
+            2 => {
+                let val = cr0 & !Cr0Flags::TASK_SWITCHED.bits();
+                self.handle_cr_write(0, val, instr_len)
+            }
+            _ => {
+                // LMSW loads CR0[3:0], but cannot clear PE.
+                let val = (cr0 & !0b1110) | (cr_info.lmsw_source_data as u64 & 0b1111);
+                self.handle_cr_write(0, val, instr_len)
+            }
+        }
+    }
+
+    /// Returns the basic exit reason and the exit qualification.
+    #[cfg(feature = "trace")]
+    pub fn raw_exit_info(&self) -> (u32, u64) {
+        use bit_field::BitField;
+        use libvmm::vmx::vmcs::{VmcsField32ReadOnly, VmcsField64ReadOnly};
+        let reason = VmcsField32ReadOnly::VM_EXIT_REASON
+            .read()
+            .map_or(0, |r| r.get_bits(0..16));
+        let qualification = VmcsField64ReadOnly::EXIT_QUALIFICATION.read().unwrap_or(0);
+        (reason, qualification)
+    }
+
     pub fn handle_exit(&mut self) -> HvResult {
         let exit_info = VmExitInfo::new()?;
         trace!("VM exit: {:#x?}", exit_info);
@@ -45,13 +94,47 @@ impl VmExit<'_> {
         //     exit_info.exit_instruction_length as _,
         // )?;
 
+        self.kind = match exit_info.exit_reason {
+            VmxExitReason::EXCEPTION_NMI => ExitKind::Exception,
+            VmxExitReason::EXTERNAL_INTERRUPT => ExitKind::ExternalInterrupt,
+            VmxExitReason::CPUID => ExitKind::Cpuid,
+            VmxExitReason::VMCALL => ExitKind::Hypercall,
+            VmxExitReason::MSR_READ => ExitKind::MsrRead,
+            VmxExitReason::MSR_WRITE => ExitKind::MsrWrite,
+            VmxExitReason::EPT_VIOLATION | VmxExitReason::EPT_MISCONFIG => {
+                ExitKind::NestedPageFault
+            }
+            VmxExitReason::IO_INSTRUCTION => ExitKind::Io,
+            VmxExitReason::CR_ACCESS => ExitKind::CrAccess,
+            VmxExitReason::HLT | VmxExitReason::MWAIT_INSTRUCTION => ExitKind::Hlt,
+            VmxExitReason::PAUSE_INSTRUCTION => ExitKind::Pause,
+            VmxExitReason::PML_FULL => ExitKind::DirtyLogFull,
+            VmxExitReason::TRIPLE_FAULT => ExitKind::Shutdown,
+            _ => ExitKind::Other,
+        };
         let res = match exit_info.exit_reason {
             VmxExitReason::EXCEPTION_NMI => self.handle_exception_nmi(&exit_info),
             VmxExitReason::CPUID => self.handle_cpuid(),
+            VmxExitReason::XSETBV => self.handle_xsetbv(),
             VmxExitReason::VMCALL => self.handle_hypercall(),
             VmxExitReason::MSR_READ => self.handle_msr_read(),
             VmxExitReason::MSR_WRITE => self.handle_msr_write(),
             VmxExitReason::EPT_VIOLATION => self.handle_ept_violation(&exit_info),
+            VmxExitReason::CR_ACCESS => self.handle_cr_access(&exit_info),
+            VmxExitReason::HLT => self.handle_hlt(),
+            VmxExitReason::MWAIT_INSTRUCTION => self.handle_mwait(),
+            VmxExitReason::PAUSE_INSTRUCTION => self.handle_pause(),
+            VmxExitReason::INIT => {
+                // Only INITs sent through the xAPIC, whose SIPIs are not trapped and would never
+                // wake up a parked CPU. Keep running the guest, the driver refuses to offline
+                // CPUs in xAPIC mode.
+                warn!(
+                    "Ignored INIT received by CPU {} in xAPIC mode",
+                    self.cpu_data.id
+                );
+                Ok(())
+            }
+            VmxExitReason::PML_FULL => self.cpu_data.vcpu.sync_dirty_log(),
             VmxExitReason::TRIPLE_FAULT => {
                 error!("Triple fault: {:#x?}", exit_info);
                 self.cpu_data.vcpu.inject_fault()?;
@@ -3,19 +3,24 @@ mod context;
 mod cpuid;
 mod entry;
 mod exception;
+mod fpu;
+mod guest_page_table;
+mod mtrr;
 mod page_table;
 mod percpu;
 mod segmentation;
 mod tables;
 
+pub mod apic;
+pub mod asid;
 pub mod cpu;
 pub mod serial;
 pub mod vmm;
 
 pub use context::{GeneralRegisters, LinuxContext};
 pub use exception::ExceptionType;
+pub use fpu::FpuState;
+pub use guest_page_table::GuestPageTable;
 pub use page_table::PageTable as HostPageTable;
-pub use page_table::PageTable as GuestPageTable;
-pub use page_table::PageTableImmut as GuestPageTableImmut;
 pub use percpu::ArchPerCpu;
 pub use vmm::NestedPageTable;
@@ -0,0 +1,169 @@
+//! Memory Type Range Registers (MTRRs) of the host, see Intel SDM Volume 3A, Section 11.11.
+
+#![cfg_attr(not(feature = "intel"), allow(dead_code))]
+
+use alloc::vec::Vec;
+
+use bit_field::BitField;
+use libvmm::msr::Msr;
+use numeric_enum_macro::numeric_enum;
+
+use crate::memory::PhysAddr;
+
+numeric_enum! {
+    #[repr(u8)]
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum MemType {
+        Uncached = 0,
+        WriteCombining = 1,
+        WriteThrough = 4,
+        WriteProtected = 5,
+        WriteBack = 6,
+    }
+}
+
+const FIXED_MTRRS: [Msr; 11] = [
+    Msr::IA32_MTRR_FIX64K_00000,
+    Msr::IA32_MTRR_FIX16K_80000,
+    Msr::IA32_MTRR_FIX16K_A0000,
+    Msr::IA32_MTRR_FIX4K_C0000,
+    Msr::IA32_MTRR_FIX4K_C8000,
+    Msr::IA32_MTRR_FIX4K_D0000,
+    Msr::IA32_MTRR_FIX4K_D8000,
+    Msr::IA32_MTRR_FIX4K_E0000,
+    Msr::IA32_MTRR_FIX4K_E8000,
+    Msr::IA32_MTRR_FIX4K_F0000,
+    Msr::IA32_MTRR_FIX4K_F8000,
+];
+
+const PHYS_ADDR_MASK: u64 = 0x000f_ffff_ffff_f000; // 12..52
+
+struct VariableRange {
+    base: u64,
+    mask: u64,
+    mem_type: u8,
+}
+
+struct HostMtrrs {
+    enabled: bool,
+    fixed_enabled: bool,
+    default_type: u8,
+    fixed: [u64; FIXED_MTRRS.len()],
+    variable: Vec<VariableRange>,
+}
+
+lazy_static! {
+    static ref HOST_MTRRS: HostMtrrs = HostMtrrs::read();
+}
+
+impl MemType {
+    fn from_raw(raw: u8) -> Self {
+        // Reserved encodings are treated as UC.
+        Self::try_from(raw).unwrap_or(Self::Uncached)
+    }
+}
+
+impl HostMtrrs {
+    fn read() -> Self {
+        let cap = Msr::IA32_MTRRCAP.read();
+        let def_type = Msr::IA32_MTRR_DEF_TYPE.read();
+        let mut fixed = [0; FIXED_MTRRS.len()];
+        let fixed_enabled = cap.get_bit(8) && def_type.get_bit(10);
+        if fixed_enabled {
+            for (val, msr) in fixed.iter_mut().zip(FIXED_MTRRS) {
+                *val = msr.read();
+            }
+        }
+        let mut variable = Vec::new();
+        for i in 0..cap.get_bits(0..8) as u32 {
+            let (base, mask) = unsafe {
+                (
+                    x86::msr::rdmsr(Msr::IA32_MTRR_PHYSBASE0 as u32 + i * 2),
+                    x86::msr::rdmsr(Msr::IA32_MTRR_PHYSMASK0 as u32 + i * 2),
+                )
+            };
+            if mask.get_bit(11) {
+                variable.push(VariableRange {
+                    base: base & PHYS_ADDR_MASK,
+                    mask: mask & PHYS_ADDR_MASK,
+                    mem_type: base.get_bits(0..8) as u8,
+                });
+            }
+        }
+        Self {
+            enabled: def_type.get_bit(11),
+            fixed_enabled,
+            default_type: def_type.get_bits(0..8) as u8,
+            fixed,
+            variable,
+        }
+    }
+
+    fn fixed_type(&self, paddr: u64) -> Option<u8> {
+        let (idx, offset) = match paddr {
+            0..=0x7_ffff => (0, paddr >> 16),
+            0x8_0000..=0x9_ffff => (1, (paddr - 0x8_0000) >> 14),
+            0xa_0000..=0xb_ffff => (2, (paddr - 0xa_0000) >> 14),
+            0xc_0000..=0xf_ffff => (3 + ((paddr - 0xc_0000) >> 15) as usize, (paddr >> 12) & 7),
+            _ => return None,
+        };
+        Some(self.fixed[idx].get_bits(offset as usize * 8..offset as usize * 8 + 8) as u8)
+    }
+
+    fn memory_type(&self, paddr: u64) -> MemType {
+        if !self.enabled {
+            return MemType::Uncached;
+        }
+        if self.fixed_enabled {
+            if let Some(t) = self.fixed_type(paddr) {
+                return MemType::from_raw(t);
+            }
+        }
+        // See Intel SDM Volume 3A, Section 11.11.4.1 for the precedences of overlapped ranges.
+        let mut ret: Option<MemType> = None;
+        for range in self
+            .variable
+            .iter()
+            .filter(|r| paddr & r.mask == r.base & r.mask)
+        {
+            let t = MemType::from_raw(range.mem_type);
+            ret = match (ret, t) {
+                (None, t) => Some(t),
+                (Some(MemType::Uncached), _) | (_, MemType::Uncached) => Some(MemType::Uncached),
+                (Some(MemType::WriteThrough), MemType::WriteBack)
+                | (Some(MemType::WriteBack), MemType::WriteThrough) => Some(MemType::WriteThrough),
+                (Some(old), t) if old == t => Some(t),
+                _ => Some(MemType::Uncached),
+            };
+        }
+        ret.unwrap_or_else(|| MemType::from_raw(self.default_type))
+    }
+
+    /// Whether the memory type is the same in `[paddr, paddr + size)`, where `size` is a power
+    /// of 2 and `paddr` is aligned to it.
+    fn is_uniform(&self, paddr: u64, size: u64) -> bool {
+        if !self.enabled {
+            return true;
+        }
+        // Fixed ranges have 4K granularity, huge pages below 1M are not worth checking.
+        if self.fixed_enabled && paddr < 0x10_0000 {
+            return false;
+        }
+        let offset_mask = size - 1;
+        self.variable.iter().all(|r| {
+            // Ranges that cover the whole page or do not intersect it keep the type uniform.
+            let high_mask = r.mask & !offset_mask;
+            r.mask & offset_mask == 0 || paddr & high_mask != r.base & high_mask
+        })
+    }
+}
+
+/// Returns the memory type of the physical address `paddr` specified by host MTRRs.
+pub fn memory_type(paddr: PhysAddr) -> MemType {
+    HOST_MTRRS.memory_type(paddr as u64)
+}
+
+/// Whether host MTRRs specify the same memory type for the whole page of `size` at `paddr`.
+pub fn is_uniform(paddr: PhysAddr, size: usize) -> bool {
+    HOST_MTRRS.is_uniform(paddr as u64, size as u64)
+}
@@ -8,8 +8,8 @@ use x86_64::{
     structures::paging::PhysFrame,
 };
 
+use crate::memory::Level4PageTable;
 use crate::memory::{GenericPTE, MemFlags, PagingInstr, PhysAddr, VirtAddr};
-use crate::memory::{Level4PageTable, Level4PageTableImmut};
 
 impl From<MemFlags> for PTF {
     fn from(f: MemFlags) -> Self {
@@ -26,8 +26,11 @@ impl From<MemFlags> for PTF {
         if f.contains(MemFlags::USER) {
             ret |= Self::USER_ACCESSIBLE;
         }
+        // PAT entries are setup in `ArchPerCpu::init()`.
         if f.contains(MemFlags::IO) {
             ret |= Self::NO_CACHE;
+        } else if f.contains(MemFlags::WRITE_COMBINING) {
+            ret |= Self::WRITE_THROUGH;
         }
         ret
     }
@@ -50,6 +53,8 @@ impl From<PTF> for MemFlags {
         }
         if f.contains(PTF::NO_CACHE) {
             ret |= Self::IO;
+        } else if f.contains(PTF::WRITE_THROUGH) {
+            ret |= Self::WRITE_COMBINING;
         }
         ret
     }
@@ -76,6 +81,9 @@ impl GenericPTE for PTEntry {
     fn is_huge(&self) -> bool {
         PTF::from_bits_truncate(self.0).contains(PTF::HUGE_PAGE)
     }
+    fn is_dirty(&self) -> bool {
+        PTF::from_bits_truncate(self.0).contains(PTF::DIRTY)
+    }
 
     fn set_addr(&mut self, paddr: PhysAddr) {
         self.0 = (self.0 & !PHYS_ADDR_MASK) | (paddr as u64 & PHYS_ADDR_MASK);
@@ -91,6 +99,9 @@ impl GenericPTE for PTEntry {
         self.0 = (paddr as u64 & PHYS_ADDR_MASK)
             | (PTF::PRESENT | PTF::WRITABLE | PTF::USER_ACCESSIBLE).bits();
     }
+    fn clear_dirty(&mut self) {
+        self.0 &= !PTF::DIRTY.bits();
+    }
     fn clear(&mut self) {
         self.0 = 0
     }
@@ -126,4 +137,3 @@ impl PagingInstr for X86PagingInstr {
 }
 
 pub type PageTable = Level4PageTable<VirtAddr, PTEntry, X86PagingInstr>;
-pub type PageTableImmut = Level4PageTableImmut<VirtAddr, PTEntry>;
@@ -1,11 +1,13 @@
 use libvmm::msr::Msr;
 use x86::{segmentation, segmentation::SegmentSelector};
 
+use super::apic;
 use super::tables::{GdtStruct, TssStruct, IDT};
 
 pub struct ArchPerCpu {
     tss: TssStruct,
     gdt: GdtStruct,
+    apic_id: u32,
 }
 
 impl ArchPerCpu {
@@ -28,5 +30,17 @@ impl ArchPerCpu {
 
         // PAT0: WB, PAT1: WC, PAT2: UC
         unsafe { Msr::IA32_PAT.write(0x070106) };
+
+        self.apic_id = apic::apic_id();
+    }
+
+    /// Returns the local APIC ID of this CPU.
+    pub fn apic_id(&self) -> u32 {
+        self.apic_id
+    }
+
+    /// Send an NMI to this CPU.
+    pub fn send_nmi(&self) {
+        apic::send_nmi(self.apic_id)
     }
 }
@@ -90,6 +90,16 @@ impl Segment {
         }
     }
 
+    /// A segment in real mode, whose base is `selector << 4` and limit is 64 KB.
+    pub fn real_mode(selector: u16, access_rights: SegmentAccessRights) -> Self {
+        Self {
+            selector: SegmentSelector::from_raw(selector),
+            base: (selector as u64) << 4,
+            limit: 0xffff,
+            access_rights,
+        }
+    }
+
     pub fn from_selector(selector: SegmentSelector, gdt: &DescriptorTablePointer) -> Self {
         let index = selector.index() as usize;
         let table = GdtStruct::from_pointer(gdt);
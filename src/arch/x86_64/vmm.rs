@@ -6,12 +6,29 @@ mod vendor;
 #[path = "amd/mod.rs"]
 mod vendor;
 
+use core::convert::TryFrom;
+use core::sync::atomic::Ordering;
+
+use libvmm::msr::Msr;
+use numeric_enum_macro::numeric_enum;
 use x86_64::registers::control::{Cr0Flags, Cr4Flags};
+use x86_64::registers::model_specific::EferFlags;
+use x86_64::registers::rflags::RFlags;
 
+use super::apic::{self, IpiDestination, StartupIpi};
 use super::GeneralRegisters;
-use crate::{error::HvResult, percpu::PerCpu};
+use crate::cell::{Cell, ROOT_CELL_ID};
+use crate::config::CellFlags;
+use crate::error::HvResult;
+use crate::header::HvHeader;
+use crate::hypercall::PvFeatures;
+use crate::percpu::{CpuEvents, CpuStat, PerCpu};
+use crate::stats::{Histogram, Instant};
+#[cfg(feature = "trace")]
+use crate::trace::TraceEntry;
 
-pub use vendor::{check_hypervisor_feature, NestedPageTable, Vcpu};
+pub use vendor::{check_hypervisor_feature, max_asid, NestedPageTable, Vcpu};
+pub use vendor::{set_hw_dirty_log, take_hw_dirty_log};
 
 pub trait VcpuAccessGuestState {
     // Architecture independent methods:
@@ -26,6 +43,27 @@ pub trait VcpuAccessGuestState {
     fn set_return_val(&mut self, ret_val: usize) {
         self.regs_mut().rax = ret_val as _
     }
+    /// Set at most 4 values returned by a hypercall, in RAX, RDI, RSI and RDX.
+    fn set_return_vals(&mut self, vals: &[u64]) {
+        let regs = self.regs_mut();
+        let ret_regs = [&mut regs.rax, &mut regs.rdi, &mut regs.rsi, &mut regs.rdx];
+        for (reg, &val) in ret_regs.into_iter().zip(vals) {
+            *reg = val;
+        }
+    }
+    /// Returns the general register numbered `index` in instruction encodings, including RSP.
+    fn gpr(&self, index: u8) -> u64 {
+        match index {
+            4 => self.stack_pointer(),
+            _ => self.regs().get_reg_of_index(index),
+        }
+    }
+    fn set_gpr(&mut self, index: u8, val: u64) {
+        match index {
+            4 => self.set_stack_pointer(val),
+            _ => self.regs_mut().set_reg_of_index(index, val),
+        }
+    }
 
     // Methods only available for x86 cpus:
     fn rflags(&self) -> u64;
@@ -33,12 +71,72 @@ pub trait VcpuAccessGuestState {
     fn gs_base(&self) -> u64;
     fn cr(&self, cr_idx: usize) -> u64;
     fn set_cr(&mut self, cr_idx: usize, val: u64);
+    fn efer(&self) -> u64;
+}
+
+numeric_enum! {
+    #[repr(u32)]
+    /// Vendor independent classes of VM exits, used for statistics.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum ExitKind {
+        Other = 0,
+        Exception = 1,
+        Nmi = 2,
+        ExternalInterrupt = 3,
+        Cpuid = 4,
+        Hypercall = 5,
+        MsrRead = 6,
+        MsrWrite = 7,
+        NestedPageFault = 8,
+        Io = 9,
+        CrAccess = 10,
+        Hlt = 11,
+        DirtyLogFull = 12,
+        Shutdown = 13,
+        Pause = 14,
+    }
+}
+
+const NUM_EXIT_KINDS: usize = 15;
+
+/// Number and handling cycles of VM exits of each `ExitKind` on a CPU, only recorded with the
+/// `stats` feature.
+pub struct ExitStats {
+    cycles: [Histogram; NUM_EXIT_KINDS],
+}
+
+impl ExitStats {
+    pub const fn new() -> Self {
+        #[allow(clippy::declare_interior_mutable_const)]
+        const EMPTY: Histogram = Histogram::new();
+        Self {
+            cycles: [EMPTY; NUM_EXIT_KINDS],
+        }
+    }
+
+    fn record(&self, kind: ExitKind, cycles: u64) {
+        self.cycles[kind as usize].atomic_add(cycles);
+    }
+
+    /// Print statistics of all VM exits occurred on CPU `cpu_id`.
+    pub fn dump(&self, cpu_id: u32) {
+        for (i, hist) in self.cycles.iter().enumerate() {
+            if hist.count() > 0 {
+                let kind = ExitKind::try_from(i as u32).unwrap();
+                println!("CPU {} VM exit {:?}: {}", cpu_id, kind, hist.as_string());
+            }
+        }
+    }
 }
 
 const VM_EXIT_LEN_CPUID: u8 = 2;
 const VM_EXIT_LEN_RDMSR: u8 = 2;
 const VM_EXIT_LEN_WRMSR: u8 = 2;
 const VM_EXIT_LEN_HYPERCALL: u8 = 3;
+const VM_EXIT_LEN_HLT: u8 = 1;
+const VM_EXIT_LEN_MWAIT: u8 = 3;
+const VM_EXIT_LEN_PAUSE: u8 = 2;
+const VM_EXIT_LEN_XSETBV: u8 = 3;
 
 const HOST_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
     Cr0Flags::PAGING.bits()
@@ -48,23 +146,190 @@ const HOST_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
         | Cr0Flags::MONITOR_COPROCESSOR.bits()
         | Cr0Flags::PROTECTED_MODE_ENABLE.bits(),
 );
-const HOST_CR4: Cr4Flags = Cr4Flags::PHYSICAL_ADDRESS_EXTENSION;
+/// SSE is enabled for the hypervisor, see `fpu`.
+const HOST_CR4: Cr4Flags = Cr4Flags::from_bits_truncate(
+    Cr4Flags::PHYSICAL_ADDRESS_EXTENSION.bits()
+        | Cr4Flags::OSFXSR.bits()
+        | Cr4Flags::OSXMMEXCPT_ENABLE.bits(),
+);
+
+/// Value of CR0 after INIT.
+const INIT_CR0: Cr0Flags = Cr0Flags::from_bits_truncate(
+    Cr0Flags::CACHE_DISABLE.bits()
+        | Cr0Flags::NOT_WRITE_THROUGH.bits()
+        | Cr0Flags::EXTENSION_TYPE.bits(),
+);
+
+/// Invalidate cached translations derived from nested page tables on all CPUs running the
+/// hypervisor.
+pub(super) fn nested_tlb_shootdown() {
+    let cpu_data = PerCpu::current_mut();
+    if cpu_data.is_hv_enabled() {
+        if let Err(e) = cpu_data.vcpu.flush_nested_tlb() {
+            error!("Failed to flush nested TLB: {:?}", e);
+        }
+    }
+    PerCpu::broadcast_events(CpuEvents::FLUSH_NESTED_TLB);
+}
+
+/// Whether HLT and MWAIT are trapped in `cell`. CPUs idle in the hypervisor with MWAIT, which
+/// must be woken up by interrupts left pending for the guest.
+pub(super) fn hlt_exiting(cell: &Cell) -> bool {
+    if !cell.config.flags().contains(CellFlags::HLT_EXITING) {
+        return false;
+    }
+    if !super::cpu::has_mwait_interrupt_break() {
+        if !cell.hlt_exiting_warned.swap(true, Ordering::Relaxed) {
+            warn!(
+                "HLT exiting of cell {} is not supported",
+                cell.config.name()
+            );
+        }
+        return false;
+    }
+    true
+}
+
+/// Paravirtual features of `cell` in CPUID leaf 0x4000_0001.
+fn pv_features(cell: &Cell) -> PvFeatures {
+    let flags = cell.config.flags();
+    let mut features = PvFeatures::ABI_INFO;
+    if flags.contains(CellFlags::VIRTUAL_CONSOLE_PERMITTED) {
+        features |= PvFeatures::DEBUG_CONSOLE;
+    }
+    // Same as `hlt_exiting()`, without warnings.
+    if flags.contains(CellFlags::HLT_EXITING) && super::cpu::has_mwait_interrupt_break() {
+        features |= PvFeatures::HLT_IDLE;
+    }
+    features
+}
+
+/// Apply the CPUID overrides of `cell` to the results `res` of leaf `function`, subleaf `index`.
+fn apply_cpuid_overrides(cell: &Cell, function: u32, index: u32, res: &mut [u32; 4]) {
+    for entry in cell.config.cpuid_entries() {
+        if entry.matches(function, index) {
+            entry.apply(res);
+        }
+    }
+}
+
+/// CPUID leaf `function`, subleaf `index` of the processor with the overrides of `cell`.
+fn cell_cpuid(cell: &Cell, function: u32, index: u32) -> [u32; 4] {
+    use super::cpuid::cpuid;
+    let res = cpuid!(function, index);
+    let mut res = [res.eax, res.ebx, res.ecx, res.edx];
+    apply_cpuid_overrides(cell, function, index, &mut res);
+    res
+}
+
+/// XCR0 bits `cell` can set: states supported by the processor and reported in leaf 0xD after
+/// the CPUID overrides, without states of features hidden by them.
+fn cell_xcr0(cell: &Cell) -> u64 {
+    use super::cpuid::{CpuIdEax, FeatureInfoFlags};
+    use super::fpu::{supported_xcr0, XCR0_AMX, XCR0_AVX512, XCR0_MPX};
+    use x86_64::registers::xcontrol::XCr0Flags;
+    let features = cell_cpuid(cell, CpuIdEax::FeatureInfo as _, 0);
+    let features = FeatureInfoFlags::from_bits_truncate(features[2] as _);
+    if supported_xcr0() == 0 || !features.contains(FeatureInfoFlags::XSAVE) {
+        return 0;
+    }
+    let states = cell_cpuid(cell, CpuIdEax::ExtendedState as _, 0);
+    let mut xcr0 = supported_xcr0() & ((states[3] as u64) << 32 | states[0] as u64);
+    // AVX512F in EBX[16], MPX in EBX[14] and AMX-TILE in EDX[24].
+    let ext_features = cell_cpuid(cell, CpuIdEax::ExtendedFeatures as _, 0);
+    if !features.contains(FeatureInfoFlags::AVX) {
+        xcr0 &= !(XCr0Flags::AVX.bits() | XCR0_AVX512);
+    }
+    if ext_features[1] & (1 << 16) == 0 {
+        xcr0 &= !XCR0_AVX512;
+    }
+    if ext_features[1] & (1 << 14) == 0 {
+        xcr0 &= !XCR0_MPX;
+    }
+    if ext_features[3] & (1 << 24) == 0 {
+        xcr0 &= !XCR0_AMX;
+    }
+    xcr0 | XCr0Flags::X87.bits()
+}
+
+/// Limit the numbers of logical processors and cores in topology leaves to the CPUs of the
+/// cell. APIC ID shifts are kept, as the cell sees physical APIC IDs.
+fn limit_cpuid_topology(function: u32, num_cpus: u32, res: &mut [u32; 4]) {
+    use super::cpuid::CpuIdEax;
+    if function == CpuIdEax::FeatureInfo as _ {
+        // Maximum number of addressable logical processor IDs in EBX[23:16].
+        let n = ((res[1] >> 16) & 0xff).min(num_cpus);
+        res[1] = (res[1] & !0xff_0000) | n << 16;
+    } else if function == CpuIdEax::CacheParams as _ {
+        // Maximum number of addressable core IDs minus one in EAX[31:26].
+        let n = (res[0] >> 26).min(num_cpus.saturating_sub(1));
+        res[0] = (res[0] & !(0x3f << 26)) | n << 26;
+    } else if function == CpuIdEax::ExtendedTopology as _
+        || function == CpuIdEax::ExtendedTopologyV2 as _
+    {
+        // Number of logical processors at this level in EBX[15:0].
+        res[1] = (res[1] & 0xffff).min(num_cpus);
+    } else if function == CpuIdEax::AmdProcessorCapacity as _ {
+        // Number of physical cores minus one in ECX[7:0].
+        let nc = (res[2] & 0xff).min(num_cpus.saturating_sub(1));
+        res[2] = (res[2] & !0xff) | nc;
+    }
+}
+
+/// Returns whether writing `val` to CR0 or CR4 causes #GP.
+fn is_illegal_cr_write(vcpu: &Vcpu, cr: u8, val: u64) -> bool {
+    let efer = EferFlags::from_bits_truncate(vcpu.efer());
+    match cr {
+        0 => {
+            let cr0 = Cr0Flags::from_bits_truncate(val);
+            let cr4 = Cr4Flags::from_bits_truncate(vcpu.cr(4));
+            let paging = cr0.contains(Cr0Flags::PAGING);
+            val >> 32 != 0
+                || (paging && !cr0.contains(Cr0Flags::PROTECTED_MODE_ENABLE))
+                || (cr0.contains(Cr0Flags::NOT_WRITE_THROUGH)
+                    && !cr0.contains(Cr0Flags::CACHE_DISABLE))
+                || (paging
+                    && efer.contains(EferFlags::LONG_MODE_ENABLE)
+                    && !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION))
+        }
+        4 => {
+            let cr4 = Cr4Flags::from_bits_truncate(val);
+            let long_mode = efer.contains(EferFlags::LONG_MODE_ACTIVE);
+            val & !Vcpu::supported_cr4_bits() != 0
+                || (long_mode && !cr4.contains(Cr4Flags::PHYSICAL_ADDRESS_EXTENSION))
+                || (!long_mode && cr4.contains(Cr4Flags::PCID))
+        }
+        _ => false,
+    }
+}
+
+fn msr_stat(id: u64) -> CpuStat {
+    if id == Msr::IA32_X2APIC_ICR as u64 {
+        CpuStat::VmExitsMsrX2apicIcr
+    } else {
+        CpuStat::VmExitsMsrOther
+    }
+}
 
 pub(super) struct VmExit<'a> {
     pub cpu_data: &'a mut PerCpu,
+    /// Set by the vendor specific `handle_exit`.
+    pub kind: ExitKind,
 }
 
 impl VmExit<'_> {
     pub fn new() -> Self {
         Self {
             cpu_data: PerCpu::current_mut(),
+            kind: ExitKind::Other,
         }
     }
 
     pub fn handle_msr_read(&mut self) -> HvResult {
-        let guest_regs = self.cpu_data.vcpu.regs_mut();
-        let id = guest_regs.rcx;
+        let id = self.cpu_data.vcpu.regs().rcx;
+        self.cpu_data.inc_stat(msr_stat(id));
         warn!("VM exit: RDMSR({:#x})", id);
+        let guest_regs = self.cpu_data.vcpu.regs_mut();
         // TODO
         guest_regs.rax = 0;
         guest_regs.rdx = 0;
@@ -76,69 +341,222 @@ impl VmExit<'_> {
         let guest_regs = self.cpu_data.vcpu.regs();
         let id = guest_regs.rcx;
         let value = guest_regs.rax | (guest_regs.rdx << 32);
-        warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
-        // TODO
+        self.cpu_data.inc_stat(msr_stat(id));
+        if id == Msr::IA32_X2APIC_ICR as u64 {
+            self.handle_x2apic_icr_write(value);
+        } else {
+            warn!("VM exit: WRMSR({:#x}) <- {:#x}", id, value);
+            // TODO
+        }
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_WRMSR)?;
         Ok(())
     }
 
+    /// INIT and SIPI sent to CPUs running the hypervisor are delivered as `CpuEvents`, so that
+    /// they are parked in the hypervisor instead of being reset. Other IPIs are sent as is.
+    ///
+    /// INIT and SIPI to CPUs outside the hypervisor (a CPU coming online for the first time) are
+    /// only sent by the root cell while there is room for another CPU, as the driver makes the
+    /// CPU enter the hypervisor once it is online.
+    fn handle_x2apic_icr_write(&mut self, icr: u64) {
+        let (ipi, dest) = match StartupIpi::from_x2apic_icr(icr) {
+            Some(res) => res,
+            None => return apic::write_x2apic_icr(icr),
+        };
+        let cell_id = self.cpu_data.cell().id;
+        let self_id = self.cpu_data.id;
+        let mut targets =
+            PerCpu::entered().filter(|cpu_data| cpu_data.id != self_id && cpu_data.is_hv_enabled());
+        match dest {
+            IpiDestination::Physical(apic_id) => {
+                match targets.find(|cpu_data| cpu_data.apic_id() == apic_id) {
+                    Some(target) if target.cell().id == cell_id => target.send_startup_ipi(ipi),
+                    Some(target) => warn!(
+                        "CPU {} cannot send {:?} to CPU {} in another cell",
+                        self_id, ipi, target.id
+                    ),
+                    None if cell_id == ROOT_CELL_ID
+                        && PerCpu::entered_cpus() < HvHeader::get().max_cpus =>
+                    {
+                        apic::write_x2apic_icr(icr)
+                    }
+                    None => warn!(
+                        "CPU {} cannot send {:?} to APIC ID {} outside the hypervisor",
+                        self_id, ipi, apic_id
+                    ),
+                }
+            }
+            IpiDestination::AllExcludingSelf => {
+                for target in targets.filter(|cpu_data| cpu_data.cell().id == cell_id) {
+                    target.send_startup_ipi(ipi);
+                }
+            }
+            IpiDestination::Unsupported => {
+                warn!("Unsupported destination of {:?}: ICR={:#x}", ipi, icr)
+            }
+        }
+    }
+
     pub fn handle_cpuid(&mut self) -> HvResult {
         use super::cpuid::{cpuid, CpuIdEax, FeatureInfoFlags};
+        self.cpu_data.inc_stat(CpuStat::VmExitsCpuid);
         let signature = unsafe { &*("RVMRVMRVMRVM".as_ptr() as *const [u32; 3]) };
         let cr4_flags = Cr4Flags::from_bits_truncate(self.cpu_data.vcpu.cr(4));
+        let cell = self.cpu_data.cell();
+        let config = &cell.config;
         let guest_regs = self.cpu_data.vcpu.regs_mut();
         let function = guest_regs.rax as u32;
-        if function == CpuIdEax::HypervisorInfo as _ {
-            guest_regs.rax = CpuIdEax::HypervisorFeatures as u32 as _;
-            guest_regs.rbx = signature[0] as _;
-            guest_regs.rcx = signature[1] as _;
-            guest_regs.rdx = signature[2] as _;
+        let index = guest_regs.rcx as u32;
+
+        let mut res = if function == CpuIdEax::HypervisorInfo as _ {
+            [
+                CpuIdEax::HypervisorFeatures as _,
+                signature[0],
+                signature[1],
+                signature[2],
+            ]
         } else if function == CpuIdEax::HypervisorFeatures as _ {
-            guest_regs.rax = 0;
-            guest_regs.rbx = 0;
-            guest_regs.rcx = 0;
-            guest_regs.rdx = 0;
+            [pv_features(cell).bits(), 0, 0, 0]
         } else {
-            let res = cpuid!(guest_regs.rax, guest_regs.rcx);
-            guest_regs.rax = res.eax as _;
-            guest_regs.rbx = res.ebx as _;
-            guest_regs.rcx = res.ecx as _;
-            guest_regs.rdx = res.edx as _;
-            if function == CpuIdEax::FeatureInfo as _ {
-                let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
-                if cr4_flags.contains(Cr4Flags::OSXSAVE) {
-                    flags.insert(FeatureInfoFlags::OSXSAVE);
-                }
-                flags.remove(FeatureInfoFlags::VMX);
-                flags.insert(FeatureInfoFlags::HYPERVISOR);
-                guest_regs.rcx = flags.bits();
-            } else if function == CpuIdEax::AmdFeatureInfo as _ {
-                let mut flags = FeatureInfoFlags::from_bits_truncate(guest_regs.rcx as _);
-                flags.remove(FeatureInfoFlags::SVM);
-                guest_regs.rcx = flags.bits();
+            let res = cpuid!(function, index);
+            [res.eax, res.ebx, res.ecx, res.edx]
+        };
+        apply_cpuid_overrides(cell, function, index, &mut res);
+
+        // Features below can not be overridden by the cell configuration.
+        if function == CpuIdEax::FeatureInfo as _ {
+            let mut flags = FeatureInfoFlags::from_bits_truncate(res[2] as _);
+            if cr4_flags.contains(Cr4Flags::OSXSAVE) {
+                flags.insert(FeatureInfoFlags::OSXSAVE);
+            } else {
+                flags.remove(FeatureInfoFlags::OSXSAVE);
             }
+            flags.remove(FeatureInfoFlags::VMX);
+            if config.flags().contains(CellFlags::HLT_EXITING) {
+                flags.remove(FeatureInfoFlags::MONITOR);
+            }
+            flags.insert(FeatureInfoFlags::HYPERVISOR);
+            res[2] = flags.bits() as _;
+        } else if function == CpuIdEax::AmdFeatureInfo as _ {
+            let mut flags = FeatureInfoFlags::from_bits_truncate(res[2] as _);
+            flags.remove(FeatureInfoFlags::SVM);
+            res[2] = flags.bits() as _;
+        } else if function == CpuIdEax::ExtendedState as _ && index == 0 {
+            // States which can be enabled in XCR0 in EDX:EAX.
+            let xcr0 = cell_xcr0(cell);
+            res[0] = xcr0 as u32;
+            res[3] = (xcr0 >> 32) as u32;
         }
+        limit_cpuid_topology(function, config.num_cpus(), &mut res);
+
+        guest_regs.rax = res[0] as _;
+        guest_regs.rbx = res[1] as _;
+        guest_regs.rcx = res[2] as _;
+        guest_regs.rdx = res[3] as _;
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_CPUID)?;
         Ok(())
     }
 
+    /// Emulate a write of `val` to CR`cr` by MOV to CR, CLTS or LMSW of `instr_len` bytes.
+    /// Illegal values cause #GP instead.
+    pub fn handle_cr_write(&mut self, cr: u8, val: u64, instr_len: u8) -> HvResult {
+        self.cpu_data.inc_stat(CpuStat::VmExitsCr);
+        let vcpu = &mut self.cpu_data.vcpu;
+        if is_illegal_cr_write(vcpu, cr, val) {
+            warn!("Illegal write to CR{}: {:#x}", cr, val);
+            return vcpu.inject_fault();
+        }
+        // CR3 loads are not intercepted.
+        match cr {
+            0 => vcpu.write_guest_cr0(val)?,
+            4 => vcpu.set_cr(4, val),
+            _ => return hv_result_err!(ENOSYS),
+        }
+        // Drop guest linear translations (with the nested ones), as the processor does.
+        vcpu.flush_nested_tlb()?;
+        vcpu.advance_rip(instr_len)
+    }
+
+    /// Emulate MOV from CR`cr` to the general register `gpr`, of `instr_len` bytes.
+    pub fn handle_cr_read(&mut self, cr: u8, gpr: u8, instr_len: u8) -> HvResult {
+        self.cpu_data.inc_stat(CpuStat::VmExitsCr);
+        let vcpu = &mut self.cpu_data.vcpu;
+        let val = match cr {
+            0 | 3 | 4 => vcpu.cr(cr as usize),
+            _ => return hv_result_err!(ENOSYS),
+        };
+        vcpu.set_gpr(gpr, val);
+        vcpu.advance_rip(instr_len)
+    }
+
+    /// Only XCR0 can be written, with states reported by CPUID leaf 0xD of the cell. Other values
+    /// cause #GP.
+    pub fn handle_xsetbv(&mut self) -> HvResult {
+        use super::fpu::is_valid_xcr0;
+        self.cpu_data.inc_stat(CpuStat::VmExitsXsetbv);
+        let guest_regs = self.cpu_data.vcpu.regs();
+        let index = guest_regs.rcx as u32;
+        let val = (guest_regs.rax & 0xffff_ffff) | (guest_regs.rdx << 32);
+        if index != 0 || !is_valid_xcr0(val, cell_xcr0(self.cpu_data.cell())) {
+            warn!("Illegal XSETBV({:#x}) <- {:#x}", index, val);
+            return self.cpu_data.vcpu.inject_fault();
+        }
+        self.cpu_data.fpu.set_guest_xcr0(val);
+        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_XSETBV)
+    }
+
+    /// Idle in the hypervisor until an event arrives, or an interrupt is pending if the guest
+    /// can take it. The guest continues after HLT, as it does after interrupts.
+    pub fn handle_hlt(&mut self) -> HvResult {
+        let rflags = RFlags::from_bits_truncate(self.cpu_data.vcpu.rflags());
+        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_HLT)?;
+        self.cpu_data.idle(rflags.contains(RFlags::INTERRUPT_FLAG));
+        Ok(())
+    }
+
+    /// MONITOR is hidden from cells with HLT exiting, and writes to the monitored range cannot
+    /// be detected, so MWAIT returns at once, as a spurious wake-up.
+    pub fn handle_mwait(&mut self) -> HvResult {
+        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_MWAIT)
+    }
+
+    /// The guest spins with PAUSE for long, usually waiting for a lock held by a CPU
+    /// that is not running, e.g. parked or handling a VM exit.
+    pub fn handle_pause(&mut self) -> HvResult {
+        self.cpu_data.inc_stat(CpuStat::VmExitsPause);
+        debug!(
+            "CPU {} is spinning @ RIP({:#x})",
+            self.cpu_data.id,
+            self.cpu_data.vcpu.instr_pointer()
+        );
+        self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_PAUSE)
+    }
+
     pub fn handle_hypercall(&mut self) -> HvResult {
-        use crate::hypercall::HyperCall;
+        use crate::hypercall::{HyperCall, HyperCallArgs};
+        self.cpu_data.inc_stat(CpuStat::VmExitsHypercall);
         self.cpu_data.vcpu.advance_rip(VM_EXIT_LEN_HYPERCALL)?;
         let guest_regs = self.cpu_data.vcpu.regs();
-        let (code, arg0, arg1) = (guest_regs.rax, guest_regs.rdi, guest_regs.rsi);
-        HyperCall::new(self.cpu_data).hypercall(code as _, arg0, arg1)?;
+        let args = HyperCallArgs {
+            code: guest_regs.rax as _,
+            arg0: guest_regs.rdi,
+            arg1: guest_regs.rsi,
+            arg2: guest_regs.rdx,
+            arg3: guest_regs.r10,
+            arg4: guest_regs.r8,
+        };
+        HyperCall::new(self.cpu_data).hypercall(&args)?;
         Ok(())
     }
 
     #[allow(dead_code)]
     fn test_read_guest_memory(&self, gvaddr: usize, size: usize) -> HvResult {
-        use crate::cell;
         use crate::memory::{addr::phys_to_virt, GenericPageTableImmut};
+        use x86_64::structures::idt::PageFaultErrorCode;
 
         let pt = self.cpu_data.vcpu.guest_page_table();
-        let (gpaddr, _, _) = pt.query(gvaddr)?;
-        let (hpaddr, _, _) = cell::root_cell().gpm.page_table().query(gpaddr)?;
+        let (gpaddr, _) = pt.walk(gvaddr, PageFaultErrorCode::empty())?;
+        let (hpaddr, _, _) = self.cpu_data.cell().gpm().page_table().query(gpaddr)?;
         println!(
             "GVA({:#x?}) -> GPA({:#x?}) -> HPA({:#x?}):",
             gvaddr, gpaddr, hpaddr
@@ -150,7 +568,13 @@ impl VmExit<'_> {
 }
 
 pub(super) fn vmexit_handler() {
+    let start = Instant::now();
+    #[cfg(feature = "trace")]
+    let trace_start = super::cpu::current_cycle();
     let mut vmexit = VmExit::new();
+    #[cfg(feature = "trace")]
+    let trace_rip = vmexit.cpu_data.vcpu.instr_pointer();
+    vmexit.cpu_data.inc_stat(CpuStat::VmExitsTotal);
     let res = vmexit.handle_exit();
     if let Err(err) = res {
         error!(
@@ -159,4 +583,24 @@ pub(super) fn vmexit_handler() {
         );
         vmexit.cpu_data.fault().unwrap();
     }
+    // Events may be left pending by the NMI handler.
+    vmexit.cpu_data.handle_events();
+    vmexit
+        .cpu_data
+        .exit_stats
+        .record(vmexit.kind, start.elapsed());
+    #[cfg(feature = "trace")]
+    {
+        let (reason, qualification) = vmexit.raw_exit_info();
+        vmexit.cpu_data.trace.record(TraceEntry {
+            tsc: trace_start,
+            rip: trace_rip,
+            qualification,
+            cycles: super::cpu::current_cycle() - trace_start,
+            reason,
+            kind: vmexit.kind as u32,
+        });
+    }
+    vmexit.cpu_data.wait_while_parked();
+    vmexit.cpu_data.fpu.restore_guest();
 }
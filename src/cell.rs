@@ -1,15 +1,36 @@
-use crate::arch::NestedPageTable;
+use alloc::string::String;
+use alloc::vec::Vec;
+use core::sync::atomic::AtomicBool;
+
+use spin::{Mutex, RwLock, RwLockReadGuard, RwLockWriteGuard};
+
+use crate::arch::{asid, vmm, NestedPageTable};
 use crate::config::{CellConfig, HvSystemConfig};
 use crate::error::HvResult;
+use crate::header::HvHeader;
 use crate::memory::addr::{GuestPhysAddr, HostPhysAddr};
-use crate::memory::{MemFlags, MemoryRegion, MemorySet};
+use crate::memory::{MemFlags, MemoryRegion, MemorySet, PAGE_SIZE};
+use crate::percpu::PerCpu;
+
+pub const ROOT_CELL_ID: u32 = 0;
+
+/// Maximum length of a line printed by `Cell::console_putc()`.
+const CONSOLE_LINE_MAXLEN: usize = 128;
 
 #[derive(Debug)]
 pub struct Cell<'a> {
+    /// Cell ID, 0 for the root cell.
+    pub id: u32,
+    /// VPID (Intel) or ASID (AMD) to tag TLB entries of this cell.
+    pub asid: u16,
     /// Cell configuration.
     pub config: CellConfig<'a>,
     /// Guest physical memory set.
-    pub gpm: MemorySet<NestedPageTable>,
+    gpm: RwLock<MemorySet<NestedPageTable>>,
+    /// Characters printed by `console_putc()` but not ended with a newline.
+    console_line: Mutex<Vec<u8>>,
+    /// Set after warning that HLT exiting requested by the config is not supported.
+    pub(crate) hlt_exiting_warned: AtomicBool,
 }
 
 impl Cell<'_> {
@@ -27,6 +48,9 @@ impl Cell<'_> {
             hv_phys_size,
             MemFlags::READ | MemFlags::NO_HUGEPAGES,
         ))?;
+        // Expose the console page (read-only) to `jailhouse console`.
+        let console_paddr = hv_phys_start + HvHeader::get().console_page;
+        gpm.remap(console_paddr, PAGE_SIZE, console_paddr)?;
         // Map all physical memory regions.
         for region in cell_config.mem_regions() {
             gpm.insert(MemoryRegion::new_with_offset_mapper(
@@ -39,16 +63,101 @@ impl Cell<'_> {
         trace!("Guest phyiscal memory set: {:#x?}", gpm);
 
         Ok(Self {
+            id: ROOT_CELL_ID,
+            asid: asid::alloc(ROOT_CELL_ID)?,
             config: cell_config,
-            gpm,
+            gpm: RwLock::new(gpm),
+            console_line: Mutex::new(Vec::new()),
+            hlt_exiting_warned: AtomicBool::new(false),
+        })
+    }
+
+    /// Lock the guest physical memory set for reading.
+    pub fn gpm(&self) -> RwLockReadGuard<MemorySet<NestedPageTable>> {
+        lock_handling_events(|| self.gpm.try_read())
+    }
+
+    /// Lock the guest physical memory set for writing.
+    pub fn gpm_mut(&self) -> RwLockWriteGuard<MemorySet<NestedPageTable>> {
+        lock_handling_events(|| self.gpm.try_write())
+    }
+
+    /// Print a character from this cell to the hypervisor console (the UART and the console
+    /// page). Characters are printed line by line, prefixed with the cell name.
+    pub fn console_putc(&self, c: u8) {
+        let mut line = self.console_line.lock();
+        if c != b'\n' {
+            line.push(c);
+        }
+        if c == b'\n' || line.len() >= CONSOLE_LINE_MAXLEN {
+            crate::logging::print(format_args!(
+                "[{}] {}\n",
+                self.config.name(),
+                String::from_utf8_lossy(&line)
+            ));
+            line.clear();
+        }
+    }
+}
+
+#[allow(dead_code)] // Dirty logging is not used by any hypercall yet.
+impl Cell<'_> {
+    /// Start logging writes of this cell to the guest physical memory `[start, start + size)`.
+    pub fn start_dirty_log(&self, start: GuestPhysAddr, size: usize) -> HvResult {
+        let mut gpm = self.gpm_mut();
+        vmm::take_hw_dirty_log(); // Discard stale pages.
+        vmm::set_hw_dirty_log(true);
+        gpm.start_dirty_log(start, size).map_err(|e| {
+            vmm::set_hw_dirty_log(false);
+            e
         })
     }
+
+    /// Returns a bitmap of guest physical pages written since the last call (or
+    /// `start_dirty_log()`), and clear it. See `MemorySet::fetch_dirty_log()`.
+    pub fn fetch_dirty_log(&self) -> HvResult<Vec<u64>> {
+        let mut gpm = self.gpm_mut();
+        let pages = vmm::take_hw_dirty_log();
+        gpm.fetch_dirty_log(pages.as_deref())
+    }
+
+    /// Stop logging writes of this cell.
+    pub fn stop_dirty_log(&self) -> HvResult {
+        let mut gpm = self.gpm_mut();
+        vmm::set_hw_dirty_log(false);
+        gpm.stop_dirty_log()
+    }
+}
+
+/// The lock holder may be waiting for us to handle events (e.g. TLB shootdowns), while NMIs may
+/// be blocked in the hypervisor (e.g. GIF is cleared on AMD), so handle events while spinning.
+fn lock_handling_events<T>(try_lock: impl Fn() -> Option<T>) -> T {
+    loop {
+        if let Some(guard) = try_lock() {
+            return guard;
+        }
+        PerCpu::current_mut().handle_events();
+        core::hint::spin_loop();
+    }
+}
+
+impl Drop for Cell<'_> {
+    fn drop(&mut self) {
+        asid::free(self.id);
+    }
 }
 
-static ROOT_CELL: spin::Once<Cell> = spin::Once::new();
+/// Set by the primary CPU in `init()` and cleared by the last CPU leaving the hypervisor in
+/// `shutdown()`, while no other CPUs access it.
+static mut ROOT_CELL: Option<Cell> = None;
 
 pub fn root_cell<'a>() -> &'a Cell<'a> {
-    ROOT_CELL.get().expect("Uninitialized root cell!")
+    unsafe { ROOT_CELL.as_ref() }.expect("Uninitialized root cell!")
+}
+
+/// Returns the number of cells, only the root cell is supported now.
+pub fn num_cells() -> usize {
+    unsafe { ROOT_CELL.as_ref() }.map_or(0, |_| 1)
 }
 
 pub fn init() -> HvResult {
@@ -58,6 +167,11 @@ pub fn init() -> HvResult {
     info!("Root cell init end.");
     debug!("{:#x?}", root_cell);
 
-    ROOT_CELL.call_once(|| root_cell);
+    unsafe { ROOT_CELL = Some(root_cell) };
     Ok(())
 }
+
+/// Destroy all cells.
+pub fn shutdown() {
+    unsafe { ROOT_CELL = None };
+}
@@ -1,15 +1,45 @@
 use core::fmt::{Debug, Formatter, Result};
 use core::{mem::size_of, slice};
 
+use bitflags::bitflags;
+
 use crate::error::HvResult;
 use crate::memory::MemFlags;
 
 const CONFIG_SIGNATURE: [u8; 6] = *b"RVMSYS";
-const CONFIG_REVISION: u16 = 10;
+const CONFIG_REVISION: u16 = 11;
 
 const HV_CELL_NAME_MAXLEN: usize = 31;
 const HV_MAX_IOMMU_UNITS: usize = 8;
 
+/// `pci_device_type` of virtual shared memory devices, the same as Jailhouse.
+const PCI_TYPE_IVSHMEM: u8 = 3;
+
+bitflags! {
+    /// Flags of a cell, the same as Jailhouse in the low 16 bits.
+    pub struct CellFlags: u32 {
+        const PASSIVE_COMMREG = 1 << 0;
+        const TEST_DEVICE = 1 << 1;
+        const VIRTUAL_CONSOLE_ACTIVE = 1 << 2;
+        /// The cell is allowed to print to the hypervisor console by `DebugConsolePutc`.
+        const VIRTUAL_CONSOLE_PERMITTED = 1 << 3;
+        /// Trap HLT and MWAIT, CPUs of the cell idle in the hypervisor until an interrupt or
+        /// event arrives (RVM specific).
+        const HLT_EXITING = 1 << 16;
+        /// Trap PAUSE loops, which usually mean spinning on a lock held by a CPU that is not
+        /// running (RVM specific).
+        const PAUSE_LOOP_EXITING = 1 << 17;
+    }
+}
+
+bitflags! {
+    /// Flags of a CPUID override entry.
+    pub struct CpuidEntryFlags: u32 {
+        /// The entry only applies to the subleaf `index`, otherwise to all subleaves.
+        const SUBLEAF = 1 << 0;
+    }
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 struct HvConsole {
@@ -43,6 +73,7 @@ pub struct HvCellDesc {
     pub pio_bitmap_size: u32,
     pub num_pci_devices: u32,
     pub num_pci_caps: u32,
+    pub num_cpuid_entries: u32,
 
     vpci_irq_base: u32,
 
@@ -111,6 +142,19 @@ pub struct HvPciCapability {
     flags: u16,
 }
 
+/// Override of the CPUID results seen by a cell: for EAX, EBX, ECX and EDX in order, bits in
+/// `clear` are cleared first, then bits in `set` are set.
+#[derive(Debug)]
+#[repr(C, packed)]
+pub struct HvCpuidEntry {
+    function: u32,
+    index: u32,
+    flags: u32,
+    _padding: u32,
+    clear: [u32; 4],
+    set: [u32; 4],
+}
+
 #[derive(Debug)]
 #[repr(C, packed)]
 struct HvIommu {
@@ -172,6 +216,7 @@ struct CellConfigLayout {
     pio_bitmap: [u8; 0],
     pci_devices: [HvPciDevice; 0],
     pci_caps: [HvPciCapability; 0],
+    cpuid_entries: [HvCpuidEntry; 0],
 }
 
 pub struct CellConfig<'a> {
@@ -191,6 +236,40 @@ impl HvCellDesc {
             + self.pio_bitmap_size as usize
             + self.num_pci_devices as usize * size_of::<HvPciDevice>()
             + self.num_pci_caps as usize * size_of::<HvPciCapability>()
+            + self.num_cpuid_entries as usize * size_of::<HvCpuidEntry>()
+    }
+}
+
+impl HvPciDevice {
+    /// Whether this is a virtual shared memory (ivshmem) device.
+    pub fn is_ivshmem(&self) -> bool {
+        self.pci_device_type == PCI_TYPE_IVSHMEM
+    }
+
+    pub fn num_msix_vectors(&self) -> u16 {
+        self.num_msix_vectors
+    }
+
+    /// Guest physical address of the MSI-X table.
+    pub fn msix_address(&self) -> u64 {
+        self.msix_address
+    }
+}
+
+impl HvCpuidEntry {
+    /// Whether the entry applies to CPUID leaf `function` and subleaf `index`.
+    pub fn matches(&self, function: u32, index: u32) -> bool {
+        let flags = CpuidEntryFlags::from_bits_truncate(self.flags);
+        self.function == function
+            && (!flags.contains(CpuidEntryFlags::SUBLEAF) || self.index == index)
+    }
+
+    /// Apply the override to the CPUID results `regs` (EAX, EBX, ECX and EDX).
+    pub fn apply(&self, regs: &mut [u32; 4]) {
+        let (clear, set) = (self.clear, self.set);
+        for (i, reg) in regs.iter_mut().enumerate() {
+            *reg = (*reg & !clear[i]) | set[i];
+        }
     }
 }
 
@@ -212,6 +291,16 @@ impl HvSystemConfig {
         }
         Ok(())
     }
+
+    /// The initial log level in bits 8..12 of `flags`: 0 keeps the build-time level, otherwise
+    /// the level number plus one (see `logging::level_filter`). Set by
+    /// `JAILHOUSE_SYS_LOG_LEVEL()` of `scripts/guest/jailhouse.patch` in system configs.
+    pub fn log_level(&self) -> Option<u64> {
+        match (self.flags >> 8) & 0xf {
+            0 => None,
+            n => Some(n as u64 - 1),
+        }
+    }
 }
 
 impl<'a> CellConfig<'a> {
@@ -227,6 +316,16 @@ impl<'a> CellConfig<'a> {
         self.desc.config_size()
     }
 
+    pub fn name(&self) -> &str {
+        let name = &self.desc.name;
+        let len = name.iter().position(|&c| c == 0).unwrap_or(name.len());
+        core::str::from_utf8(&name[..len]).unwrap_or("<invalid>")
+    }
+
+    pub fn flags(&self) -> CellFlags {
+        CellFlags::from_bits_truncate(self.desc.flags)
+    }
+
     pub fn cpu_set(&self) -> &[u64] {
         // XXX: data may unaligned, which cause panic on debug mode. Same below.
         // See: https://doc.rust-lang.org/src/core/slice/mod.rs.html#6435-6443
@@ -239,19 +338,42 @@ impl<'a> CellConfig<'a> {
             slice::from_raw_parts(ptr, self.desc.num_memory_regions as usize)
         }
     }
+
+    /// Number of CPUs in the cell.
+    pub fn num_cpus(&self) -> u32 {
+        self.cpu_set().iter().map(|set| set.count_ones()).sum()
+    }
+
+    /// PCI devices, followed by PCI capabilities and CPUID overrides.
+    pub fn pci_devices(&self) -> &[HvPciDevice] {
+        let num = self.desc.num_pci_devices as usize;
+        let caps_size = self.desc.num_pci_caps as usize * size_of::<HvPciCapability>();
+        unsafe {
+            let end = self.cpuid_entries().as_ptr() as *const u8;
+            let ptr = end.sub(caps_size + num * size_of::<HvPciDevice>()) as _;
+            slice::from_raw_parts(ptr, num)
+        }
+    }
+
+    /// CPUID overrides, at the end of the cell configuration.
+    pub fn cpuid_entries(&self) -> &[HvCpuidEntry] {
+        let num = self.desc.num_cpuid_entries as usize;
+        unsafe {
+            let end = self.config_ptr::<u8>().add(self.size());
+            let ptr = end.sub(num * size_of::<HvCpuidEntry>()) as _;
+            slice::from_raw_parts(ptr, num)
+        }
+    }
 }
 
 impl Debug for CellConfig<'_> {
     fn fmt(&self, f: &mut Formatter) -> Result {
-        let name = self.desc.name;
-        let mut len = 0;
-        while name[len] != 0 {
-            len += 1;
-        }
         f.debug_struct("CellConfig")
-            .field("name", &core::str::from_utf8(&name[..len]))
+            .field("name", &self.name())
+            .field("flags", &self.flags())
             .field("size", &self.size())
             .field("mem_regions", &self.mem_regions())
+            .field("cpuid_entries", &self.cpuid_entries())
             .finish()
     }
 }
@@ -0,0 +1,61 @@
+//! In-memory console of the hypervisor, read by `jailhouse console` in the root cell.
+
+use core::fmt::{Arguments, Result, Write};
+use core::sync::atomic::{fence, Ordering};
+
+use spin::Mutex;
+
+const CONSOLE_CONTENT_SIZE: usize = 2048;
+
+/// The same layout as `struct jailhouse_console` in Jailhouse.
+///
+/// Readers retry if `lock` is set or `tail` changed while copying `content`.
+#[repr(C, align(4096))]
+struct ConsolePage {
+    lock: u32,
+    tail: u32,
+    /// A ring buffer, the next byte is written to `content[tail % CONSOLE_CONTENT_SIZE]`.
+    content: [u8; CONSOLE_CONTENT_SIZE],
+}
+
+/// Referenced by `HvHeader::console_page` via `__console_offset` in the linker script.
+#[used]
+#[link_section = ".console"]
+static mut CONSOLE_PAGE: ConsolePage = ConsolePage {
+    lock: 0,
+    tail: 0,
+    content: [0; CONSOLE_CONTENT_SIZE],
+};
+
+/// Serializes writers of `CONSOLE_PAGE`.
+static CONSOLE_WRITER: Mutex<ConsoleWriter> = Mutex::new(ConsoleWriter);
+
+struct ConsoleWriter;
+
+impl Write for ConsoleWriter {
+    fn write_str(&mut self, s: &str) -> Result {
+        unsafe {
+            for &byte in s.as_bytes() {
+                let tail = core::ptr::read_volatile(&CONSOLE_PAGE.tail);
+                core::ptr::write_volatile(
+                    &mut CONSOLE_PAGE.content[tail as usize % CONSOLE_CONTENT_SIZE],
+                    byte,
+                );
+                core::ptr::write_volatile(&mut CONSOLE_PAGE.tail, tail.wrapping_add(1));
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Append formatted output to the console page.
+pub fn putfmt(fmt: Arguments) {
+    let mut writer = CONSOLE_WRITER.lock();
+    unsafe {
+        core::ptr::write_volatile(&mut CONSOLE_PAGE.lock, 1);
+        fence(Ordering::SeqCst);
+        writer.write_fmt(fmt).ok();
+        fence(Ordering::SeqCst);
+        core::ptr::write_volatile(&mut CONSOLE_PAGE.lock, 0);
+    }
+}
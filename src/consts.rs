@@ -11,6 +11,9 @@ pub const HV_HEAP_SIZE: usize = 32 * 1024 * 1024; // 32 MB
 /// Size of the per-CPU data (stack and other CPU-local data).
 pub const PER_CPU_SIZE: usize = 512 * 1024; // 512 KB
 
+/// Size of the crash dump at the end of the hypervisor memory.
+pub const CRASH_DUMP_SIZE: usize = 64 * 1024; // 64 KB
+
 /// Start virtual address of the hypervisor memory.
 pub const HV_BASE: usize = 0xffff_ff00_0000_0000;
 
@@ -35,6 +38,11 @@ pub fn hv_end() -> VirtAddr {
     HV_BASE + HvSystemConfig::get().hypervisor_memory.size as usize
 }
 
+/// Start virtual address of the crash dump, which is excluded from the free memory pool.
+pub fn crash_dump_start() -> VirtAddr {
+    hv_end() - CRASH_DUMP_SIZE
+}
+
 extern "C" {
     fn __header_start();
     fn __core_end();
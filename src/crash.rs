@@ -0,0 +1,192 @@
+//! Crash dump of all CPUs on hypervisor panic.
+//!
+//! The dump is written to the last `CRASH_DUMP_SIZE` bytes of the hypervisor memory, which is
+//! reserved by Linux and survives a warm reboot. It can be read from `/dev/mem` before the
+//! hypervisor is enabled again (the driver clears the hypervisor memory on enabling).
+
+use core::fmt::{self, Write};
+use core::mem::size_of;
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
+
+use crate::arch::vmm::VcpuAccessGuestState;
+use crate::arch::{cpu, GeneralRegisters};
+use crate::consts::{crash_dump_start, CRASH_DUMP_SIZE};
+use crate::memory::addr::virt_to_phys;
+use crate::percpu::{CpuEvents, CpuStat, PerCpu, NUM_CPU_STATS};
+
+const CRASH_MAGIC: [u8; 8] = *b"RVMCRASH";
+const CRASH_VERSION: u32 = 2;
+const CRASH_MESSAGE_MAXLEN: usize = 2048;
+
+/// Time to wait for other CPUs to capture their states.
+const CAPTURE_TIMEOUT_NS: u64 = 1_000_000_000;
+
+/// Placed at the beginning of the crash dump, followed by `max_cpus` entries of `CpuDump`.
+#[repr(C)]
+struct CrashDumpHeader {
+    magic: [u8; 8],
+    version: u32,
+    panic_cpu: u32,
+    max_cpus: u32,
+    message_len: u32,
+    /// TSC at the time of the panic.
+    tsc: u64,
+    message: [u8; CRASH_MESSAGE_MAXLEN],
+}
+
+#[repr(C)]
+struct CpuDump {
+    /// Set to 1 after the state of this CPU is captured.
+    captured: u32,
+    /// Whether the CPU was running the hypervisor (guest state is valid).
+    hv_enabled: u32,
+    /// Where the hypervisor was interrupted by the NMI, 0 for the panicking CPU.
+    host_rip: u64,
+    host_rsp: u64,
+    guest_regs: GeneralRegisters,
+    guest_rip: u64,
+    guest_rsp: u64,
+    guest_rflags: u64,
+    guest_cr0: u64,
+    guest_cr3: u64,
+    guest_cr4: u64,
+    guest_efer: u64,
+    /// Counters indexed by `CpuStat`.
+    stats: [u32; NUM_CPU_STATS],
+}
+
+const MAX_DUMP_CPUS: usize =
+    (CRASH_DUMP_SIZE - size_of::<CrashDumpHeader>()) / size_of::<CpuDump>();
+
+/// Set after the memory of the crash dump is reserved.
+static CRASH_DUMP_READY: AtomicBool = AtomicBool::new(false);
+static CRASH_IN_PROGRESS: AtomicBool = AtomicBool::new(false);
+static CAPTURED_CPUS: AtomicU32 = AtomicU32::new(0);
+
+/// Writes formatted output to a fixed buffer, truncating on overflow.
+struct BufWriter<'a> {
+    buf: &'a mut [u8],
+    len: usize,
+}
+
+impl Write for BufWriter<'_> {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        let n = s.len().min(self.buf.len() - self.len);
+        self.buf[self.len..self.len + n].copy_from_slice(&s.as_bytes()[..n]);
+        self.len += n;
+        Ok(())
+    }
+}
+
+fn dump_header<'a>() -> &'a mut CrashDumpHeader {
+    unsafe { &mut *(crash_dump_start() as *mut CrashDumpHeader) }
+}
+
+fn cpu_dump<'a>(cpu_id: u32) -> Option<&'a mut CpuDump> {
+    if cpu_id as usize >= MAX_DUMP_CPUS {
+        return None;
+    }
+    let base = crash_dump_start() + size_of::<CrashDumpHeader>();
+    unsafe { Some(&mut *((base as *mut CpuDump).add(cpu_id as usize))) }
+}
+
+pub fn init() {
+    CRASH_IN_PROGRESS.store(false, Ordering::Release);
+    CAPTURED_CPUS.store(0, Ordering::Release);
+    CRASH_DUMP_READY.store(true, Ordering::Release);
+}
+
+/// Returns whether a CPU has panicked and a crash dump is being taken.
+pub fn in_progress() -> bool {
+    CRASH_IN_PROGRESS.load(Ordering::Acquire)
+}
+
+/// Record the state of the current CPU, which was interrupted at `host_rip` and `host_rsp`.
+///
+/// May be called in the NMI handler, so it takes no locks and does not allocate.
+pub fn capture_cpu(cpu_data: &PerCpu, host_rip: u64, host_rsp: u64) {
+    let dump = match cpu_dump(cpu_data.id) {
+        Some(dump) => dump,
+        None => return,
+    };
+    if dump.captured != 0 {
+        return;
+    }
+    dump.hv_enabled = cpu_data.is_hv_enabled() as u32;
+    dump.host_rip = host_rip;
+    dump.host_rsp = host_rsp;
+    if cpu_data.is_hv_enabled() {
+        let vcpu = &cpu_data.vcpu;
+        dump.guest_regs = vcpu.regs().clone();
+        dump.guest_rip = vcpu.instr_pointer();
+        dump.guest_rsp = vcpu.stack_pointer();
+        dump.guest_rflags = vcpu.rflags();
+        dump.guest_cr0 = vcpu.cr(0);
+        dump.guest_cr3 = vcpu.cr(3);
+        dump.guest_cr4 = vcpu.cr(4);
+        dump.guest_efer = vcpu.efer();
+    }
+    for (i, stat) in dump.stats.iter_mut().enumerate() {
+        *stat = CpuStat::try_from(i as u32).map_or(0, |s| cpu_data.stat(s));
+    }
+    unsafe { core::ptr::write_volatile(&mut dump.captured, 1) };
+    CAPTURED_CPUS.fetch_add(1, Ordering::AcqRel);
+}
+
+/// Write the crash dump of all CPUs, called by the panic handler on `cpu_data`.
+///
+/// Other CPUs are notified by NMIs to capture their own states, and will return to Linux
+/// before the next VM entry. If another CPU is already taking the dump, only the state of the
+/// current CPU is captured.
+pub fn dump(cpu_data: &PerCpu, msg: fmt::Arguments) {
+    if !CRASH_DUMP_READY.load(Ordering::Acquire) {
+        return;
+    }
+    if CRASH_IN_PROGRESS.swap(true, Ordering::AcqRel) {
+        capture_cpu(cpu_data, 0, 0);
+        return;
+    }
+
+    let max_cpus = (crate::header::HvHeader::get().max_cpus as usize).min(MAX_DUMP_CPUS);
+    unsafe {
+        core::ptr::write_bytes(
+            crash_dump_start() as *mut u8,
+            0,
+            size_of::<CrashDumpHeader>() + max_cpus * size_of::<CpuDump>(),
+        );
+    }
+    let header = dump_header();
+    header.version = CRASH_VERSION;
+    header.panic_cpu = cpu_data.id;
+    header.max_cpus = max_cpus as u32;
+    header.tsc = cpu::current_cycle();
+    let mut writer = BufWriter {
+        buf: &mut header.message,
+        len: 0,
+    };
+    writer.write_fmt(msg).ok();
+    header.message_len = writer.len as u32;
+
+    capture_cpu(cpu_data, 0, 0);
+    let targets = PerCpu::entered().filter(|c| c.id != cpu_data.id && c.is_hv_enabled());
+    let mut num_targets = 0;
+    for target in targets {
+        target.send_events(CpuEvents::CRASH_DUMP);
+        num_targets += 1;
+    }
+    let deadline = cpu::current_time_nanos() + CAPTURE_TIMEOUT_NS;
+    while CAPTURED_CPUS.load(Ordering::Acquire) < num_targets + 1
+        && cpu::current_time_nanos() < deadline
+    {
+        core::hint::spin_loop();
+    }
+
+    // Mark the dump valid at last, after all captured states are written.
+    unsafe { core::ptr::write_volatile(&mut header.magic, CRASH_MAGIC) };
+    error!(
+        "Crash dump of {}/{} CPUs written to physical address {:#x}",
+        CAPTURED_CPUS.load(Ordering::Acquire),
+        num_targets + 1,
+        virt_to_phys(crash_dump_start()),
+    );
+}
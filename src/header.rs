@@ -31,7 +31,7 @@ struct HvHeaderStuff {
     core_size: unsafe extern "C" fn(),
     percpu_size: usize,
     entry: unsafe extern "C" fn(),
-    console_page: usize,
+    console_page: unsafe extern "C" fn(),
     gcov_info_head: usize,
     max_cpus: u32,
     online_cpus: u32,
@@ -43,6 +43,7 @@ struct HvHeaderStuff {
 extern "C" {
     fn __entry_offset();
     fn __core_size();
+    fn __console_offset();
 }
 
 #[used]
@@ -52,7 +53,7 @@ static HEADER_STUFF: HvHeaderStuff = HvHeaderStuff {
     core_size: __core_size,
     percpu_size: PER_CPU_SIZE,
     entry: __entry_offset,
-    console_page: 0,
+    console_page: __console_offset,
     gcov_info_head: 0,
     max_cpus: 0,
     online_cpus: 0,
@@ -68,6 +69,7 @@ impl Debug for HvHeader {
             .field("core_size", &self.core_size)
             .field("percpu_size", &self.percpu_size)
             .field("entry", &self.entry)
+            .field("console_page", &self.console_page)
             .field("max_cpus", &self.max_cpus)
             .field("online_cpus", &self.online_cpus)
             .finish()
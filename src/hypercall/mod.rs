@@ -2,47 +2,259 @@ use core::convert::TryFrom;
 use core::sync::atomic::{AtomicU32, Ordering};
 
 use bit_field::BitField;
+use bitflags::bitflags;
 use numeric_enum_macro::numeric_enum;
 
 use crate::arch::vmm::VcpuAccessGuestState;
-use crate::arch::GuestPageTableImmut;
+use crate::arch::GuestPageTable;
+use crate::cell::ROOT_CELL_ID;
+use crate::config::CellFlags;
 use crate::error::HvResult;
-use crate::percpu::PerCpu;
+use crate::logging;
+use crate::memory::gaccess::{check_gpaddr, AsGuestPtr};
+use crate::memory::MemFlags;
+use crate::percpu::{CpuEvents, CpuStat, PerCpu};
+#[cfg(feature = "trace")]
+use crate::trace::TraceBuffer;
 
 numeric_enum! {
     #[repr(u32)]
+    /// Hypercall codes. Codes in class 0 (bits 30..32) can only be called at CPL 0, codes in
+    /// other classes only at CPL > 0.
     #[derive(Debug, Eq, PartialEq, Copy, Clone)]
     pub enum HyperCallCode {
         HypervisorDisable = 0,
+        HypervisorGetInfo = 5,
+        CpuGetInfo = 7,
+        DebugConsolePutc = 8,
+        HypervisorSetLogLevel = 16,
+        HypervisorDumpStats = 17,
+        HypervisorGetTrace = 18,
+        HypervisorGetAbi = 19,
+        // Class 1: safe calls for user mode applications.
+        UserGetVersion = 0x4000_0000,
+        UserGetCellId = 0x4000_0001,
+        UserIvshmemDoorbell = 0x4000_0002,
     }
 }
 
+numeric_enum! {
+    #[repr(u64)]
+    /// Information types of `HypervisorGetInfo`.
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum HvInfoType {
+        MemPoolSize = 0,
+        MemPoolUsed = 1,
+        RemapPoolSize = 2,
+        RemapPoolUsed = 3,
+        NumCells = 4,
+    }
+}
+
+numeric_enum! {
+    #[repr(u64)]
+    /// Information types of `HypervisorGetAbi`.
+    #[derive(Debug, Eq, PartialEq, Copy, Clone)]
+    pub enum AbiInfoType {
+        /// Version of the paravirtual ABI, see `PV_ABI_VERSION`. Since ABI 1.1, also the
+        /// masks of supported hypercalls in classes 0 and 1.
+        Version = 0,
+        /// Mask of supported hypercall codes in a class: bit `n` is set if code
+        /// `class << 30 | n` is supported.
+        CallMask = 1,
+    }
+}
+
+bitflags! {
+    /// Paravirtual features advertised to a cell in EAX of CPUID leaf 0x4000_0001.
+    pub struct PvFeatures: u32 {
+        /// `HypervisorGetAbi` is supported, all other hypercalls can be discovered by it.
+        const ABI_INFO = 1 << 0;
+        /// The cell is permitted to print by `DebugConsolePutc`.
+        const DEBUG_CONSOLE = 1 << 1;
+        /// HLT idles in the hypervisor, with lower wake-up latency than spinning in the cell.
+        const HLT_IDLE = 1 << 2;
+    }
+}
+
+/// Version of the paravirtual ABI: the major version in bits 16..32 changes on incompatible
+/// changes, the minor version in bits 0..16 on compatible additions.
+const PV_ABI_VERSION: u64 = 1 << 16 | 3;
+
+/// Code and arguments of a hypercall, passed in RAX, and RDI, RSI, RDX, R10 and R8.
+#[derive(Debug, Clone, Copy)]
+pub struct HyperCallArgs {
+    pub code: u32,
+    pub arg0: u64,
+    pub arg1: u64,
+    pub arg2: u64,
+    pub arg3: u64,
+    pub arg4: u64,
+}
+
+/// Values returned by a successful hypercall, in RAX, and RDI, RSI and RDX. Registers of
+/// values not returned are preserved.
+#[derive(Debug, Clone, Copy)]
+pub struct HyperCallRet {
+    vals: [u64; 4],
+    len: usize,
+}
+
+impl HyperCallRet {
+    /// Return at most 4 values.
+    pub fn new(vals: &[u64]) -> Self {
+        let mut ret = Self {
+            vals: [0; 4],
+            len: vals.len(),
+        };
+        ret.vals[..vals.len()].copy_from_slice(vals);
+        ret
+    }
+
+    pub fn values(&self) -> &[u64] {
+        &self.vals[..self.len]
+    }
+}
+
+impl From<usize> for HyperCallRet {
+    fn from(val: usize) -> Self {
+        Self::new(&[val as u64])
+    }
+}
+
+type HyperCallHandler = fn(&mut HyperCall, &HyperCallArgs) -> HyperCallResult;
+
+/// Handlers of hypercalls, which also pick their arguments. New hypercalls are registered here.
+const HYPERCALL_HANDLERS: &[(HyperCallCode, HyperCallHandler)] = &[
+    (HyperCallCode::HypervisorDisable, |hc, _| {
+        hc.hypervisor_disable()
+    }),
+    (HyperCallCode::HypervisorGetInfo, |hc, args| {
+        hc.hypervisor_get_info(args.arg0)
+    }),
+    (HyperCallCode::CpuGetInfo, |hc, args| {
+        hc.cpu_get_info(args.arg0, args.arg1)
+    }),
+    (HyperCallCode::DebugConsolePutc, |hc, args| {
+        hc.debug_console_putc(args.arg0)
+    }),
+    (HyperCallCode::HypervisorSetLogLevel, |hc, args| {
+        hc.hypervisor_set_log_level(args.arg0, args.arg1)
+    }),
+    (HyperCallCode::HypervisorDumpStats, |hc, _| {
+        hc.hypervisor_dump_stats()
+    }),
+    (HyperCallCode::HypervisorGetTrace, |hc, args| {
+        hc.hypervisor_get_trace(args.arg0, args.arg1)
+    }),
+    (HyperCallCode::HypervisorGetAbi, |hc, args| {
+        hc.hypervisor_get_abi(args.arg0, args.arg1)
+    }),
+    (HyperCallCode::UserGetVersion, |hc, _| hc.user_get_version()),
+    (HyperCallCode::UserGetCellId, |hc, _| hc.user_get_cell_id()),
+    (HyperCallCode::UserIvshmemDoorbell, |hc, args| {
+        hc.user_ivshmem_doorbell(args.arg0, args.arg1)
+    }),
+];
+
+/// Information types of `CpuGetInfo`, statistics start from `CPU_INFO_STAT_BASE`.
+const CPU_INFO_STATE: u64 = 0;
+const CPU_INFO_STAT_BASE: u64 = 1000;
+
+/// CPU states reported by `CpuGetInfo`.
+const CPU_STATE_RUNNING: usize = 0;
+const CPU_STATE_FAILED: usize = 2;
+
+/// Maximum length of module names passed to `HypervisorSetLogLevel`.
+const LOG_MODULE_MAXLEN: usize = 128;
+
+/// Size of an MSI-X table entry: message address (low and high), data and vector control.
+const MSIX_ENTRY_SIZE: usize = 16;
+
 impl HyperCallCode {
+    fn class(self) -> u32 {
+        (self as u32).get_bits(30..32)
+    }
+
     fn is_privileged(self) -> bool {
-        (self as u32).get_bits(30..32) == 0
+        self.class() == 0
+    }
+
+    /// Whether the hypercall is supported by this build.
+    #[allow(clippy::match_like_matches_macro)] // results depend on features
+    fn is_available(self) -> bool {
+        match self {
+            Self::HypervisorDumpStats => cfg!(feature = "stats"),
+            Self::HypervisorGetTrace => cfg!(feature = "trace"),
+            _ => true,
+        }
+    }
+
+    /// Mask of hypercalls in `class` supported by this build, see `AbiInfoType::CallMask`.
+    fn supported_mask(class: u32) -> u64 {
+        HYPERCALL_HANDLERS
+            .iter()
+            .map(|&(code, _)| code)
+            .filter(|&code| code.class() == class && code.is_available())
+            .fold(0, |mask, code| mask | 1 << (code as u32).get_bits(0..30))
     }
 }
 
-pub type HyperCallResult = HvResult<usize>;
+pub type HyperCallResult = HvResult<HyperCallRet>;
+
+/// Number of CPUs waiting in `HypervisorDisable`.
+static TRY_DISABLE_CPUS: AtomicU32 = AtomicU32::new(0);
+
+/// Reset the state of hypercalls when the hypervisor is disabled.
+pub fn reset() {
+    TRY_DISABLE_CPUS.store(0, Ordering::Release);
+}
+
+/// Whether some CPU has called `HypervisorDisable`.
+pub fn disabling() -> bool {
+    TRY_DISABLE_CPUS.load(Ordering::Acquire) > 0
+}
+
+/// Wait until all CPUs running the hypervisor are leaving, then return `cpu_data` to Linux.
+pub fn leave_hypervisor(cpu_data: &mut PerCpu, ret_code: Option<usize>) -> HvResult {
+    let cpus = PerCpu::activated_cpus();
+    if TRY_DISABLE_CPUS.fetch_add(1, Ordering::SeqCst) == 0 {
+        // Parked CPUs sleep until notified, they do not call `HypervisorDisable` themselves.
+        for target in PerCpu::entered().filter(|c| c.id != cpu_data.id) {
+            if target.is_hv_enabled() && target.is_parked() {
+                target.send_events(CpuEvents::WAKE_UP);
+            }
+        }
+    }
+    while TRY_DISABLE_CPUS.load(Ordering::Acquire) < cpus {
+        core::hint::spin_loop();
+    }
+    cpu_data.deactivate_vmm(ret_code)
+}
 
 pub struct HyperCall<'a> {
     cpu_data: &'a mut PerCpu,
-    _gpt: GuestPageTableImmut,
+    gpt: GuestPageTable,
 }
 
 impl<'a> HyperCall<'a> {
     pub fn new(cpu_data: &'a mut PerCpu) -> Self {
         Self {
-            _gpt: cpu_data.vcpu.guest_page_table(),
+            gpt: cpu_data.vcpu.guest_page_table(),
             cpu_data,
         }
     }
 
-    pub fn hypercall(&mut self, code: u32, arg0: u64, _arg1: u64) -> HvResult {
-        let code = match HyperCallCode::try_from(code) {
-            Ok(code) => code,
-            Err(_) => {
-                warn!("Hypercall not supported: {}", code);
+    pub fn hypercall(&mut self, args: &HyperCallArgs) -> HvResult {
+        let (code, handler) = match HYPERCALL_HANDLERS
+            .iter()
+            .find(|(code, _)| *code as u32 == args.code)
+        {
+            Some(&entry) => entry,
+            None => {
+                warn!("Hypercall not supported: {:#x}", args.code);
+                let ret = hv_err!(ENOSYS).code();
+                self.cpu_data.vcpu.set_return_val(ret as _);
                 return Ok(());
             }
         };
@@ -59,41 +271,206 @@ impl<'a> HyperCall<'a> {
             return Ok(());
         }
 
-        debug!("HyperCall: {:?} => arg0={:#x}", code, arg0);
-        let ret = match code {
-            HyperCallCode::HypervisorDisable => self.hypervisor_disable(),
-        };
+        debug!("HyperCall: {:?} => {:#x?}", code, args);
+        let ret = handler(self, args);
         if ret.is_err() {
             warn!("HyperCall: {:?} <= {:x?}", code, ret);
         } else {
             debug!("HyperCall: {:?} <= {:x?}", code, ret);
         }
 
-        if !code.is_privileged() {
-            if ret.is_err() {
-                self.cpu_data.fault()?;
-            }
-        } else {
-            let val = match ret {
-                Ok(ret) => ret,
-                Err(err) => err.code() as _,
-            };
-            self.cpu_data.vcpu.set_return_val(val);
+        match ret {
+            Ok(ret) => self.cpu_data.vcpu.set_return_vals(ret.values()),
+            Err(err) => self.cpu_data.vcpu.set_return_val(err.code() as _),
         }
 
         Ok(())
     }
 
     fn hypervisor_disable(&mut self) -> HyperCallResult {
-        let cpus = PerCpu::activated_cpus();
+        leave_hypervisor(self.cpu_data, Some(0))?;
+        unreachable!()
+    }
 
-        static TRY_DISABLE_CPUS: AtomicU32 = AtomicU32::new(0);
-        TRY_DISABLE_CPUS.fetch_add(1, Ordering::SeqCst);
-        while TRY_DISABLE_CPUS.load(Ordering::Acquire) < cpus {
-            core::hint::spin_loop();
+    fn hypervisor_get_info(&mut self, info_type: u64) -> HyperCallResult {
+        let (pool_size, pool_used) = crate::memory::mem_pool_stats();
+        match HvInfoType::try_from(info_type) {
+            Ok(HvInfoType::MemPoolSize) => Ok(pool_size.into()),
+            Ok(HvInfoType::MemPoolUsed) => Ok(pool_used.into()),
+            // Devices are mapped in the hypervisor page table directly, no remapping pool.
+            Ok(HvInfoType::RemapPoolSize) | Ok(HvInfoType::RemapPoolUsed) => Ok(0.into()),
+            Ok(HvInfoType::NumCells) => Ok(crate::cell::num_cells().into()),
+            Err(_) => hv_result_err!(EINVAL, format!("Invalid info type {}", info_type)),
         }
+    }
 
-        self.cpu_data.deactivate_vmm(0)?;
-        unreachable!()
+    fn cpu_get_info(&mut self, cpu_id: u64, info_type: u64) -> HyperCallResult {
+        if cpu_id >= PerCpu::entered_cpus() as u64 {
+            return hv_result_err!(EINVAL, format!("Invalid CPU ID {}", cpu_id));
+        }
+        let cpu_data = PerCpu::from_id(cpu_id as u32);
+        match info_type {
+            CPU_INFO_STATE => Ok(if cpu_data.is_hv_enabled() {
+                CPU_STATE_RUNNING.into()
+            } else {
+                CPU_STATE_FAILED.into()
+            }),
+            _ => info_type
+                .checked_sub(CPU_INFO_STAT_BASE)
+                .and_then(|idx| CpuStat::try_from(idx as u32).ok())
+                .map(|stat| (cpu_data.stat(stat) as usize).into())
+                .ok_or_else(|| hv_err!(EINVAL, format!("Invalid info type {}", info_type))),
+        }
+    }
+
+    fn debug_console_putc(&mut self, c: u64) -> HyperCallResult {
+        // Gated by the cell flags as Jailhouse does: the flags of `HvCellDesc::console` only
+        // describe how the cell accesses its own UART (PIO or MMIO, register distance, ...),
+        // none of them grants access to the hypervisor console.
+        let cell = self.cpu_data.cell();
+        if !cell
+            .config
+            .flags()
+            .contains(CellFlags::VIRTUAL_CONSOLE_PERMITTED)
+        {
+            return hv_result_err!(EPERM, "Cell is not permitted to use the debug console");
+        }
+        cell.console_putc(c as u8);
+        Ok(0.into())
+    }
+
+    /// Set the log level of the module whose name is a C string at `module_ptr`, or the global
+    /// level if `module_ptr` is 0. Only the root cell can change log levels.
+    fn hypervisor_set_log_level(&mut self, level: u64, module_ptr: u64) -> HyperCallResult {
+        if self.cpu_data.cell().id != ROOT_CELL_ID {
+            return hv_result_err!(EPERM, "Only the root cell can set log levels");
+        }
+        let level = logging::level_filter(level)
+            .ok_or_else(|| hv_err!(EINVAL, format!("Invalid log level {}", level)))?;
+        if module_ptr == 0 {
+            logging::set_level(None, level);
+        } else {
+            let module = module_ptr
+                .as_guest_ptr::<u8>(&self.gpt)
+                .read_cstr(LOG_MODULE_MAXLEN)?;
+            logging::set_level(Some(&module), level);
+        }
+        Ok(0.into())
+    }
+
+    /// Print VM exit statistics of all CPUs to the hypervisor console.
+    fn hypervisor_dump_stats(&mut self) -> HyperCallResult {
+        if !cfg!(feature = "stats") {
+            return hv_result_err!(ENOSYS, "Built without the stats feature");
+        }
+        for cpu_data in PerCpu::entered() {
+            cpu_data.exit_stats.dump(cpu_data.id);
+        }
+        Ok(0.into())
+    }
+
+    /// Copy the VM exit trace of CPU `cpu_id` to the guest buffer at `buf_ptr`, which must be
+    /// at least as large as the size returned when `buf_ptr` is 0. Returns the copied size.
+    #[cfg(feature = "trace")]
+    fn hypervisor_get_trace(&mut self, cpu_id: u64, buf_ptr: u64) -> HyperCallResult {
+        if self.cpu_data.cell().id != ROOT_CELL_ID {
+            return hv_result_err!(EPERM, "Only the root cell can read traces");
+        }
+        if buf_ptr == 0 {
+            return Ok(TraceBuffer::MAX_SNAPSHOT_SIZE.into());
+        }
+        if cpu_id >= PerCpu::entered_cpus() as u64 {
+            return hv_result_err!(EINVAL, format!("Invalid CPU ID {}", cpu_id));
+        }
+        let data = PerCpu::from_id(cpu_id as u32).trace.snapshot(cpu_id as u32);
+        buf_ptr.as_guest_ptr::<u8>(&self.gpt).copy_to_guest(&data)?;
+        Ok(data.len().into())
+    }
+
+    #[cfg(not(feature = "trace"))]
+    fn hypervisor_get_trace(&mut self, _cpu_id: u64, _buf_ptr: u64) -> HyperCallResult {
+        hv_result_err!(ENOSYS, "Built without the trace feature")
+    }
+
+    /// Returns the paravirtual ABI version and the masks of supported hypercalls in classes 0
+    /// and 1, or the mask of supported hypercalls in `class`.
+    fn hypervisor_get_abi(&mut self, info_type: u64, class: u64) -> HyperCallResult {
+        match AbiInfoType::try_from(info_type) {
+            Ok(AbiInfoType::Version) => Ok(HyperCallRet::new(&[
+                PV_ABI_VERSION,
+                HyperCallCode::supported_mask(0),
+                HyperCallCode::supported_mask(1),
+            ])),
+            Ok(AbiInfoType::CallMask) => {
+                if class >= 4 {
+                    return hv_result_err!(EINVAL, format!("Invalid hypercall class {}", class));
+                }
+                Ok(HyperCallRet::new(&[HyperCallCode::supported_mask(
+                    class as u32,
+                )]))
+            }
+            Err(_) => hv_result_err!(EINVAL, format!("Invalid info type {}", info_type)),
+        }
+    }
+
+    /// Returns the paravirtual ABI version, also telling user mode applications that they are
+    /// running in RVM.
+    fn user_get_version(&mut self) -> HyperCallResult {
+        Ok(HyperCallRet::new(&[PV_ABI_VERSION]))
+    }
+
+    /// Returns the ID of the cell of the caller.
+    fn user_get_cell_id(&mut self) -> HyperCallResult {
+        Ok((self.cpu_data.cell().id as usize).into())
+    }
+
+    /// Ring the doorbell of the `device`-th ivshmem device of the cell, by sending the MSI
+    /// programmed by the cell in entry `vector` of the device's MSI-X table. Only fixed
+    /// interrupts to one CPU of the cell are supported, and masked vectors are dropped.
+    fn user_ivshmem_doorbell(&mut self, device: u64, vector: u64) -> HyperCallResult {
+        let cell = self.cpu_data.cell();
+        let dev = cell
+            .config
+            .pci_devices()
+            .iter()
+            .filter(|dev| dev.is_ivshmem())
+            .nth(device as usize)
+            .ok_or_else(|| hv_err!(EINVAL, format!("Invalid ivshmem device {}", device)))?;
+        if vector >= dev.num_msix_vectors() as u64 {
+            return hv_result_err!(EINVAL, format!("Invalid MSI-X vector {}", vector));
+        }
+
+        let entry_gpaddr = dev.msix_address() as usize + vector as usize * MSIX_ENTRY_SIZE;
+        if entry_gpaddr % MSIX_ENTRY_SIZE != 0 {
+            return hv_result_err!(EINVAL, "MSI-X table of the ivshmem device is not aligned");
+        }
+        let entry = check_gpaddr(entry_gpaddr, MemFlags::READ)?;
+        let [addr, _, data, ctrl] = unsafe { core::ptr::read_volatile(entry as *const [u32; 4]) };
+        if ctrl.get_bit(0) {
+            return Ok(0.into());
+        }
+        // Physical destination mode and fixed delivery mode only.
+        let apic_id = addr.get_bits(12..20);
+        let irq = data.get_bits(0..8) as u8;
+        if addr.get_bits(20..32) != 0xfee
+            || addr.get_bit(2)
+            || data.get_bits(8..11) != 0
+            || irq < 16
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("Unsupported MSI: address {:#x}, data {:#x}", addr, data)
+            );
+        }
+        if !PerCpu::entered()
+            .any(|cpu| cpu.is_hv_enabled() && cpu.apic_id() == apic_id && cpu.cell().id == cell.id)
+        {
+            return hv_result_err!(
+                EINVAL,
+                format!("MSI destination {} is not a CPU of the cell", apic_id)
+            );
+        }
+        crate::arch::apic::send_ipi(apic_id, irq);
+        Ok(0.into())
     }
 }
@@ -6,9 +6,9 @@ use crate::percpu::{CpuState, PerCpu};
 
 fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
     let ret_code = if cpu_data.state != CpuState::HvDisabled && cpu_data.vcpu.in_hypercall() {
-        hv_err!(EIO).code() as usize
+        Some(hv_err!(EIO).code() as usize)
     } else {
-        0
+        None
     };
     match cpu_data.state {
         CpuState::HvEnabled => cpu_data.deactivate_vmm(ret_code)?,
@@ -20,6 +20,8 @@ fn try_handle_panic(cpu_data: &mut PerCpu) -> HvResult {
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
     let cpu_data = PerCpu::current_mut();
+    // Dump first, the log below takes locks that the crashed CPUs may hold.
+    crate::crash::dump(cpu_data, format_args!("{}", info));
     error!("\n{}\nCurrent Cpu: {:#x?}", info, cpu_data);
     let err = try_handle_panic(cpu_data);
     error!("Try handle panic failed: {:?}", err);
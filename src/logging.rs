@@ -1,23 +1,177 @@
 use {
+    alloc::{string::String, vec::Vec},
     core::fmt,
     log::{self, Level, LevelFilter, Log, Metadata, Record},
+    spin::RwLock,
 };
 
+/// Messages from the same call site are printed at most `RATE_LIMIT_BURST` times in each
+/// `RATE_LIMIT_INTERVAL_NS` on each CPU, the others are dropped and counted.
+const RATE_LIMIT_BURST: u32 = 10;
+const RATE_LIMIT_INTERVAL_NS: u64 = 1_000_000_000;
+/// Number of call sites tracked on each CPU.
+const RATE_LIMIT_SITES: usize = 8;
+
+/// Log levels indexed by their numbers used in configurations and hypercalls.
+const LEVEL_FILTERS: [LevelFilter; 6] = [
+    LevelFilter::Off,
+    LevelFilter::Error,
+    LevelFilter::Warn,
+    LevelFilter::Info,
+    LevelFilter::Debug,
+    LevelFilter::Trace,
+];
+
+struct LogFilter {
+    global: LevelFilter,
+    /// Levels of modules (and their submodules), overriding the global level.
+    modules: Vec<(String, LevelFilter)>,
+}
+
+static LOG_FILTER: RwLock<LogFilter> = RwLock::new(LogFilter {
+    global: LevelFilter::Off,
+    modules: Vec::new(),
+});
+
+impl LogFilter {
+    /// Returns the level of `target`, which is a module path like `rvm::arch::x86_64::vmm`.
+    /// Module levels can be set with or without the crate name.
+    fn level(&self, target: &str) -> LevelFilter {
+        let path = target.split_once("::").map_or("", |(_, path)| path);
+        self.modules
+            .iter()
+            .filter(|(module, _)| is_in_module(target, module) || is_in_module(path, module))
+            .max_by_key(|(module, _)| module.len())
+            .map_or(self.global, |&(_, level)| level)
+    }
+
+    fn max_level(&self) -> LevelFilter {
+        self.modules
+            .iter()
+            .map(|&(_, level)| level)
+            .fold(self.global, core::cmp::max)
+    }
+}
+
+fn is_in_module(path: &str, module: &str) -> bool {
+    path.strip_prefix(module)
+        .map_or(false, |rest| rest.is_empty() || rest.starts_with("::"))
+}
+
 pub fn init() {
-    log::set_logger(&SimpleLogger).unwrap();
-    log::set_max_level(match option_env!("LOG") {
-        Some("error") => LevelFilter::Error,
-        Some("warn") => LevelFilter::Warn,
-        Some("info") => LevelFilter::Info,
-        Some("debug") => LevelFilter::Debug,
-        Some("trace") => LevelFilter::Trace,
-        _ => LevelFilter::Off,
-    });
+    // Already set if the hypervisor is enabled again.
+    log::set_logger(&SimpleLogger).ok();
+    LOG_FILTER.write().modules.clear();
+    set_level(
+        None,
+        match option_env!("LOG") {
+            Some("error") => LevelFilter::Error,
+            Some("warn") => LevelFilter::Warn,
+            Some("info") => LevelFilter::Info,
+            Some("debug") => LevelFilter::Debug,
+            Some("trace") => LevelFilter::Trace,
+            _ => LevelFilter::Off,
+        },
+    );
+}
+
+/// Returns the log level numbered `num`, from 0 (`Off`) to 5 (`Trace`).
+pub fn level_filter(num: u64) -> Option<LevelFilter> {
+    LEVEL_FILTERS.get(num as usize).copied()
+}
+
+/// Set the log level of `module` and its submodules, or the global level if `module` is `None`.
+pub fn set_level(module: Option<&str>, level: LevelFilter) {
+    let mut filter = LOG_FILTER.write();
+    match module {
+        Some(module) => match filter.modules.iter_mut().find(|(m, _)| m == module) {
+            Some(entry) => entry.1 = level,
+            None => filter.modules.push((module.into(), level)),
+        },
+        None => filter.global = level,
+    }
+    log::set_max_level(filter.max_level());
+}
+
+#[derive(Clone, Copy)]
+struct CallSite {
+    file: &'static str,
+    line: u32,
+    window_start: u64,
+    printed: u32,
+    suppressed: u32,
+}
+
+/// Per-CPU rate limiter of log messages.
+pub struct RateLimiter {
+    sites: [Option<CallSite>; RATE_LIMIT_SITES],
+    next_evicted: usize,
+    busy: bool,
+}
+
+impl RateLimiter {
+    pub const fn new() -> Self {
+        Self {
+            sites: [None; RATE_LIMIT_SITES],
+            next_evicted: 0,
+            busy: false,
+        }
+    }
+
+    /// Returns whether `record` should be printed, and the number of messages from the same
+    /// call site dropped since the last printed one.
+    fn check(&mut self, record: &Record, now: u64) -> (bool, u32) {
+        let (file, line) = match (record.file_static(), record.line()) {
+            (Some(file), Some(line)) => (file, line),
+            _ => return (true, 0),
+        };
+        // Reentered from the NMI handler.
+        if self.busy {
+            return (true, 0);
+        }
+        self.busy = true;
+        let idx = match self
+            .sites
+            .iter()
+            .position(|s| matches!(s, Some(s) if s.line == line && s.file == file))
+        {
+            Some(idx) => idx,
+            None => {
+                let idx = self.next_evicted;
+                self.next_evicted = (idx + 1) % RATE_LIMIT_SITES;
+                self.sites[idx] = Some(CallSite {
+                    file,
+                    line,
+                    window_start: now,
+                    printed: 0,
+                    suppressed: 0,
+                });
+                idx
+            }
+        };
+        let site = self.sites[idx].as_mut().unwrap();
+        let ret = if now.wrapping_sub(site.window_start) >= RATE_LIMIT_INTERVAL_NS {
+            let suppressed = site.suppressed;
+            site.window_start = now;
+            site.printed = 1;
+            site.suppressed = 0;
+            (true, suppressed)
+        } else if site.printed < RATE_LIMIT_BURST {
+            site.printed += 1;
+            (true, 0)
+        } else {
+            site.suppressed += 1;
+            (false, 0)
+        };
+        self.busy = false;
+        ret
+    }
 }
 
 #[allow(dead_code)]
 pub fn print(args: fmt::Arguments) {
     crate::arch::serial::putfmt(args);
+    crate::console::putfmt(args);
 }
 
 #[cfg(not(test))]
@@ -66,17 +220,42 @@ enum ColorCode {
 struct SimpleLogger;
 
 impl Log for SimpleLogger {
-    fn enabled(&self, _metadata: &Metadata) -> bool {
-        true
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        // Do not spin in case the lock holder is interrupted by an NMI on this CPU.
+        match LOG_FILTER.try_read() {
+            Some(filter) => metadata.level() <= filter.level(metadata.target()),
+            None => true,
+        }
     }
     fn log(&self, record: &Record) {
         if !self.enabled(record.metadata()) {
             return;
         }
 
-        let time_micros = crate::arch::cpu::current_time_nanos() / 1000;
-        let cpu_id = crate::percpu::PerCpu::current().id;
+        let time_nanos = crate::arch::cpu::current_time_nanos();
+        let time_micros = time_nanos / 1000;
+        let cpu_data = crate::percpu::PerCpu::current_mut();
+        let cpu_id = cpu_data.id;
         let level = record.level();
+        // Errors are always printed, debug and trace messages are asked for explicitly.
+        let (need_print, suppressed) = if matches!(level, Level::Warn | Level::Info) {
+            cpu_data.log_limiter.check(record, time_nanos)
+        } else {
+            (true, 0)
+        };
+        if !need_print {
+            return;
+        }
+        if suppressed > 0 {
+            print(with_color!(
+                ColorCode::BrightBlack,
+                "[{:>4}.{:06} {}] {} similar messages suppressed\n",
+                time_micros / 1_000_000,
+                time_micros % 1_000_000,
+                cpu_id,
+                suppressed,
+            ));
+        }
         let level_color = match level {
             Level::Error => ColorCode::BrightRed,
             Level::Warn => ColorCode::BrightYellow,
@@ -22,12 +22,16 @@ mod error;
 
 mod cell;
 mod config;
+mod console;
 mod consts;
+mod crash;
 mod header;
 mod hypercall;
 mod memory;
 mod percpu;
 mod stats;
+#[cfg(feature = "trace")]
+mod trace;
 
 #[cfg(not(test))]
 mod lang;
@@ -46,6 +50,8 @@ use percpu::PerCpu;
 static INITED_CPUS: AtomicU32 = AtomicU32::new(0);
 static INIT_EARLY_OK: AtomicU32 = AtomicU32::new(0);
 static INIT_LATE_OK: AtomicU32 = AtomicU32::new(0);
+static READY_CPUS: AtomicU32 = AtomicU32::new(0);
+static FAILED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ERROR_NUM: AtomicI32 = AtomicI32::new(0);
 
 fn has_err() -> bool {
@@ -67,6 +73,32 @@ fn wait_for_counter(counter: &AtomicU32, max_value: u32) -> HvResult {
     wait_for(|| counter.load(Ordering::Acquire) < max_value)
 }
 
+/// Called by the last CPU leaving the hypervisor, to release global resources and reset the
+/// global state so that the hypervisor can be enabled again.
+fn shutdown() {
+    info!("Shutting down hypervisor...");
+    cell::shutdown();
+    memory::shutdown();
+    arch::vmm::set_hw_dirty_log(false);
+    hypercall::reset();
+    INITED_CPUS.store(0, Ordering::Release);
+    INIT_EARLY_OK.store(0, Ordering::Release);
+    INIT_LATE_OK.store(0, Ordering::Release);
+    READY_CPUS.store(0, Ordering::Release);
+    FAILED_CPUS.store(0, Ordering::Release);
+    ERROR_NUM.store(0, Ordering::Release);
+    PerCpu::reset_counters();
+}
+
+/// Called by CPUs which failed to enable the hypervisor, or were sent back to Linux because
+/// another CPU failed. The last one releases global resources after others have switched back
+/// to the Linux page table.
+fn enable_failed() {
+    if FAILED_CPUS.fetch_add(1, Ordering::AcqRel) + 1 == HvHeader::get().online_cpus {
+        shutdown();
+    }
+}
+
 fn primary_init_early() -> HvResult {
     logging::init();
     info!("Primary CPU init early...");
@@ -94,11 +126,16 @@ fn primary_init_early() -> HvResult {
 
     memory::init_heap();
     system_config.check()?;
+    if let Some(level) = system_config.log_level().and_then(logging::level_filter) {
+        logging::set_level(None, level);
+    }
     info!("Hypervisor header: {:#x?}", HvHeader::get());
     debug!("System config: {:#x?}", system_config);
 
     memory::init_frame_allocator();
+    crash::init();
     memory::init_hv_page_table()?;
+    arch::apic::init()?;
     cell::init()?;
 
     INIT_EARLY_OK.store(1, Ordering::Release);
@@ -138,13 +175,49 @@ fn main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
         wait_for_counter(&INIT_LATE_OK, 1)?
     }
 
+    // No CPU enters the guest before all others are ready, so that only a failed VM entry
+    // leaves some CPUs running the guest.
+    READY_CPUS.fetch_add(1, Ordering::SeqCst);
+    wait_for_counter(&READY_CPUS, online_cpus)?;
+    cpu_data.activate_vmm()
+}
+
+/// A CPU coming online after the hypervisor is enabled enters it alone (called by the driver
+/// on CPU hotplug), and gets a fresh vCPU running Linux directly.
+fn hotplug_main(cpu_data: &mut PerCpu, linux_sp: usize) -> HvResult {
+    println!("Hotplugged CPU {} entered.", cpu_data.id);
+    cpu_data.init(linux_sp, cell::root_cell())?;
+    println!("CPU {} init OK.", cpu_data.id);
     cpu_data.activate_vmm()
 }
 
 extern "sysv64" fn entry(cpu_data: &mut PerCpu, linux_sp: usize) -> i32 {
+    // Set after all CPUs online at enabling time are initialized.
+    if INIT_LATE_OK.load(Ordering::Acquire) != 0 {
+        let e = hotplug_main(cpu_data, linux_sp).unwrap_err();
+        error!("{:?}", e);
+        // Other CPUs keep running the hypervisor. `activate_vmm()` has undone its count.
+        cpu_data.rollback();
+        cpu_data.release_id();
+        println!(
+            "CPU {} return back to driver with code {}.",
+            cpu_data.id,
+            e.code()
+        );
+        return e.code();
+    }
+
     if let Err(e) = main(cpu_data, linux_sp) {
         error!("{:?}", e);
-        ERROR_NUM.store(e.code(), Ordering::Release);
+        // Keep the first error, others are usually EBUSY caused by it.
+        ERROR_NUM
+            .compare_exchange(0, e.code(), Ordering::AcqRel, Ordering::Acquire)
+            .ok();
+        cpu_data.rollback();
+        // Before the ready barrier in `main()`, all CPUs fail and roll back. After it, CPUs
+        // that entered the guest return to Linux and are counted as failed as well.
+        PerCpu::leave_all_activated();
+        enable_failed();
     }
     let code = ERROR_NUM.load(Ordering::Acquire);
     println!(
@@ -14,6 +14,8 @@ type FrameAlloc = bitmap_allocator::BitAlloc1M;
 struct FrameAllocator {
     base: PhysAddr,
     inner: FrameAlloc,
+    total: usize,
+    used: usize,
 }
 
 /// A safe wrapper for physical frame allocation.
@@ -30,13 +32,20 @@ impl FrameAllocator {
         Self {
             base: 0,
             inner: FrameAlloc::DEFAULT,
+            total: 0,
+            used: 0,
         }
     }
 
     fn init(&mut self, base: PhysAddr, size: usize) {
         self.base = align_up(base);
         let page_count = align_up(size) / PAGE_SIZE;
+        // Frames leaked by the last run are discarded, nothing uses them after the hypervisor is
+        // disabled.
+        self.inner.remove(0..FrameAlloc::CAP);
         self.inner.insert(0..page_count);
+        self.total = page_count;
+        self.used = 0;
     }
 
     /// # Safety
@@ -44,6 +53,9 @@ impl FrameAllocator {
     /// This function is unsafe because you need to deallocate manually.
     unsafe fn alloc(&mut self) -> Option<PhysAddr> {
         let ret = self.inner.alloc().map(|idx| idx * PAGE_SIZE + self.base);
+        if ret.is_some() {
+            self.used += 1;
+        }
         trace!("Allocate frame: {:x?}", ret);
         ret
     }
@@ -60,6 +72,9 @@ impl FrameAllocator {
             .inner
             .alloc_contiguous(frame_count, align_log2)
             .map(|idx| idx * PAGE_SIZE + self.base);
+        if ret.is_some() {
+            self.used += frame_count;
+        }
         trace!(
             "Allocate {} frames with alignment {}: {:x?}",
             frame_count,
@@ -74,7 +89,8 @@ impl FrameAllocator {
     /// This function is unsafe because the frame must have been allocated.
     unsafe fn dealloc(&mut self, target: PhysAddr) {
         trace!("Deallocate frame: {:x}", target);
-        self.inner.dealloc((target - self.base) / PAGE_SIZE)
+        self.inner.dealloc((target - self.base) / PAGE_SIZE);
+        self.used -= 1;
     }
 
     /// # Safety
@@ -86,6 +102,7 @@ impl FrameAllocator {
         for i in start_idx..start_idx + frame_count {
             self.inner.dealloc(i)
         }
+        self.used -= frame_count;
     }
 }
 
@@ -198,7 +215,7 @@ impl Drop for Frame {
 /// Initialize the physical frame allocator.
 pub(super) fn init() {
     let mem_pool_start = crate::consts::free_memory_start();
-    let mem_pool_end = align_down(crate::consts::hv_end());
+    let mem_pool_end = align_down(crate::consts::crash_dump_start());
     let mem_pool_size = mem_pool_end - mem_pool_start;
     FRAME_ALLOCATOR
         .lock()
@@ -209,3 +226,9 @@ pub(super) fn init() {
         mem_pool_start..mem_pool_end
     );
 }
+
+/// Returns the total and used number of physical frames.
+pub(super) fn stats() -> (usize, usize) {
+    let allocator = FRAME_ALLOCATOR.lock();
+    (allocator.total, allocator.used)
+}
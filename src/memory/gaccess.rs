@@ -1,26 +1,32 @@
 #![allow(dead_code)]
 
+use alloc::string::String;
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
 use core::marker::PhantomData;
-use core::mem::size_of;
+use core::mem::{size_of, MaybeUninit};
+use core::{ptr, slice};
 
-use super::addr::{page_offset, phys_to_virt, GuestPhysAddr, GuestVirtAddr};
-use super::GenericPageTableImmut;
-use crate::arch::GuestPageTableImmut;
-use crate::error::HvResult;
+use x86_64::structures::idt::PageFaultErrorCode as PFEC;
+
+use super::addr::{page_offset, phys_to_virt, GuestPhysAddr, GuestVirtAddr, HostVirtAddr};
+use super::{hv_page_table, GenericPageTableImmut, MemFlags, PAGE_SIZE};
+use crate::arch::GuestPageTable;
+use crate::error::{HvError, HvResult};
+use crate::percpu::PerCpu;
 
 pub struct GuestPtr<'a, T> {
     gvaddr: GuestVirtAddr,
-    guest_pt: &'a GuestPageTableImmut,
+    guest_pt: &'a GuestPageTable,
     mark: PhantomData<T>,
 }
 
 pub trait AsGuestPtr: Copy {
-    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTableImmut) -> GuestPtr<'_, T>;
+    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTable) -> GuestPtr<'_, T>;
 }
 
 impl AsGuestPtr for GuestVirtAddr {
-    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTableImmut) -> GuestPtr<'_, T> {
+    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTable) -> GuestPtr<'_, T> {
         GuestPtr {
             gvaddr: self,
             guest_pt,
@@ -30,7 +36,7 @@ impl AsGuestPtr for GuestVirtAddr {
 }
 
 impl AsGuestPtr for u64 {
-    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTableImmut) -> GuestPtr<'_, T> {
+    fn as_guest_ptr<T>(self, guest_pt: &GuestPageTable) -> GuestPtr<'_, T> {
         (self as GuestVirtAddr).as_guest_ptr(guest_pt)
     }
 }
@@ -47,16 +53,11 @@ impl<T> GuestPtr<'_, T> {
     }
 
     pub fn as_guest_paddr(&self) -> HvResult<GuestPhysAddr> {
-        let gpaddr = self.guest_pt.query(self.gvaddr)?.0;
-        Self::check_gpaddr(gpaddr)?;
+        let gpaddr = self.guest_pt.walk(self.gvaddr, PFEC::empty())?.0;
+        check_gpaddr(gpaddr, MemFlags::READ)?;
         Ok(gpaddr)
     }
 
-    fn check_gpaddr(_gpaddr: GuestPhysAddr) -> HvResult {
-        // TODO
-        Ok(())
-    }
-
     fn check_raw(addr: usize) -> HvResult {
         if addr == 0 {
             return hv_result_err!(EFAULT, "GuestPtr is null");
@@ -71,84 +72,206 @@ impl<T> GuestPtr<'_, T> {
         Self::check_raw(self.gvaddr)
     }
 
-    pub fn read(&self) -> HvResult<T> {
+    /// Returns an iterator over host memory chunks backing `size` bytes of guest memory from
+    /// this pointer, see `GuestChunks`.
+    pub fn chunks(&self, size: usize, access: MemFlags) -> GuestChunks<'_> {
+        GuestChunks {
+            gvaddr: self.gvaddr,
+            remaining: size,
+            access,
+            guest_pt: self.guest_pt,
+        }
+    }
+
+    /// Copy `dst.len()` bytes from the guest memory at this pointer to `dst`.
+    pub fn copy_from_guest(&self, dst: &mut [u8]) -> HvResult {
+        unsafe { self.copy_from_guest_raw(dst.as_mut_ptr(), dst.len()) }
+    }
+
+    /// Copy `size` bytes from the guest memory at this pointer to `dst`, which may be
+    /// uninitialized.
+    ///
+    /// # Safety
+    ///
+    /// `dst` must be valid for writes of `size` bytes.
+    unsafe fn copy_from_guest_raw(&self, dst: *mut u8, size: usize) -> HvResult {
         self.check_ptr()?;
-        let mut ret = core::mem::MaybeUninit::uninit();
-        let mut dst = ret.as_mut_ptr() as *mut u8;
-
-        let mut gvaddr = self.gvaddr;
-        let mut size = size_of::<T>();
-        while size > 0 {
-            let (gpaddr, _, pg_size) = self.guest_pt.query(gvaddr)?;
-            Self::check_gpaddr(gpaddr)?;
-            let pgoff = pg_size.page_offset(gvaddr);
-            let read_size = (pg_size as usize - pgoff).min(size);
-            gvaddr += read_size;
-            size -= read_size;
-            unsafe {
-                dst.copy_from_nonoverlapping(phys_to_virt(gpaddr) as *const _, read_size);
-                dst = dst.add(read_size);
-            }
+        let mut off = 0;
+        for chunk in self.chunks(size, MemFlags::READ) {
+            let (hvaddr, len) = chunk?;
+            ptr::copy_nonoverlapping(hvaddr as *const u8, dst.add(off), len);
+            off += len;
         }
-        unsafe { Ok(ret.assume_init()) }
+        Ok(())
     }
 
-    pub fn _write(&mut self, data: T) -> HvResult {
+    /// Copy `src` to the guest memory at this pointer.
+    pub fn copy_to_guest(&mut self, src: &[u8]) -> HvResult {
         self.check_ptr()?;
-        let mut src = &data as *const _ as *const u8;
-
-        let mut gvaddr = self.gvaddr;
-        let mut size = size_of::<T>();
-        while size > 0 {
-            let (gpaddr, _, pg_size) = self.guest_pt.query(gvaddr)?;
-            Self::check_gpaddr(gpaddr)?;
-            let pgoff = pg_size.page_offset(gvaddr);
-            let write_size = (pg_size as usize - pgoff).min(size);
-            gvaddr += write_size;
-            size -= write_size;
-            let dst = phys_to_virt(gpaddr) as *mut u8;
+        let mut off = 0;
+        for chunk in self.chunks(src.len(), MemFlags::WRITE) {
+            let (hvaddr, len) = chunk?;
             unsafe {
-                dst.copy_from_nonoverlapping(src, write_size);
-                src = src.add(write_size);
-            }
+                slice::from_raw_parts_mut(hvaddr as *mut u8, len)
+                    .copy_from_slice(&src[off..off + len])
+            };
+            off += len;
         }
         Ok(())
     }
 
+    /// Read a NUL-terminated string at this pointer, which is at most `max_len` bytes long
+    /// (without the NUL).
+    pub fn read_cstr(&self, max_len: usize) -> HvResult<String> {
+        self.check_ptr()?;
+        let mut bytes = Vec::new();
+        // Stop at the NUL, pages after it are not required to be accessible.
+        for chunk in self.chunks(max_len.saturating_add(1), MemFlags::READ) {
+            let (hvaddr, len) = chunk?;
+            let data = unsafe { slice::from_raw_parts(hvaddr as *const u8, len) };
+            if let Some(nul) = data.iter().position(|&b| b == 0) {
+                bytes.extend_from_slice(&data[..nul]);
+                return String::from_utf8(bytes).map_err(|_| {
+                    hv_err!(
+                        EINVAL,
+                        format!("Guest string {:#x?} is not UTF-8", self.gvaddr)
+                    )
+                });
+            }
+            bytes.extend_from_slice(data);
+        }
+        hv_result_err!(
+            ERANGE,
+            format!(
+                "Guest string {:#x?} is longer than {}",
+                self.gvaddr, max_len
+            )
+        )
+    }
+
+    pub fn read(&self) -> HvResult<T> {
+        self.check_ptr()?;
+        let mut ret = MaybeUninit::<T>::uninit();
+        unsafe {
+            self.copy_from_guest_raw(ret.as_mut_ptr() as *mut u8, size_of::<T>())?;
+            Ok(ret.assume_init())
+        }
+    }
+
+    pub fn write(&mut self, data: T) -> HvResult {
+        self.check_ptr()?;
+        let src = unsafe { slice::from_raw_parts(&data as *const _ as *const u8, size_of::<T>()) };
+        self.copy_to_guest(src)
+    }
+
     pub fn as_ref(&self) -> HvResult<&T> {
         self.check_ptr()?;
         let size = size_of::<T>();
-        let (gpaddr, _, pg_size) = self.guest_pt.query(self.gvaddr)?;
-        Self::check_gpaddr(gpaddr)?;
-        if page_offset(gpaddr) + size > pg_size as usize {
+        let gpaddr = self.guest_pt.walk(self.gvaddr, PFEC::empty())?.0;
+        if page_offset(gpaddr) + size > PAGE_SIZE {
             return hv_result_err!(
                 EINVAL,
                 "GuestPtr::as_ref() requires data layout not to cross pages"
             );
         }
-        let ptr = phys_to_virt(gpaddr) as *const _;
+        let ptr = check_gpaddr(gpaddr, MemFlags::READ)? as *const _;
         unsafe { Ok(&*ptr) }
     }
 
     pub fn as_mut(&mut self) -> HvResult<&mut T> {
         self.check_ptr()?;
         let size = size_of::<T>();
-        let (gpaddr, _, pg_size) = self.guest_pt.query(self.gvaddr)?;
-        Self::check_gpaddr(gpaddr)?;
-        if page_offset(gpaddr) + size > pg_size as usize {
+        let gpaddr = self.guest_pt.walk(self.gvaddr, PFEC::CAUSED_BY_WRITE)?.0;
+        if page_offset(gpaddr) + size > PAGE_SIZE {
             return hv_result_err!(
                 EINVAL,
                 "GuestPtr::as_mut() requires data layout not to cross pages"
             );
         }
-        let ptr = phys_to_virt(gpaddr) as *mut _;
+        let ptr = check_gpaddr(gpaddr, MemFlags::WRITE)? as *mut _;
         unsafe { Ok(&mut *ptr) }
     }
 
     pub fn gpaddr_to_ref_mut(gpaddr: GuestPhysAddr) -> HvResult<&'static mut T> {
         Self::check_raw(gpaddr)?;
-        Self::check_gpaddr(gpaddr)?;
-        let ptr = unsafe { &mut *(phys_to_virt(gpaddr) as *mut T) };
+        if page_offset(gpaddr) + size_of::<T>() > PAGE_SIZE {
+            return hv_result_err!(
+                EINVAL,
+                "GuestPtr::gpaddr_to_ref_mut() requires data layout not to cross pages"
+            );
+        }
+        let hvaddr = check_gpaddr(gpaddr, MemFlags::READ | MemFlags::WRITE)?;
+        let ptr = unsafe { &mut *(hvaddr as *mut T) };
         Ok(ptr)
     }
 }
+
+/// Iterator over chunks of a guest virtual memory range, each chunk is the host virtual address
+/// and size of guest memory in one 4K guest physical page. Each page is translated and checked
+/// by the time it is reached, so the iteration stops at the first fault.
+pub struct GuestChunks<'a> {
+    gvaddr: GuestVirtAddr,
+    remaining: usize,
+    access: MemFlags,
+    guest_pt: &'a GuestPageTable,
+}
+
+impl Iterator for GuestChunks<'_> {
+    type Item = HvResult<(HostVirtAddr, usize)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.remaining == 0 {
+            return None;
+        }
+        let pfec = if self.access.contains(MemFlags::WRITE) {
+            PFEC::CAUSED_BY_WRITE
+        } else {
+            PFEC::empty()
+        };
+        let res = self
+            .guest_pt
+            .walk(self.gvaddr, pfec)
+            .map_err(HvError::from)
+            .and_then(|(gpaddr, _)| check_gpaddr(gpaddr, self.access))
+            .map(|hvaddr| {
+                let len = (PAGE_SIZE - page_offset(hvaddr)).min(self.remaining);
+                self.gvaddr += len;
+                self.remaining -= len;
+                (hvaddr, len)
+            });
+        if res.is_err() {
+            self.remaining = 0;
+        }
+        Some(res)
+    }
+}
+
+/// Check whether the 4K page containing `gpaddr` can be accessed with `access` by the
+/// current cell, returns the host virtual address of `gpaddr` in the hypervisor.
+pub fn check_gpaddr(gpaddr: GuestPhysAddr, access: MemFlags) -> HvResult<HostVirtAddr> {
+    let cell = PerCpu::current().cell();
+    let gpm = cell.gpm();
+    // Only regions with `DMA` are mapped to the hypervisor, see `init_hv_page_table()`.
+    let region = gpm
+        .find_region(gpaddr)
+        .filter(|r| r.flags.contains(access | MemFlags::DMA))
+        .ok_or_else(|| {
+            hv_err!(
+                EFAULT,
+                format!("Cell {} cannot access GPA {:#x?}", cell.id, gpaddr)
+            )
+        })?;
+    let (hpaddr, _, _) = gpm.page_table().query(gpaddr)?;
+    // The region may have been remapped after the hypervisor page table was created.
+    let hvaddr = phys_to_virt(gpaddr);
+    match hv_page_table().read().page_table().query(hvaddr) {
+        Ok((paddr, _, _)) if paddr == hpaddr => Ok(hvaddr),
+        _ => hv_result_err!(
+            EFAULT,
+            format!(
+                "GPA {:#x?} of region {:#x?} is not mapped to the hypervisor",
+                gpaddr, region
+            )
+        ),
+    }
+}
@@ -1,14 +1,21 @@
 //! Dynamic memory allocation.
 
 use buddy_system_allocator::LockedHeap;
+use spin::Once;
 
 use crate::consts::HV_HEAP_SIZE;
 
 #[cfg_attr(not(test), global_allocator)]
 static HEAP_ALLOCATOR: LockedHeap<32> = LockedHeap::<32>::new();
 
-/// Initialize the global heap allocator.
+/// Initialize the global heap allocator. The heap is kept when the hypervisor is disabled, so it
+/// is initialized only once.
 pub(super) fn init() {
+    static INIT: Once<()> = Once::new();
+    INIT.call_once(init_once);
+}
+
+fn init_once() {
     const MACHINE_ALIGN: usize = core::mem::size_of::<usize>();
     const HEAP_BLOCK: usize = HV_HEAP_SIZE / MACHINE_ALIGN;
     static mut HEAP: [usize; HEAP_BLOCK] = [0; HEAP_BLOCK];
@@ -23,3 +30,9 @@ pub(super) fn init() {
         heap_start..heap_start + HV_HEAP_SIZE
     );
 }
+
+/// Returns the total and used bytes of the heap.
+pub(super) fn stats() -> (usize, usize) {
+    let heap = HEAP_ALLOCATOR.lock();
+    (heap.stats_total_bytes(), heap.stats_alloc_actual())
+}
@@ -3,10 +3,12 @@
 //! Memory management.
 
 use alloc::collections::btree_map::{BTreeMap, Entry};
+use alloc::vec::Vec;
 use core::fmt::{Debug, Formatter, Result};
+use core::ops::Range;
 
-use super::addr::{align_down, align_up};
-use super::{mapper::Mapper, paging::GenericPageTable, MemFlags};
+use super::addr::{align_down, align_up, is_aligned, PhysAddr};
+use super::{mapper::Mapper, paging::GenericPageTable, MemFlags, PAGE_SIZE};
 use crate::error::HvResult;
 
 #[derive(Clone)]
@@ -23,6 +25,8 @@ where
 {
     regions: BTreeMap<PT::VA, MemoryRegion<PT::VA>>,
     pt: PT,
+    /// The range of pages whose writes are being logged.
+    dirty_log: Option<Range<usize>>,
 }
 
 impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
@@ -45,6 +49,26 @@ impl<VA: From<usize> + Into<usize> + Copy> MemoryRegion<VA> {
         let p3 = p2 + other.size;
         !(p1 <= p2 || p0 >= p3)
     }
+
+    /// Test whether `vaddr` is inside this region, but not at its start.
+    fn is_strictly_contains(&self, vaddr: usize) -> bool {
+        let start = self.start.into();
+        start < vaddr && vaddr < start + self.size
+    }
+
+    /// Shrink this region to end at `vaddr`, returns the remaining part.
+    fn split_off(&mut self, vaddr: usize) -> Self {
+        let start = self.start.into();
+        let left_size = vaddr - start;
+        let right = Self::new(
+            vaddr.into(),
+            self.size - left_size,
+            self.flags,
+            self.mapper.clone(),
+        );
+        self.size = left_size;
+        right
+    }
 }
 
 impl<PT: GenericPageTable> MemorySet<PT>
@@ -55,6 +79,7 @@ where
         Self {
             regions: BTreeMap::new(),
             pt: PT::new(),
+            dirty_log: None,
         }
     }
 
@@ -62,6 +87,7 @@ where
         Self {
             regions: self.regions.clone(),
             pt: self.pt.clone(),
+            dirty_log: None,
         }
     }
 
@@ -100,6 +126,7 @@ where
     pub fn delete(&mut self, start: PT::VA) -> HvResult {
         if let Entry::Occupied(e) = self.regions.entry(start) {
             self.pt.unmap(e.get())?;
+            self.pt.flush(None);
             e.remove();
             Ok(())
         } else {
@@ -113,6 +140,228 @@ where
         }
     }
 
+    /// Split memory regions which cross `start` or `start + size`, so that the range
+    /// `[start, start + size)` consists of whole regions.
+    pub fn split(&mut self, start: PT::VA, size: usize) -> HvResult {
+        let start = start.into();
+        if !is_aligned(start) || !is_aligned(size) {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "MemorySet::split(): unaligned range {:#x?}",
+                    start..start + size
+                )
+            );
+        }
+        self.split_at(start)?;
+        self.split_at(start + size)
+    }
+
+    /// Change the flags of all memory regions in `[start, start + size)` to `flags`. The range
+    /// must be fully mapped.
+    pub fn protect(&mut self, start: PT::VA, size: usize, flags: MemFlags) -> HvResult {
+        self.split(start, size)?;
+        for key in self.regions_in(start.into(), size)? {
+            let region = self.regions.get_mut(&key).unwrap();
+            region.flags = flags;
+            self.pt.protect(region)?;
+        }
+        self.pt.flush(None);
+        Ok(())
+    }
+
+    /// Map the range `[start, start + size)` to the physical memory starting from `paddr`,
+    /// keeping the flags. The range must be fully mapped, and will be unmapped temporarily.
+    /// On failure, the range is mapped as before (regions may be split).
+    pub fn remap(&mut self, start: PT::VA, size: usize, paddr: PhysAddr) -> HvResult {
+        if !is_aligned(paddr) {
+            return hv_result_err!(
+                EINVAL,
+                format!("MemorySet::remap(): unaligned paddr {:#x?}", paddr)
+            );
+        }
+        self.split(start, size)?;
+        let start = start.into();
+        let mut replaced = Vec::new();
+        let mut res = Ok(());
+        for key in self.regions_in(start, size)? {
+            let old = &self.regions[&key];
+            let new = MemoryRegion::new_with_offset_mapper(
+                key,
+                paddr + (key.into() - start),
+                old.size,
+                old.flags,
+            );
+            match self.replace_region(new) {
+                Ok(old) => replaced.push(old),
+                Err(e) => {
+                    res = Err(e);
+                    break;
+                }
+            }
+        }
+        if res.is_err() {
+            // Roll back the regions already remapped.
+            for old in replaced.into_iter().rev() {
+                self.replace_region(old)?;
+            }
+        }
+        self.pt.flush(None);
+        res
+    }
+
+    /// Replace the region starting at the same address as `new`, which must have the same
+    /// size, returns the old one. The old mapping is kept on failure.
+    fn replace_region(&mut self, new: MemoryRegion<PT::VA>) -> HvResult<MemoryRegion<PT::VA>> {
+        let old = &self.regions[&new.start];
+        self.pt.unmap(old)?;
+        if let Err(e) = self.pt.map(&new) {
+            self.pt.map(old)?;
+            return Err(e);
+        }
+        Ok(self.regions.insert(new.start, new).unwrap())
+    }
+
+    /// Returns the region containing `vaddr`.
+    pub fn find_region(&self, vaddr: PT::VA) -> Option<&MemoryRegion<PT::VA>> {
+        let addr = vaddr.into();
+        match self.regions.range(..=vaddr).next_back() {
+            Some((_, region)) if addr < region.start.into() + region.size => Some(region),
+            _ => None,
+        }
+    }
+
+    /// Start logging writes to pages in `[start, start + size)`. The range must be fully mapped.
+    pub fn start_dirty_log(&mut self, start: PT::VA, size: usize) -> HvResult {
+        if self.dirty_log.is_some() {
+            return hv_result_err!(EBUSY, "MemorySet: dirty logging is already started");
+        }
+        self.split(start, size)?;
+        self.regions_in(start.into(), size)?;
+        let range = start.into()..start.into() + size;
+        self.dirty_log = Some(range.clone());
+        self.clear_dirty(range, |_| {})?;
+        self.pt.flush(None);
+        Ok(())
+    }
+
+    /// Returns a bitmap of pages in the logging range that have been written since the last
+    /// call (or `start_dirty_log()`), bit `i` for the `i`-th 4K page, and clear it.
+    ///
+    /// If `candidates` is given (e.g. logged by hardware), only these pages are checked instead
+    /// of all pages in the range. Dirty huge pages are reported as a whole.
+    pub fn fetch_dirty_log(&mut self, candidates: Option<&[PT::VA]>) -> HvResult<Vec<u64>> {
+        let range = match &self.dirty_log {
+            Some(range) => range.clone(),
+            None => return hv_result_err!(EINVAL, "MemorySet: dirty logging is not started"),
+        };
+        let mut bitmap = vec![0u64; (range.len() / PAGE_SIZE + 63) / 64];
+        let mut mark = |pages: Range<usize>| {
+            let start = pages.start.max(range.start);
+            let end = pages.end.min(range.end);
+            for idx in (start - range.start) / PAGE_SIZE..(end - range.start) / PAGE_SIZE {
+                bitmap[idx / 64] |= 1 << (idx % 64);
+            }
+        };
+        match candidates {
+            Some(candidates) => {
+                for &vaddr in candidates {
+                    let vaddr = align_down(vaddr.into());
+                    if range.contains(&vaddr) {
+                        self.clear_dirty(vaddr..vaddr + PAGE_SIZE, &mut mark)?;
+                    }
+                }
+            }
+            None => self.clear_dirty(range.clone(), &mut mark)?,
+        }
+        self.pt.flush(None);
+        Ok(bitmap)
+    }
+
+    /// Stop logging writes, write permissions removed for logging are restored.
+    pub fn stop_dirty_log(&mut self) -> HvResult {
+        let range = match self.dirty_log.take() {
+            Some(range) => range,
+            None => return hv_result_err!(EINVAL, "MemorySet: dirty logging is not started"),
+        };
+        for key in self.regions_in(range.start, range.len())? {
+            self.pt.protect(&self.regions[&key])?;
+        }
+        self.pt.flush(None);
+        Ok(())
+    }
+
+    /// Handle a write fault at `vaddr` caused by write-protection for dirty logging: restore
+    /// the write permission of the page, so that it will be reported as dirty. Returns `false`
+    /// if the fault is not caused by dirty logging.
+    pub fn handle_dirty_fault(&mut self, vaddr: PT::VA) -> HvResult<bool> {
+        let addr = vaddr.into();
+        if !matches!(&self.dirty_log, Some(range) if range.contains(&addr)) {
+            return Ok(false);
+        }
+        let flags = match self.regions.range(..=vaddr).next_back() {
+            Some((_, region)) if region.flags.contains(MemFlags::WRITE) => region.flags,
+            _ => return Ok(false),
+        };
+        let (paddr, _, size) = self.pt.query(vaddr)?;
+        self.pt.update(vaddr, size.align_down(paddr), flags)?;
+        // The stale read-only mapping may be cached, which would cause the fault again.
+        self.pt.flush(Some(vaddr));
+        Ok(true)
+    }
+
+    /// Clear the dirty state of pages in `range`, and call `f` with the address range of each
+    /// page which was dirty. TLBs are not flushed.
+    fn clear_dirty(&mut self, range: Range<usize>, mut f: impl FnMut(Range<usize>)) -> HvResult {
+        let mut vaddr = range.start;
+        while vaddr < range.end {
+            let (dirty, size) = self.pt.test_and_clear_dirty(vaddr.into())?;
+            let page_start = size.align_down(vaddr);
+            if dirty {
+                f(page_start..page_start + size as usize);
+            }
+            vaddr = page_start + size as usize;
+        }
+        Ok(())
+    }
+
+    /// Split the region which strictly contains `vaddr` into two.
+    fn split_at(&mut self, vaddr: usize) -> HvResult {
+        let key = match self.regions.range(..PT::VA::from(vaddr)).next_back() {
+            Some((&key, region)) if region.is_strictly_contains(vaddr) => key,
+            _ => return Ok(()),
+        };
+        self.pt.split_at(vaddr.into())?;
+        let right = self.regions.get_mut(&key).unwrap().split_off(vaddr);
+        self.regions.insert(right.start, right);
+        self.pt.flush(None);
+        Ok(())
+    }
+
+    /// Returns the start addresses of regions which exactly cover `[start, start + size)`.
+    fn regions_in(&self, start: usize, size: usize) -> HvResult<Vec<PT::VA>> {
+        let end = start + size;
+        let mut next = start;
+        let mut keys = Vec::new();
+        for (&key, region) in self.regions.range(PT::VA::from(start)..PT::VA::from(end)) {
+            if key.into() != next {
+                break;
+            }
+            next += region.size;
+            keys.push(key);
+        }
+        if next != end {
+            return hv_result_err!(
+                EINVAL,
+                format!(
+                    "MemorySet: range {:#x?} is not fully mapped",
+                    start..start + size
+                )
+            );
+        }
+        Ok(keys)
+    }
+
     pub fn clear(&mut self) {
         for region in self.regions.values() {
             self.pt.unmap(region).unwrap();
@@ -163,3 +412,248 @@ where
         self.clear();
     }
 }
+
+#[cfg(test)]
+mod test {
+    use alloc::collections::BTreeMap;
+
+    use super::*;
+    use crate::memory::paging::{GenericPageTableImmut, PageSize, PagingError, PagingResult};
+
+    /// A page table recording 4K mappings, which fails to map `fail_paddr`. Dirty pages are
+    /// tracked by write-protection as the NPT does: writable pages are dirty.
+    struct MockPageTable {
+        pages: BTreeMap<usize, (PhysAddr, MemFlags)>,
+        fail_paddr: Option<PhysAddr>,
+    }
+
+    impl GenericPageTableImmut for MockPageTable {
+        type VA = usize;
+
+        unsafe fn from_root(_root_paddr: PhysAddr) -> Self {
+            unimplemented!()
+        }
+        fn root_paddr(&self) -> PhysAddr {
+            0
+        }
+        fn query(&self, vaddr: usize) -> PagingResult<(PhysAddr, MemFlags, PageSize)> {
+            let (paddr, flags) = self
+                .pages
+                .get(&align_down(vaddr))
+                .ok_or(PagingError::NotMapped)?;
+            Ok((*paddr, *flags, PageSize::Size4K))
+        }
+    }
+
+    impl GenericPageTable for MockPageTable {
+        fn new() -> Self {
+            Self {
+                pages: BTreeMap::new(),
+                fail_paddr: None,
+            }
+        }
+        fn map(&mut self, region: &MemoryRegion<usize>) -> HvResult {
+            let pages = (region.start..region.start + region.size).step_by(PAGE_SIZE);
+            for vaddr in pages.clone() {
+                if self.pages.contains_key(&vaddr) {
+                    return Err(PagingError::AlreadyMapped.into());
+                }
+                if Some(region.mapper.map_fn(vaddr)) == self.fail_paddr {
+                    return Err(PagingError::NoMemory.into());
+                }
+            }
+            for vaddr in pages {
+                let paddr = region.mapper.map_fn(vaddr);
+                self.pages.insert(vaddr, (paddr, region.flags));
+            }
+            Ok(())
+        }
+        fn unmap(&mut self, region: &MemoryRegion<usize>) -> HvResult {
+            for vaddr in (region.start..region.start + region.size).step_by(PAGE_SIZE) {
+                self.pages.remove(&vaddr).ok_or(PagingError::NotMapped)?;
+            }
+            Ok(())
+        }
+        fn update(
+            &mut self,
+            vaddr: usize,
+            paddr: PhysAddr,
+            flags: MemFlags,
+        ) -> PagingResult<PageSize> {
+            self.pages.insert(align_down(vaddr), (paddr, flags));
+            Ok(PageSize::Size4K)
+        }
+        fn protect(&mut self, region: &MemoryRegion<usize>) -> HvResult {
+            for vaddr in (region.start..region.start + region.size).step_by(PAGE_SIZE) {
+                self.pages.get_mut(&vaddr).ok_or(PagingError::NotMapped)?.1 = region.flags;
+            }
+            Ok(())
+        }
+        fn split_at(&mut self, _vaddr: usize) -> HvResult {
+            Ok(())
+        }
+        fn test_and_clear_dirty(&mut self, vaddr: usize) -> PagingResult<(bool, PageSize)> {
+            let flags = &mut self
+                .pages
+                .get_mut(&align_down(vaddr))
+                .ok_or(PagingError::NotMapped)?
+                .1;
+            let dirty = flags.contains(MemFlags::WRITE);
+            flags.remove(MemFlags::WRITE);
+            Ok((dirty, PageSize::Size4K))
+        }
+        fn clone(&self) -> Self {
+            Self {
+                pages: self.pages.clone(),
+                fail_paddr: self.fail_paddr,
+            }
+        }
+        unsafe fn activate(&self) {}
+        fn flush(&self, _vaddr: Option<usize>) {}
+    }
+
+    const RW: MemFlags =
+        MemFlags::from_bits_truncate(MemFlags::READ.bits() | MemFlags::WRITE.bits());
+
+    /// A set with regions `[0x10000, 0x20000)` mapped with offset 0x8000, and
+    /// `[0x20000, 0x24000)` mapped with offset 0x20000.
+    fn new_set() -> MemorySet<MockPageTable> {
+        let mut ms = MemorySet::new();
+        ms.insert(MemoryRegion::new_with_offset_mapper(
+            0x10000, 0x8000, 0x10000, RW,
+        ))
+        .unwrap();
+        ms.insert(MemoryRegion::new_with_offset_mapper(0x20000, 0, 0x4000, RW))
+            .unwrap();
+        ms
+    }
+
+    fn layout(ms: &MemorySet<MockPageTable>) -> Vec<(usize, usize)> {
+        ms.regions.values().map(|r| (r.start, r.size)).collect()
+    }
+
+    #[test]
+    fn test_split() {
+        let mut ms = new_set();
+        ms.split(0x14000, 0x4000).unwrap();
+        assert_eq!(
+            layout(&ms),
+            [
+                (0x10000, 0x4000),
+                (0x14000, 0x4000),
+                (0x18000, 0x8000),
+                (0x20000, 0x4000)
+            ]
+        );
+        // Splitting at existing boundaries or outside regions does nothing.
+        ms.split(0x20000, 0x10000).unwrap();
+        assert_eq!(layout(&ms).len(), 4);
+        assert!(ms.split(0x14001, 0x1000).is_err());
+        assert!(ms.split(0x14000, 0x1001).is_err());
+        // Mappings are not changed.
+        assert_eq!(ms.pt.query(0x18000).unwrap().0, 0x10000);
+    }
+
+    #[test]
+    fn test_protect() {
+        let mut ms = new_set();
+        ms.protect(0x1f000, 0x2000, MemFlags::READ).unwrap();
+        assert_eq!(ms.find_region(0x1f000).unwrap().flags, MemFlags::READ);
+        assert_eq!(ms.find_region(0x20000).unwrap().flags, MemFlags::READ);
+        assert_eq!(ms.find_region(0x1e000).unwrap().flags, RW);
+        assert_eq!(ms.find_region(0x21000).unwrap().flags, RW);
+        assert_eq!(ms.pt.query(0x20000).unwrap().1, MemFlags::READ);
+        assert_eq!(ms.pt.query(0x21000).unwrap().1, RW);
+        // The range must be fully mapped.
+        assert!(ms.protect(0x23000, 0x2000, MemFlags::READ).is_err());
+    }
+
+    #[test]
+    fn test_remap() {
+        let mut ms = new_set();
+        ms.remap(0x1e000, 0x4000, 0x100000).unwrap();
+        assert_eq!(ms.pt.query(0x1d000).unwrap().0, 0x15000);
+        assert_eq!(ms.pt.query(0x1e000).unwrap().0, 0x100000);
+        assert_eq!(ms.pt.query(0x21000).unwrap().0, 0x103000);
+        assert_eq!(ms.pt.query(0x22000).unwrap().0, 0x2000);
+        assert_eq!(ms.find_region(0x21000).unwrap().flags, RW);
+    }
+
+    #[test]
+    fn test_remap_rollback() {
+        let mut ms = new_set();
+        // Fails on the second region, the first one must be restored.
+        ms.pt.fail_paddr = Some(0x102000);
+        assert!(ms.remap(0x1e000, 0x4000, 0x100000).is_err());
+        for vaddr in (0x10000..0x24000).step_by(PAGE_SIZE) {
+            let offset = if vaddr < 0x20000 { 0x8000 } else { 0x20000 };
+            assert_eq!(ms.pt.query(vaddr).unwrap().0, vaddr - offset);
+            assert_eq!(
+                ms.find_region(vaddr).unwrap().mapper.map_fn(vaddr),
+                vaddr - offset
+            );
+        }
+    }
+
+    #[test]
+    fn test_start_dirty_log() {
+        let mut ms = new_set();
+        ms.start_dirty_log(0x1e000, 0x4000).unwrap();
+        assert!(ms.start_dirty_log(0x1e000, 0x4000).is_err());
+        // Pages in the range are write-protected, the regions keep their flags.
+        assert_eq!(
+            layout(&ms),
+            [
+                (0x10000, 0xe000),
+                (0x1e000, 0x2000),
+                (0x20000, 0x2000),
+                (0x22000, 0x2000)
+            ]
+        );
+        assert_eq!(ms.pt.query(0x1d000).unwrap().1, RW);
+        assert_eq!(ms.pt.query(0x1e000).unwrap().1, MemFlags::READ);
+        assert_eq!(ms.pt.query(0x21000).unwrap().1, MemFlags::READ);
+        assert_eq!(ms.pt.query(0x22000).unwrap().1, RW);
+        assert_eq!(ms.find_region(0x1e000).unwrap().flags, RW);
+        // Writes outside the range are not caused by dirty logging.
+        assert!(!ms.handle_dirty_fault(0x1d000).unwrap());
+        assert!(ms.handle_dirty_fault(0x1f123).unwrap());
+        assert_eq!(ms.pt.query(0x1f000).unwrap().1, RW);
+    }
+
+    #[test]
+    fn test_fetch_dirty_log() {
+        let mut ms = new_set();
+        assert!(ms.fetch_dirty_log(None).is_err());
+        ms.start_dirty_log(0x1e000, 0x4000).unwrap();
+        assert_eq!(ms.fetch_dirty_log(None).unwrap(), [0]);
+        ms.handle_dirty_fault(0x1f000).unwrap();
+        ms.handle_dirty_fault(0x21000).unwrap();
+        assert_eq!(ms.fetch_dirty_log(None).unwrap(), [0b1010]);
+        // Fetching clears the log and write-protects the pages again.
+        assert_eq!(ms.pt.query(0x1f000).unwrap().1, MemFlags::READ);
+        assert_eq!(ms.fetch_dirty_log(None).unwrap(), [0]);
+        // Only candidates in the range are checked and cleared.
+        ms.handle_dirty_fault(0x1e000).unwrap();
+        ms.handle_dirty_fault(0x20000).unwrap();
+        let candidates = [0x1e800, 0x22000, 0x30000];
+        assert_eq!(ms.fetch_dirty_log(Some(&candidates)).unwrap(), [0b1]);
+        assert_eq!(ms.fetch_dirty_log(None).unwrap(), [0b100]);
+    }
+
+    #[test]
+    fn test_stop_dirty_log() {
+        let mut ms = new_set();
+        assert!(ms.stop_dirty_log().is_err());
+        ms.start_dirty_log(0x1e000, 0x4000).unwrap();
+        ms.handle_dirty_fault(0x1f000).unwrap();
+        ms.stop_dirty_log().unwrap();
+        // Write permissions are restored, and writes no longer fault for logging.
+        for vaddr in (0x10000..0x24000).step_by(PAGE_SIZE) {
+            assert_eq!(ms.pt.query(vaddr).unwrap().1, RW);
+        }
+        assert!(!ms.handle_dirty_fault(0x1e000).unwrap());
+        assert!(ms.fetch_dirty_log(None).is_err());
+        assert!(ms.stop_dirty_log().is_err());
+    }
+}
@@ -58,7 +58,7 @@ pub mod gaccess;
 use core::ops::{Deref, DerefMut};
 
 use bitflags::bitflags;
-use spin::{Once, RwLock};
+use spin::RwLock;
 
 use crate::arch::HostPageTable;
 use crate::config::HvSystemConfig;
@@ -69,7 +69,7 @@ use crate::header::HvHeader;
 pub use addr::{GuestPhysAddr, GuestVirtAddr, HostPhysAddr, HostVirtAddr, PhysAddr, VirtAddr};
 pub use frame::Frame;
 pub use mm::{MemoryRegion, MemorySet};
-pub use paging::{GenericPTE, PagingInstr};
+pub use paging::{GenericPTE, PageSize, PagingInstr};
 pub use paging::{GenericPageTable, GenericPageTableImmut, Level4PageTable, Level4PageTableImmut};
 
 pub const PAGE_SIZE: usize = paging::PageSize::Size4K as usize;
@@ -83,14 +83,16 @@ bitflags! {
         const IO            = 1 << 4;
         const NO_HUGEPAGES  = 1 << 8;
         const USER          = 1 << 9;
+        const WRITE_COMBINING = 1 << 10;
     }
 }
 
-/// Page table used for hypervisor.
-static HV_PT: Once<RwLock<MemorySet<HostPageTable>>> = Once::new();
+/// Page table used for hypervisor. Set by the primary CPU in `init_hv_page_table()` and cleared
+/// by the last CPU leaving the hypervisor in `shutdown()`, while no other CPUs access it.
+static mut HV_PT: Option<RwLock<MemorySet<HostPageTable>>> = None;
 
 pub fn hv_page_table<'a>() -> &'a RwLock<MemorySet<HostPageTable>> {
-    HV_PT.get().expect("Uninitialized hypervisor page table!")
+    unsafe { HV_PT.as_ref() }.expect("Uninitialized hypervisor page table!")
 }
 
 pub fn init_heap() {
@@ -106,6 +108,17 @@ pub fn init_frame_allocator() {
     frame::init();
 }
 
+/// Returns the total and used number of pages in the hypervisor memory pool, which consists of
+/// the physical frames and the heap.
+pub fn mem_pool_stats() -> (usize, usize) {
+    let (frames_total, frames_used) = frame::stats();
+    let (heap_total, heap_used) = heap::stats();
+    (
+        frames_total + heap_total / PAGE_SIZE,
+        frames_used + addr::page_count(heap_used),
+    )
+}
+
 pub fn init_hv_page_table() -> HvResult {
     let header = HvHeader::get();
     let sys_config = HvSystemConfig::get();
@@ -155,10 +168,15 @@ pub fn init_hv_page_table() -> HvResult {
     info!("Hypervisor page table init end.");
     debug!("Hypervisor virtual memory set: {:#x?}", hv_pt);
 
-    HV_PT.call_once(|| RwLock::new(hv_pt));
+    unsafe { HV_PT = Some(RwLock::new(hv_pt)) };
     Ok(())
 }
 
+/// Release the hypervisor page table. Must be called after switching to the Linux page table.
+pub fn shutdown() {
+    unsafe { HV_PT = None };
+}
+
 #[repr(align(4096))]
 pub struct AlignedPage([u8; PAGE_SIZE]);
 
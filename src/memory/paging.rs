@@ -76,6 +76,13 @@ pub trait GenericPTE: Debug + Clone {
     fn is_present(&self) -> bool;
     /// Returns whether this entry maps to a huge frame.
     fn is_huge(&self) -> bool;
+    /// Returns whether the mapped page has been written since the last `clear_dirty()`.
+    fn is_dirty(&self) -> bool;
+    /// Returns whether a huge page of `size` can map the physical memory at `paddr`, e.g. the
+    /// memory type is the same across the page.
+    fn can_map_huge(_paddr: PhysAddr, _size: PageSize) -> bool {
+        true
+    }
 
     /// Set physical address for terminal entries.
     fn set_addr(&mut self, paddr: PhysAddr);
@@ -83,6 +90,8 @@ pub trait GenericPTE: Debug + Clone {
     fn set_flags(&mut self, flags: MemFlags, is_huge: bool);
     /// Set physical address and flags for intermediate table entries.
     fn set_table(&mut self, paddr: PhysAddr);
+    /// Clear the dirty state, so that following writes to the page can be detected.
+    fn clear_dirty(&mut self);
     /// Set this entry to zero.
     fn clear(&mut self);
 }
@@ -115,6 +124,13 @@ pub trait GenericPageTable: GenericPageTableImmut {
         paddr: PhysAddr,
         flags: MemFlags,
     ) -> PagingResult<PageSize>;
+    /// Change the flags of all pages in `region` to `region.flags`, keep the mapped addresses.
+    fn protect(&mut self, region: &MemoryRegion<Self::VA>) -> HvResult;
+    /// Split huge pages (if any) so that `vaddr` is at a page boundary.
+    fn split_at(&mut self, vaddr: Self::VA) -> HvResult;
+    /// Clear the dirty state of the page containing `vaddr`. Returns whether it was dirty and the
+    /// page size.
+    fn test_and_clear_dirty(&mut self, vaddr: Self::VA) -> PagingResult<(bool, PageSize)>;
 
     fn clone(&self) -> Self;
 
@@ -329,6 +345,54 @@ where
         entry.set_flags(flags, size.is_huge());
         Ok(size)
     }
+
+    fn protect_page(&mut self, vaddr: VA, flags: MemFlags) -> PagingResult<PageSize> {
+        let (entry, size) = self.inner.get_entry_mut(vaddr)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        entry.set_flags(flags, size.is_huge());
+        Ok(size)
+    }
+
+    fn test_and_clear_dirty(&mut self, vaddr: VA) -> PagingResult<(bool, PageSize)> {
+        let (entry, size) = self.inner.get_entry_mut(vaddr)?;
+        if entry.is_unused() {
+            return Err(PagingError::NotMapped);
+        }
+        let dirty = entry.is_dirty();
+        if dirty {
+            entry.clear_dirty();
+        }
+        Ok((dirty, size))
+    }
+
+    fn split_page(&mut self, vaddr: VA) -> PagingResult {
+        loop {
+            let (entry, size) = self.inner.get_entry_mut(vaddr)?;
+            if entry.is_unused() {
+                return Err(PagingError::NotMapped);
+            }
+            if !size.is_huge() || size.is_aligned(vaddr.into()) {
+                return Ok(());
+            }
+            let (paddr, flags) = (entry.addr(), entry.flags());
+            let sub_size = match size {
+                PageSize::Size1G => PageSize::Size2M,
+                _ => PageSize::Size4K,
+            };
+            // Fill a new table with the same mapping first, then replace the huge page entry with
+            // it, so that the mapping is never absent.
+            let table_paddr = self
+                .alloc_intrm_table()
+                .map_err(|_| PagingError::NoMemory)?;
+            for (i, e) in table_of_mut::<PTE>(table_paddr).iter_mut().enumerate() {
+                e.set_addr(paddr + i * sub_size as usize);
+                e.set_flags(flags, sub_size.is_huge());
+            }
+            self.inner.get_entry_mut(vaddr)?.0.set_table(table_paddr);
+        }
+    }
 }
 
 /// A extended level-4 page table implements `GenericPageTable`. It use locks to avoid data
@@ -419,12 +483,14 @@ where
                 && PageSize::Size1G.is_aligned(paddr)
                 && size >= PageSize::Size1G as usize
                 && !region.flags.contains(MemFlags::NO_HUGEPAGES)
+                && PTE::can_map_huge(paddr, PageSize::Size1G)
             {
                 PageSize::Size1G
             } else if PageSize::Size2M.is_aligned(vaddr)
                 && PageSize::Size2M.is_aligned(paddr)
                 && size >= PageSize::Size2M as usize
                 && !region.flags.contains(MemFlags::NO_HUGEPAGES)
+                && PTE::can_map_huge(paddr, PageSize::Size2M)
             {
                 PageSize::Size2M
             } else {
@@ -473,6 +539,44 @@ where
         self.inner.update(vaddr, paddr, flags)
     }
 
+    fn protect(&mut self, region: &MemoryRegion<VA>) -> HvResult {
+        trace!(
+            "change mapping flags in {}: {:#x?}",
+            core::any::type_name::<Self>(),
+            region
+        );
+        let _lock = self.clonee_lock.lock();
+        let mut vaddr = region.start.into();
+        let mut size = region.size;
+        while size > 0 {
+            let page_size = self
+                .inner
+                .protect_page(vaddr.into(), region.flags)
+                .map_err(|e| {
+                    error!("failed to protect page: {:#x?}, {:?}", vaddr, e);
+                    e
+                })?;
+            assert!(page_size.is_aligned(vaddr));
+            assert!(page_size as usize <= size);
+            vaddr += page_size as usize;
+            size -= page_size as usize;
+        }
+        Ok(())
+    }
+
+    fn split_at(&mut self, vaddr: VA) -> HvResult {
+        let _lock = self.clonee_lock.lock();
+        self.inner.split_page(vaddr).map_err(|e| {
+            error!("failed to split page at {:#x?}, {:?}", vaddr.into(), e);
+            e.into()
+        })
+    }
+
+    fn test_and_clear_dirty(&mut self, vaddr: VA) -> PagingResult<(bool, PageSize)> {
+        let _lock = self.clonee_lock.lock();
+        self.inner.test_and_clear_dirty(vaddr)
+    }
+
     fn clone(&self) -> Self {
         let mut pt = Self::clone_from(self);
         // clone with lock to avoid data racing between it and its clonees.
@@ -1,13 +1,20 @@
 use core::fmt::{Debug, Formatter, Result};
-use core::sync::atomic::{AtomicU32, Ordering};
+use core::sync::atomic::{AtomicBool, AtomicU32, Ordering};
 
-use crate::arch::vmm::{Vcpu, VcpuAccessGuestState};
-use crate::arch::{cpu, ArchPerCpu, LinuxContext};
+use bitflags::bitflags;
+use numeric_enum_macro::numeric_enum;
+
+use crate::arch::apic::StartupIpi;
+use crate::arch::vmm::{ExitStats, Vcpu, VcpuAccessGuestState};
+use crate::arch::{cpu, ArchPerCpu, FpuState, LinuxContext};
 use crate::cell::Cell;
 use crate::consts::{PER_CPU_ARRAY_PTR, PER_CPU_SIZE};
 use crate::error::HvResult;
 use crate::header::HvHeader;
+use crate::logging::RateLimiter;
 use crate::memory::VirtAddr;
+#[cfg(feature = "trace")]
+use crate::trace::TraceBuffer;
 
 static ENTERED_CPUS: AtomicU32 = AtomicU32::new(0);
 static ACTIVATED_CPUS: AtomicU32 = AtomicU32::new(0);
@@ -18,6 +25,50 @@ pub enum CpuState {
     HvEnabled,
 }
 
+bitflags! {
+    /// Requests sent to other CPUs, which are notified by NMIs.
+    pub struct CpuEvents: u32 {
+        /// Invalidate cached translations derived from the nested page table.
+        const FLUSH_NESTED_TLB = 1 << 0;
+        /// Move dirty pages logged by hardware to the software log.
+        const SYNC_DIRTY_LOG = 1 << 1;
+        /// Another CPU has panicked, capture the state and return to Linux.
+        const CRASH_DUMP = 1 << 2;
+        /// INIT sent by the guest, park the CPU until SIPI.
+        const INIT = 1 << 3;
+        /// Startup IPI sent by the guest, the vector is in `PerCpu::sipi_vector`.
+        const SIPI = 1 << 4;
+        /// Nothing to handle, wake up the CPU idling in the hypervisor to check its state.
+        const WAKE_UP = 1 << 5;
+        /// Another CPU failed to enter the guest while enabling the hypervisor, return to Linux.
+        const LEAVE = 1 << 6;
+    }
+}
+
+numeric_enum! {
+    #[repr(u32)]
+    /// Per-CPU statistics, numbered as Jailhouse does on x86.
+    #[derive(Debug, Clone, Copy, Eq, PartialEq)]
+    pub enum CpuStat {
+        VmExitsTotal = 0,
+        VmExitsMmio = 1,
+        VmExitsManagement = 2,
+        VmExitsHypercall = 3,
+        VmExitsPio = 4,
+        VmExitsXapic = 5,
+        VmExitsCr = 6,
+        VmExitsCpuid = 7,
+        VmExitsXsetbv = 8,
+        VmExitsException = 9,
+        VmExitsMsrOther = 10,
+        VmExitsMsrX2apicIcr = 11,
+        /// RVM-specific: PAUSE-loop exits.
+        VmExitsPause = 12,
+    }
+}
+
+pub const NUM_CPU_STATS: usize = 13;
+
 #[repr(C, align(4096))]
 pub struct PerCpu {
     /// Referenced by arch::cpu::thread_pointer() for x86_64.
@@ -26,6 +77,24 @@ pub struct PerCpu {
     pub id: u32,
     pub state: CpuState,
     pub vcpu: Vcpu,
+    /// Guest FPU state, saved while the hypervisor uses the FPU.
+    pub fpu: FpuState,
+    /// The cell this CPU is assigned to.
+    cell: Option<&'static Cell<'static>>,
+    /// Pending `CpuEvents` sent by other CPUs.
+    events: AtomicU32,
+    /// Set if the nested TLB flush failed in the NMI handler, reported by `handle_events()`.
+    nmi_flush_failed: AtomicBool,
+    /// Vector of the last startup IPI.
+    sipi_vector: AtomicU32,
+    /// Whether the CPU received INIT and is waiting for SIPI in the hypervisor.
+    parked: bool,
+    /// Counters indexed by `CpuStat`.
+    stats: [AtomicU32; NUM_CPU_STATS],
+    pub log_limiter: RateLimiter,
+    pub exit_stats: ExitStats,
+    #[cfg(feature = "trace")]
+    pub trace: TraceBuffer,
     arch: ArchPerCpu,
     linux: LinuxContext,
     // Stack will be placed here.
@@ -33,21 +102,45 @@ pub struct PerCpu {
 
 impl PerCpu {
     pub fn new<'a>() -> HvResult<&'a mut Self> {
-        if Self::entered_cpus() >= HvHeader::get().max_cpus {
-            return hv_result_err!(EINVAL);
-        }
-
-        let cpu_id = ENTERED_CPUS.fetch_add(1, Ordering::SeqCst);
+        // IDs are contiguous and below `max_cpus`, see `entered()`.
+        let max_cpus = HvHeader::get().max_cpus;
+        let cpu_id = match ENTERED_CPUS.fetch_update(Ordering::SeqCst, Ordering::SeqCst, |n| {
+            if n < max_cpus {
+                Some(n + 1)
+            } else {
+                None
+            }
+        }) {
+            Ok(cpu_id) => cpu_id,
+            Err(_) => return hv_result_err!(EINVAL),
+        };
         let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
         let ret = unsafe { &mut *(vaddr as *mut Self) };
         ret.id = cpu_id;
         ret.self_vaddr = vaddr;
+        // Other CPUs may check the state (e.g. TLB shootdowns) before `init()`.
+        ret.state = CpuState::HvDisabled;
+        ret.events.store(0, Ordering::Release);
+        ret.nmi_flush_failed.store(false, Ordering::Release);
+        ret.parked = false;
+        for stat in &ret.stats {
+            stat.store(0, Ordering::Release);
+        }
+        ret.log_limiter = RateLimiter::new();
+        ret.exit_stats = ExitStats::new();
+        #[cfg(feature = "trace")]
+        ret.trace.reset();
         cpu::set_thread_pointer(vaddr);
         Ok(ret)
     }
 
+    pub fn from_id<'a>(cpu_id: u32) -> &'a Self {
+        let vaddr = PER_CPU_ARRAY_PTR as VirtAddr + cpu_id as usize * PER_CPU_SIZE;
+        unsafe { &*(vaddr as *const Self) }
+    }
+
     pub fn current<'a>() -> &'a Self {
-        Self::current_mut()
+        unsafe { &*(cpu::thread_pointer() as *const Self) }
     }
 
     pub fn current_mut<'a>() -> &'a mut Self {
@@ -62,15 +155,211 @@ impl PerCpu {
         ENTERED_CPUS.load(Ordering::Acquire)
     }
 
+    /// Iterate over all CPUs which have entered the hypervisor, some of them may have failed to
+    /// enable it. IDs are allocated by `new()` without gaps, in `0..entered_cpus()`.
+    pub fn entered() -> impl Iterator<Item = &'static Self> + Clone {
+        (0..Self::entered_cpus()).map(Self::from_id)
+    }
+
+    /// Give back the ID of a CPU which failed to enter the hypervisor by hotplug, unless other
+    /// CPUs have entered since, as IDs must stay contiguous.
+    pub fn release_id(&self) {
+        ENTERED_CPUS
+            .compare_exchange(self.id + 1, self.id, Ordering::SeqCst, Ordering::SeqCst)
+            .ok();
+    }
+
     pub fn activated_cpus() -> u32 {
         ACTIVATED_CPUS.load(Ordering::Acquire)
     }
 
-    pub fn init(&mut self, linux_sp: usize, cell: &Cell) -> HvResult {
+    /// Reset CPU counters, so that CPUs can enter the hypervisor again.
+    pub fn reset_counters() {
+        ENTERED_CPUS.store(0, Ordering::Release);
+        ACTIVATED_CPUS.store(0, Ordering::Release);
+    }
+
+    /// Returns the local APIC ID, valid after `init()`.
+    pub fn apic_id(&self) -> u32 {
+        self.arch.apic_id()
+    }
+
+    pub fn cell(&self) -> &'static Cell<'static> {
+        self.cell.expect("CPU is not assigned to any cell!")
+    }
+
+    pub fn is_hv_enabled(&self) -> bool {
+        // May be changed by other CPUs.
+        unsafe { core::ptr::read_volatile(&self.state) == CpuState::HvEnabled }
+    }
+
+    pub fn is_parked(&self) -> bool {
+        unsafe { core::ptr::read_volatile(&self.parked) }
+    }
+
+    /// Increase the counter of `stat` on this CPU.
+    pub fn inc_stat(&self, stat: CpuStat) {
+        self.stats[stat as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    /// Returns the counter of `stat` on this CPU.
+    pub fn stat(&self, stat: CpuStat) -> u32 {
+        self.stats[stat as usize].load(Ordering::Relaxed)
+    }
+
+    /// Post `events` to this CPU and notify it by an NMI.
+    pub fn send_events(&self, events: CpuEvents) {
+        self.events.fetch_or(events.bits(), Ordering::SeqCst);
+        self.arch.send_nmi();
+    }
+
+    /// Deliver INIT or SIPI sent by the guest on another CPU.
+    pub fn send_startup_ipi(&self, ipi: StartupIpi) {
+        match ipi {
+            StartupIpi::Init => self.send_events(CpuEvents::INIT),
+            StartupIpi::InitDeassert => {}
+            StartupIpi::Startup(vector) => {
+                self.sipi_vector.store(vector as u32, Ordering::Release);
+                self.send_events(CpuEvents::SIPI);
+            }
+        }
+    }
+
+    /// Post `events` to all other CPUs running the hypervisor, and wait until they have been
+    /// handled.
+    pub fn broadcast_events(events: CpuEvents) {
+        let current_id = Self::current().id;
+        let targets = Self::entered()
+            .filter(|cpu_data| cpu_data.id != current_id && cpu_data.is_hv_enabled());
+        for cpu_data in targets.clone() {
+            cpu_data.send_events(events);
+        }
+        for cpu_data in targets {
+            while cpu_data.is_hv_enabled()
+                && cpu_data.events.load(Ordering::Acquire) & events.bits() != 0
+            {
+                // Avoid deadlock if the target CPU is waiting for us at the same time.
+                Self::current_mut().handle_events();
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Handle pending events sent by other CPUs.
+    pub fn handle_events(&mut self) {
+        if self.nmi_flush_failed.swap(false, Ordering::AcqRel) {
+            error!(
+                "Failed to flush nested TLB on CPU {} in the NMI handler",
+                self.id
+            );
+        }
+        let events = CpuEvents::from_bits_truncate(self.events.swap(0, Ordering::AcqRel));
+        if events.is_empty() || !self.is_hv_enabled() {
+            return;
+        }
+        self.inc_stat(CpuStat::VmExitsManagement);
+        if events.contains(CpuEvents::FLUSH_NESTED_TLB) {
+            if let Err(e) = self.vcpu.flush_nested_tlb() {
+                error!("Failed to flush nested TLB on CPU {}: {:?}", self.id, e);
+            }
+        }
+        if events.contains(CpuEvents::SYNC_DIRTY_LOG) {
+            if let Err(e) = self.vcpu.sync_dirty_log() {
+                error!("Failed to sync dirty log on CPU {}: {:?}", self.id, e);
+            }
+        }
+        if events.contains(CpuEvents::CRASH_DUMP) {
+            // Usually captured in the NMI handler already.
+            crate::crash::capture_cpu(self, 0, 0);
+            if let Err(e) = self.deactivate_vmm(None) {
+                error!("Failed to return CPU {} to Linux: {:?}", self.id, e);
+            }
+        }
+        if events.contains(CpuEvents::LEAVE) {
+            if let Err(e) = self.deactivate_vmm(None) {
+                error!("Failed to return CPU {} to Linux: {:?}", self.id, e);
+            }
+        }
+        if events.contains(CpuEvents::INIT) {
+            self.park();
+        }
+        if events.contains(CpuEvents::SIPI) {
+            self.wake_up(self.sipi_vector.load(Ordering::Acquire) as u8);
+        }
+    }
+
+    /// Handle pending events in the NMI handler, returns `false` if there are no events.
+    ///
+    /// The interrupted code may hold any lock (including the heap, the serial port and the
+    /// console) and the `&mut PerCpu`, so only events which can be handled by `&self` without
+    /// locks are handled here, and nothing is printed. Others are kept pending, and will be
+    /// handled before the next VM entry.
+    pub fn handle_events_in_nmi(&self) -> bool {
+        let mask = CpuEvents::FLUSH_NESTED_TLB.bits();
+        let pending = self.events.load(Ordering::Acquire);
+        if self.is_hv_enabled() && self.events.fetch_and(!mask, Ordering::AcqRel) & mask != 0 {
+            self.inc_stat(CpuStat::VmExitsManagement);
+            if self.vcpu.flush_nested_tlb().is_err() {
+                self.nmi_flush_failed.store(true, Ordering::Release);
+            }
+        }
+        pending != 0
+    }
+
+    /// Park the CPU after INIT, it will wait in the hypervisor for SIPI before the next VM
+    /// entry. The guest state is kept, so that the CPU can return to Linux if the hypervisor is
+    /// disabled meanwhile (Linux considers the CPU offline).
+    pub fn park(&mut self) {
+        if !self.parked {
+            info!("CPU {} parked", self.id);
+            self.parked = true;
+        }
+    }
+
+    /// Start a fresh vCPU in real mode at `vector << 12`, if the CPU is parked. SIPIs to running
+    /// CPUs are ignored, as the hardware does.
+    fn wake_up(&mut self, vector: u8) {
+        if !self.parked {
+            return;
+        }
+        info!("CPU {} woken up by SIPI with vector {:#x}", self.id, vector);
+        self.fpu.reset_guest();
+        match self.vcpu.reset(self.cell(), vector) {
+            Ok(()) => self.parked = false,
+            Err(e) => error!("Failed to reset vCPU {}: {:?}", self.id, e),
+        }
+    }
+
+    /// Called before VM entries, wait until the parked CPU is woken up. Parked CPUs leave
+    /// with others when the hypervisor is disabled, and are notified by `CpuEvents::WAKE_UP`.
+    pub fn wait_while_parked(&mut self) {
+        while self.parked {
+            if crate::hypercall::disabling() {
+                if let Err(e) = crate::hypercall::leave_hypervisor(self, None) {
+                    error!("Failed to return CPU {} to Linux: {:?}", self.id, e);
+                }
+            }
+            self.idle(false);
+            self.handle_events();
+        }
+    }
+
+    /// Idle in the hypervisor on guest HLT or while parked, until an event arrives or, if
+    /// `wake_on_interrupt`, an interrupt is pending for the guest. Spurious wake-ups are possible.
+    pub fn idle(&self, wake_on_interrupt: bool) {
+        cpu::wait_for_write(&self.events, wake_on_interrupt, || {
+            self.events.load(Ordering::Acquire) != 0
+        });
+    }
+
+    pub fn init(&mut self, linux_sp: usize, cell: &'static Cell<'static>) -> HvResult {
         info!("CPU {} init...", self.id);
+        let fpu = FpuState::new()?;
+        unsafe { core::ptr::write(&mut self.fpu, fpu) };
 
         // Save CPU state used for linux.
         self.state = CpuState::HvDisabled;
+        self.cell = Some(cell);
         self.linux = LinuxContext::load_from(linux_sp);
         self.arch.init();
 
@@ -78,28 +367,93 @@ impl PerCpu {
         unsafe { crate::memory::hv_page_table().read().activate() };
 
         // Initialize vCPU. Use `ptr::write()` to avoid dropping
-        unsafe { core::ptr::write(&mut self.vcpu, Vcpu::new(&self.linux, cell)?) };
+        match Vcpu::new(&self.linux, cell) {
+            Ok(vcpu) => unsafe { core::ptr::write(&mut self.vcpu, vcpu) },
+            Err(e) => {
+                self.linux.restore();
+                unsafe { core::ptr::drop_in_place(&mut self.fpu) };
+                return Err(e);
+            }
+        }
+        #[cfg(debug_assertions)]
+        self.fpu.self_test();
 
         self.state = CpuState::HvEnabled;
         Ok(())
     }
 
+    /// Undo `init()` if it has completed, when enabling the hypervisor failed. The CPU is left
+    /// as Linux had it.
+    pub fn rollback(&mut self) {
+        if self.state != CpuState::HvEnabled {
+            return;
+        }
+        self.state = CpuState::HvDisabled;
+        if let Err(e) = self.vcpu.disable() {
+            error!(
+                "Failed to disable virtualization on CPU {}: {:?}",
+                self.id, e
+            );
+        }
+        self.linux.restore();
+        unsafe {
+            core::ptr::drop_in_place(&mut self.vcpu);
+            core::ptr::drop_in_place(&mut self.fpu);
+        }
+        info!("CPU {} rolled back.", self.id);
+    }
+
     pub fn activate_vmm(&mut self) -> HvResult {
         println!("Activating hypervisor on CPU {}...", self.id);
         ACTIVATED_CPUS.fetch_add(1, Ordering::SeqCst);
 
-        self.vcpu.enter(&self.linux)?;
-        unreachable!()
+        let err = self.vcpu.enter(&self.linux).unwrap_err();
+        ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst);
+        Err(err)
     }
 
-    pub fn deactivate_vmm(&mut self, ret_code: usize) -> HvResult {
-        println!("Deactivating hypervisor on CPU {}...", self.id);
-        ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst);
+    /// Send the CPUs running the guest back to Linux after the current CPU failed to enter it
+    /// while enabling the hypervisor, and wait until all of them have left. Events are resent,
+    /// since a CPU receiving one just before VM entry handles it only on the next VM exit.
+    pub fn leave_all_activated() {
+        let current_id = Self::current().id;
+        while Self::activated_cpus() > 0 {
+            for cpu in Self::entered().filter(|c| c.id != current_id && c.is_hv_enabled()) {
+                cpu.send_events(CpuEvents::LEAVE);
+            }
+            let deadline = cpu::current_time_nanos() + 1_000_000;
+            while Self::activated_cpus() > 0 && cpu::current_time_nanos() < deadline {
+                core::hint::spin_loop();
+            }
+        }
+    }
+
+    /// Return to Linux with the guest state, and set the return value of the hypercall to
+    /// `ret_code` if given.
+    pub fn deactivate_vmm(&mut self, ret_code: Option<usize>) -> HvResult {
+        // The console lock may be held by a crashed CPU.
+        if !crate::crash::in_progress() {
+            println!("Deactivating hypervisor on CPU {}...", self.id);
+            self.exit_stats.dump(self.id);
+        }
 
-        self.vcpu.set_return_val(ret_code);
+        if let Some(ret_code) = ret_code {
+            self.vcpu.set_return_val(ret_code);
+        }
+        self.fpu.restore_guest();
+        // Stop handling events that require virtualization enabled.
+        self.state = CpuState::HvDisabled;
         self.vcpu.exit(&mut self.linux)?;
         self.linux.restore();
-        self.state = CpuState::HvDisabled;
+        // The last CPU releases global resources after all others stopped using them. Locks
+        // may be held by the panicking CPU, so skip it on crashes.
+        let last = ACTIVATED_CPUS.fetch_sub(1, Ordering::SeqCst) == 1;
+        if crate::has_err() {
+            // Sent back by `leave_all_activated()`, counted with the CPUs that failed.
+            crate::enable_failed();
+        } else if last && !crate::crash::in_progress() {
+            crate::shutdown();
+        }
         self.linux.return_to_linux(self.vcpu.regs());
     }
 
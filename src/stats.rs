@@ -6,9 +6,15 @@ pub use _stats::*;
 #[cfg(not(feature = "stats"))]
 pub use _stats_empty::*;
 
+/// Number of buckets of `Histogram`, the last one also counts all larger values.
+pub const HISTOGRAM_BUCKETS: usize = 32;
+
 mod _stats {
+    use core::fmt::Write;
     use core::sync::atomic::{AtomicU64, Ordering};
 
+    use super::HISTOGRAM_BUCKETS;
+
     #[derive(Default)]
     pub struct StatsValue {
         count: AtomicU64,
@@ -16,13 +22,17 @@ mod _stats {
     }
 
     impl StatsValue {
-        pub fn new() -> Self {
+        pub const fn new() -> Self {
             Self {
                 count: AtomicU64::new(0),
                 sum: AtomicU64::new(0),
             }
         }
 
+        pub fn count(&self) -> u64 {
+            self.count.load(Ordering::Acquire)
+        }
+
         pub fn add(&mut self, value: u64) {
             *self.count.get_mut() += 1;
             *self.sum.get_mut() += value;
@@ -47,6 +57,50 @@ mod _stats {
         }
     }
 
+    /// Statistics of values with a histogram of power-of-two buckets, bucket `i` counts values
+    /// in `[2^i, 2^(i+1))` (0 is counted in bucket 0), the last one counts all larger values.
+    pub struct Histogram {
+        value: StatsValue,
+        buckets: [AtomicU64; HISTOGRAM_BUCKETS],
+    }
+
+    impl Histogram {
+        pub const fn new() -> Self {
+            #[allow(clippy::declare_interior_mutable_const)]
+            const ZERO: AtomicU64 = AtomicU64::new(0);
+            Self {
+                value: StatsValue::new(),
+                buckets: [ZERO; HISTOGRAM_BUCKETS],
+            }
+        }
+
+        pub fn count(&self) -> u64 {
+            self.value.count()
+        }
+
+        pub fn atomic_add(&self, value: u64) {
+            let bucket = (63 - (value | 1).leading_zeros() as usize).min(HISTOGRAM_BUCKETS - 1);
+            self.value.atomic_add(value);
+            self.buckets[bucket].fetch_add(1, Ordering::Release);
+        }
+
+        pub fn as_string(&self) -> alloc::string::String {
+            let mut s = self.value.as_string();
+            for (i, bucket) in self.buckets.iter().enumerate() {
+                let count = bucket.load(Ordering::Acquire);
+                if count == 0 {
+                    continue;
+                }
+                if i == HISTOGRAM_BUCKETS - 1 {
+                    write!(s, "\n    [2^{:<2}, inf ): {}", i, count).unwrap();
+                } else {
+                    write!(s, "\n    [2^{:<2}, 2^{:<2}): {}", i, i + 1, count).unwrap();
+                }
+            }
+            s
+        }
+    }
+
     pub struct Instant {
         timestamp: u64,
     }
@@ -68,13 +122,30 @@ mod _stats_empty {
     #[derive(Default)]
     pub struct StatsValue;
     impl StatsValue {
-        pub fn new() -> Self {
+        pub const fn new() -> Self {
             Self
         }
+        pub fn count(&self) -> u64 {
+            0
+        }
         pub fn add(&mut self, _value: u64) {}
         pub fn atomic_add(&self, _value: u64) {}
     }
 
+    pub struct Histogram;
+    impl Histogram {
+        pub const fn new() -> Self {
+            Self
+        }
+        pub fn count(&self) -> u64 {
+            0
+        }
+        pub fn atomic_add(&self, _value: u64) {}
+        pub fn as_string(&self) -> alloc::string::String {
+            alloc::string::String::new()
+        }
+    }
+
     pub struct Instant;
     impl Instant {
         pub fn now() -> Self {
@@ -108,4 +179,31 @@ mod test {
         println!("stats: {}", stats.as_string());
         assert_eq!(c, 3311503426941990459);
     }
+
+    #[test]
+    fn test_histogram() {
+        let hist = Histogram::new();
+        assert_eq!(hist.count(), 0);
+        assert_eq!(hist.as_string(), "count = 0, sum = 0, average = 0.000");
+
+        for value in [0, 1, 2, 3, 4, 7, 8, 1 << 31, 1 << 40] {
+            hist.atomic_add(value);
+        }
+        assert_eq!(hist.count(), 9);
+        let sum: u64 = 25 + (1 << 31) + (1 << 40);
+        assert_eq!(
+            hist.as_string(),
+            format!(
+                "count = 9, sum = {}, average = {}.{:03}\n    \
+                 [2^0 , 2^1 ): 2\n    \
+                 [2^1 , 2^2 ): 2\n    \
+                 [2^2 , 2^3 ): 2\n    \
+                 [2^3 , 2^4 ): 1\n    \
+                 [2^31, inf ): 2",
+                sum,
+                sum * 1000 / 9 / 1000,
+                sum * 1000 / 9 % 1000
+            )
+        );
+    }
 }
@@ -0,0 +1,123 @@
+//! Per-CPU binary trace of VM exits, recorded with the `trace` feature.
+//!
+//! Each CPU only writes to its own buffer, readers on other CPUs detect overwritten entries by
+//! checking the head before and after copying. Keep `crates/rvm-trace` in sync with the layout
+//! of `TraceHeader` and `TraceEntry`.
+
+use alloc::vec::Vec;
+use core::cell::UnsafeCell;
+use core::mem::size_of;
+use core::sync::atomic::{fence, AtomicU64, Ordering};
+
+pub const TRACE_MAGIC: u32 = u32::from_le_bytes(*b"RVMT");
+pub const TRACE_VERSION: u32 = 1;
+const TRACE_ENTRIES: usize = 1024;
+
+/// Raw exit reasons are VMX basic exit reasons on Intel CPUs, or SVM exit codes on AMD CPUs.
+const TRACE_VENDOR_INTEL: u32 = 1;
+const TRACE_VENDOR_AMD: u32 = 2;
+
+/// A traced VM exit.
+#[repr(C)]
+#[derive(Debug, Clone, Copy)]
+pub struct TraceEntry {
+    /// TSC at the beginning of the VM exit handler.
+    pub tsc: u64,
+    /// Guest RIP of the VM exit.
+    pub rip: u64,
+    /// Exit qualification on Intel CPUs, or EXITINFO1 on AMD CPUs.
+    pub qualification: u64,
+    /// Cycles spent in the VM exit handler.
+    pub cycles: u64,
+    /// Vendor specific exit reason.
+    pub reason: u32,
+    /// Vendor independent `ExitKind`.
+    pub kind: u32,
+}
+
+/// Copied to the guest by `HypervisorGetTrace`, followed by `num_entries` entries from the
+/// oldest to the newest.
+#[repr(C)]
+#[derive(Debug)]
+pub struct TraceHeader {
+    pub magic: u32,
+    pub version: u32,
+    pub vendor: u32,
+    pub cpu_id: u32,
+    pub tsc_khz: u64,
+    pub capacity: u32,
+    pub num_entries: u32,
+    /// Number of VM exits recorded since the hypervisor is enabled.
+    pub total: u64,
+}
+
+pub struct TraceBuffer {
+    /// Number of entries ever written, the next entry is written to `head % TRACE_ENTRIES`.
+    head: AtomicU64,
+    entries: UnsafeCell<[TraceEntry; TRACE_ENTRIES]>,
+}
+
+impl TraceBuffer {
+    /// Maximum size of the data returned by `snapshot()`.
+    pub const MAX_SNAPSHOT_SIZE: usize =
+        size_of::<TraceHeader>() + TRACE_ENTRIES * size_of::<TraceEntry>();
+
+    /// Discard all entries. The entries are not cleared to avoid touching the whole buffer.
+    pub fn reset(&self) {
+        self.head.store(0, Ordering::Release);
+    }
+
+    /// Append an entry, must be called only on the CPU owning the buffer.
+    pub fn record(&self, entry: TraceEntry) {
+        let head = self.head.load(Ordering::Relaxed);
+        unsafe {
+            let slot = &mut (*self.entries.get())[head as usize % TRACE_ENTRIES];
+            core::ptr::write_volatile(slot, entry);
+        }
+        self.head.store(head + 1, Ordering::Release);
+    }
+
+    /// Returns the header and entries of CPU `cpu_id` as bytes in the format of `TraceHeader`.
+    pub fn snapshot(&self, cpu_id: u32) -> Vec<u8> {
+        let head = self.head.load(Ordering::Acquire);
+        let first = head.saturating_sub(TRACE_ENTRIES as u64);
+        let mut entries = Vec::with_capacity((head - first) as usize);
+        for idx in first..head {
+            entries.push(unsafe {
+                core::ptr::read_volatile(&(*self.entries.get())[idx as usize % TRACE_ENTRIES])
+            });
+        }
+        fence(Ordering::Acquire);
+        // Entries up to the one being written now may have been overwritten during copying.
+        let new_head = self.head.load(Ordering::Acquire);
+        let valid_first = (new_head + 1)
+            .saturating_sub(TRACE_ENTRIES as u64)
+            .max(first);
+        let entries = &entries[(valid_first - first).min(entries.len() as u64) as usize..];
+
+        let header = TraceHeader {
+            magic: TRACE_MAGIC,
+            version: TRACE_VERSION,
+            vendor: if cfg!(feature = "amd") {
+                TRACE_VENDOR_AMD
+            } else {
+                TRACE_VENDOR_INTEL
+            },
+            cpu_id,
+            tsc_khz: crate::arch::cpu::frequency() as u64 * 1000,
+            capacity: TRACE_ENTRIES as u32,
+            num_entries: entries.len() as u32,
+            total: head,
+        };
+        let mut buf = Vec::with_capacity(Self::MAX_SNAPSHOT_SIZE);
+        buf.extend_from_slice(as_bytes(&header));
+        for entry in entries {
+            buf.extend_from_slice(as_bytes(entry));
+        }
+        buf
+    }
+}
+
+fn as_bytes<T>(val: &T) -> &[u8] {
+    unsafe { core::slice::from_raw_parts(val as *const T as *const u8, size_of::<T>()) }
+}